@@ -11,6 +11,9 @@ typedef enum {
   IoError,
   AnyhowError,
   UnsupportedFilesystem,
+  CorruptValue,
+  PartiallyEvicted,
+  ReadOnly,
 } PossumError;
 
 typedef struct Arc_RwLock_Handle Arc_RwLock_Handle;
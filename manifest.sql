@@ -1,4 +1,5 @@
--- See manifest_blocks.sql for the original more complicated schema.
+-- See manifest_blocks.sql for the original more complicated schema. Changes here need a step in
+-- src/migrations.rs to upgrade existing manifests.
 
 create table keys (
     key_id integer primary key,
@@ -11,10 +12,28 @@ create table keys (
     -- This is the most (concrete?) representation for the finest time granularity sqlite's internal
     -- time functions support.
     last_used integer not null default (cast(unixepoch('subsec')*1e3 as integer)),
+    -- Same representation as last_used. Null if the value doesn't expire.
+    expires integer,
+    -- Incremented when reads update last_used. Used by frequency-based eviction policies.
+    use_count integer not null default 0,
+    -- The codec a value is compressed with, or null if it's stored as is. value_length is the
+    -- compressed length on disk, and uncompressed_length is what readers see.
+    codec text,
+    uncompressed_length integer,
+    -- The per-value nonce for values encrypted by a Handle with an encryption key. Null if the
+    -- value isn't encrypted.
+    nonce blob,
+    -- Little-endian u64 checksums of consecutive 64 KiB chunks of the value as stored on disk. Null
+    -- if the value was written without being checksummed.
+    checksums blob,
+    -- A hash of the value's checksums, set when the value was written with deduplication, so later
+    -- writes of the same content can find it. Keys with the same content share a location.
+    content_hash integer,
+    -- Whether the value is stored in blocks, see value_parts. Set when the value is written, by
+    -- blocks::in_blocks.
+    in_blocks integer not null default 0,
     -- Put this last because it's most likely looked up in the index and not needed when looking at the row.
     key blob unique not null,
-    -- This is necessary for value renames
-    unique (file_id, file_offset)
     check ( iif (
         value_length=0,
         file_id is null and file_offset is null,
@@ -26,22 +45,169 @@ create index if not exists last_used_index on keys (
     key_id
 );
 
--- This is for next_value_offset. Does this duplicate the unique (file_id, file_offset) index on keys?
+-- This is for the LFU eviction policy.
+create index if not exists use_count_index on keys (
+    use_count,
+    last_used,
+    key_id
+);
+
+-- This is for deleting expired keys.
+create index if not exists expires_index on keys (expires) where expires is not null;
+
+-- This is for next_value_offset, and finding the other keys that share a deduplicated value.
 CREATE INDEX file_id_then_offset on keys (file_id, file_offset);
 -- This is for last_end_offset
 CREATE INDEX file_id_then_end_offset on keys (file_id, file_offset+value_length);
+-- This is for finding values with the same content when deduplicating.
+create index if not exists content_hash_index on keys (content_hash) where content_hash is not null;
 
 create table sums (
     key text primary key,
     value integer not null
 ) strict, without rowid;
 
-insert or ignore into sums values ('value_length', (select coalesce(sum(value_length), 0) from keys));
+insert or ignore into sums values ('value_length', (
+    select coalesce(sum(value_length), 0) from (select distinct file_id, file_offset, value_length from keys)
+));
 
+-- Values shared by deduplicated keys are counted once, when their first key is inserted and when
+-- their last key is deleted. Values stored in blocks are counted by their blocks instead.
 create trigger if not exists value_length_sum_on_delete delete on keys begin
-    update sums set value=value-old.value_length where key='value_length';
+    update sums set value=value-old.value_length
+    where key='value_length' and not old.in_blocks and not exists (
+        select 1 from keys where file_id=old.file_id and file_offset=old.file_offset and key_id!=old.key_id
+    );
 end;
 
 create trigger if not exists value_length_sum_on_insert insert on keys begin
-    update sums set value=value+new.value_length where key='value_length';
-end;
\ No newline at end of file
+    update sums set value=value+new.value_length
+    where key='value_length' and not new.in_blocks and not exists (
+        select 1 from keys where file_id=new.file_id and file_offset=new.file_offset and key_id!=new.key_id
+    );
+end;
+
+-- Tags for in-progress fetches of missing keys, so that only one Handle fetches a missing value at a
+-- time. See the singleflight fetch in DESIGN.
+create table fetches (
+    key blob primary key,
+    -- Random value chosen by the fetcher so it only clears its own tag.
+    fetch_id integer not null,
+    -- Tags older than the fetch timeout are assumed to belong to a fetcher that died.
+    started integer not null default (cast(unixepoch('subsec')*1e3 as integer))
+) strict, without rowid;
+
+-- Settings shared by every Handle on the directory, such as the limits loaded by Handle::new.
+create table settings (
+    name text primary key,
+    value any
+) strict, without rowid;
+
+-- Value lengths rounded up to the block size, for block rounded usage accounting. Handle::new stores
+-- the directory's block size in settings, and lengths aren't rounded until it does.
+insert or ignore into sums values ('block_rounded_value_length', 0);
+
+create trigger if not exists block_rounded_value_length_on_delete delete on keys begin
+    update sums set value=value-coalesce((
+        select (old.value_length+s.value-1)/s.value*s.value from settings s where s.name='block_size'
+    ), old.value_length)
+    where key='block_rounded_value_length' and not old.in_blocks and not exists (
+        select 1 from keys where file_id=old.file_id and file_offset=old.file_offset and key_id!=old.key_id
+    );
+end;
+
+create trigger if not exists block_rounded_value_length_on_insert insert on keys begin
+    update sums set value=value+coalesce((
+        select (new.value_length+s.value-1)/s.value*s.value from settings s where s.name='block_size'
+    ), new.value_length)
+    where key='block_rounded_value_length' and not new.in_blocks and not exists (
+        select 1 from keys where file_id=new.file_id and file_offset=new.file_offset and key_id!=new.key_id
+    );
+end;
+
+-- Large values are stored in blocks, which value_parts maps the parts of each value to, so that cold
+-- blocks can be evicted from values that are still in use, and blocks with the same content can be
+-- shared between values. A block starts out as its part of the value's own location. See blocks.rs
+-- and manifest_blocks.sql.
+create table blocks (
+    block_id integer primary key,
+    file_id integer not null,
+    file_offset integer not null,
+    block_length integer not null,
+    -- A hash of the checksums of the block's chunks, for finding blocks with the same content. Null
+    -- if the value was written without checksums.
+    block_hash integer,
+    -- Same representation as keys.last_used. Reads of a block update it like reads of a key.
+    last_used integer not null
+) strict;
+
+create index if not exists blocks_by_location on blocks (file_id, file_offset);
+create index if not exists blocks_by_end_offset on blocks (file_id, file_offset+block_length);
+create index if not exists blocks_by_last_used on blocks (last_used);
+create index if not exists blocks_by_hash on blocks (block_hash) where block_hash is not null;
+
+-- The parts of values stored in blocks. Offsets are within the value. The block is null if the part
+-- was evicted.
+create table value_parts (
+    key_id integer not null,
+    part_offset integer not null,
+    part_length integer not null,
+    block_id integer,
+    primary key (key_id, part_offset)
+) strict, without rowid;
+
+create index if not exists value_parts_by_block on value_parts (block_id) where block_id is not null;
+
+-- Blocks that lost their last part, waiting for the transaction that removed them to schedule
+-- them for hole punching. See blocks::take_released.
+create table released_blocks (
+    file_id integer not null,
+    file_offset integer not null,
+    block_length integer not null,
+    primary key (file_id, file_offset)
+) strict, without rowid;
+
+create trigger if not exists value_parts_on_key_delete delete on keys begin
+    delete from value_parts where key_id=old.key_id;
+end;
+
+create trigger if not exists blocks_on_value_part_delete after delete on value_parts begin
+    delete from blocks where block_id=old.block_id and not exists (
+        select 1 from value_parts where block_id=old.block_id
+    );
+end;
+
+create trigger if not exists blocks_on_value_part_update after update of block_id on value_parts begin
+    delete from blocks where block_id=old.block_id and not exists (
+        select 1 from value_parts where block_id=old.block_id
+    );
+end;
+
+create trigger if not exists block_sums_on_insert insert on blocks begin
+    update sums set value=value+new.block_length where key='value_length';
+    update sums set value=value+coalesce((
+        select (new.block_length+s.value-1)/s.value*s.value from settings s where s.name='block_size'
+    ), new.block_length) where key='block_rounded_value_length';
+end;
+
+create trigger if not exists block_sums_on_delete delete on blocks begin
+    update sums set value=value-old.block_length where key='value_length';
+    update sums set value=value-coalesce((
+        select (old.block_length+s.value-1)/s.value*s.value from settings s where s.name='block_size'
+    ), old.block_length) where key='block_rounded_value_length';
+    insert or ignore into released_blocks (file_id, file_offset, block_length)
+    values (old.file_id, old.file_offset, old.block_length);
+end;
+
+-- Values waiting to be hole punched, added in the transaction that deletes them so punches aren't
+-- lost if the process exits first. Any Handle's value puncher can punch them. Times are in the same
+-- representation as last_used.
+create table pending_punches (
+    file_id integer not null,
+    file_offset integer not null,
+    length integer not null,
+    -- Failed attempts, usually because a reader had the value or the clone lock locked.
+    attempts integer not null default 0,
+    next_attempt integer not null default (cast(unixepoch('subsec')*1e3 as integer)),
+    primary key (file_id, file_offset)
+) strict, without rowid;
@@ -1,4 +1,5 @@
 -- This is a schema that would allow keys to be partially evicted by tracking usage at the block level.
+-- manifest.sql implements it for large values with the blocks and value_parts tables, see blocks.rs.
 
 create table keys (
     key_id integer,
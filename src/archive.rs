@@ -0,0 +1,133 @@
+//! A streaming archive format for moving values between directories.
+//!
+//! An archive starts with ARCHIVE_MAGIC and a little-endian u32 version. Each value follows as a
+//! record tag byte of 1, then the key length as a u64 and the key, last_used as i64 milliseconds
+//! since the Unix epoch, a byte that's 1 if an i64 expiry in the same representation follows or
+//! 0 if there is none, and the value length as a u64 followed by the value. A record tag of 0 ends
+//! the archive, so truncated archives are detected. All integers are little-endian. Compressed
+//! values are decompressed into the archive.
+
+use super::*;
+
+pub const ARCHIVE_MAGIC: &[u8; 8] = b"possumar";
+const ARCHIVE_VERSION: u32 = 1;
+
+const RECORD_END: u8 = 0;
+const RECORD_VALUE: u8 = 1;
+
+/// Imports are committed after this many values, or IMPORT_BATCH_BYTES, whichever comes first.
+const IMPORT_BATCH_VALUES: usize = 1000;
+const IMPORT_BATCH_BYTES: u64 = 64 << 20;
+
+impl Handle {
+    /// Writes the keys starting with prefix, and their values, to an archive. The values are from a
+    /// single snapshot, and reading them doesn't update last_used. Partially evicted values are
+    /// left out. Returns the number of values written.
+    pub fn export(&self, prefix: &[u8], mut w: impl Write) -> Result<u64> {
+        let mut reader = self.read()?;
+        let items = reader.list_items(prefix)?;
+        for item in &items {
+            reader.add_value(&item.value)?;
+        }
+        let snapshot = reader.begin()?;
+        let items: Vec<_> = items
+            .into_iter()
+            .filter(|item| snapshot.value(&item.value).missing_ranges().is_empty())
+            .collect();
+        w.write_all(ARCHIVE_MAGIC)?;
+        w.write_all(&ARCHIVE_VERSION.to_le_bytes())?;
+        for item in &items {
+            let value = &item.value;
+            w.write_all(&[RECORD_VALUE])?;
+            w.write_all(&(item.key.len() as u64).to_le_bytes())?;
+            w.write_all(&item.key)?;
+            w.write_all(&timestamp_millis(value.last_used()).to_le_bytes())?;
+            match value.expires() {
+                None => w.write_all(&[0])?,
+                Some(expires) => {
+                    w.write_all(&[1])?;
+                    w.write_all(&timestamp_millis(expires).to_le_bytes())?;
+                }
+            }
+            w.write_all(&value.uncompressed_length().to_le_bytes())?;
+            snapshot.value(value).view(|bytes| w.write_all(bytes))??;
+        }
+        w.write_all(&[RECORD_END])?;
+        w.flush()?;
+        Ok(items.len() as u64)
+    }
+
+    /// Writes the values in an archive, replacing existing values with the same keys. Values are
+    /// committed in batches, so if an error occurs, values from earlier batches remain. Returns the
+    /// number of values imported.
+    pub fn import(&self, mut r: impl Read) -> Result<u64> {
+        let mut magic = [0; ARCHIVE_MAGIC.len()];
+        r.read_exact(&mut magic).context("reading archive header")?;
+        if &magic != ARCHIVE_MAGIC {
+            bail!("not a possum archive");
+        }
+        let version = u32::from_le_bytes(read_array(&mut r)?);
+        if version != ARCHIVE_VERSION {
+            bail!("unsupported archive version {}", version);
+        }
+        let mut count = 0;
+        let mut writer = self.new_writer()?;
+        let mut batch_values = 0;
+        let mut batch_bytes = 0;
+        loop {
+            match read_array(&mut r).context("reading record tag")? {
+                [RECORD_END] => break,
+                [RECORD_VALUE] => {}
+                [tag] => bail!("unknown record tag {}", tag),
+            }
+            let key_len = u64::from_le_bytes(read_array(&mut r)?);
+            let mut key = vec![];
+            (&mut r).take(key_len).read_to_end(&mut key)?;
+            if key.len() as u64 != key_len {
+                bail!("archive ended in key");
+            }
+            let last_used = timestamp_from_millis(i64::from_le_bytes(read_array(&mut r)?))?;
+            let expires = match read_array(&mut r)? {
+                [0] => None,
+                [1] => Some(timestamp_from_millis(i64::from_le_bytes(read_array(
+                    &mut r,
+                )?))?),
+                [flag] => bail!("invalid expiry flag {}", flag),
+            };
+            let value_len = u64::from_le_bytes(read_array(&mut r)?);
+            let mut value = writer.new_value().begin()?;
+            if value.copy_from((&mut r).take(value_len))? != value_len {
+                bail!("archive ended in value for key {:?}", key);
+            }
+            writer.stage_write_inner(key, value, expires, Some(last_used))?;
+            count += 1;
+            batch_values += 1;
+            batch_bytes += value_len;
+            if batch_values >= IMPORT_BATCH_VALUES || batch_bytes >= IMPORT_BATCH_BYTES {
+                writer.commit()?;
+                writer = self.new_writer()?;
+                batch_values = 0;
+                batch_bytes = 0;
+            }
+        }
+        writer.commit()?;
+        Ok(count)
+    }
+}
+
+fn read_array<const N: usize>(r: &mut impl Read) -> io::Result<[u8; N]> {
+    let mut buf = [0; N];
+    r.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+fn timestamp_millis(timestamp: Timestamp) -> i64 {
+    timestamp.and_utc().timestamp_millis()
+}
+
+fn timestamp_from_millis(millis: i64) -> Result<Timestamp> {
+    Ok(Timestamp(
+        TimestampInner::from_timestamp_millis(millis)
+            .with_context(|| format!("timestamp {} out of range", millis))?,
+    ))
+}
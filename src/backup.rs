@@ -0,0 +1,87 @@
+//! Consistent copies of a directory that's in use.
+
+use rusqlite::backup::{Backup, StepResult};
+
+use super::*;
+use crate::ownedtx::OwnedTxTrait;
+
+#[derive(Debug, Default)]
+pub struct BackupReport {
+    /// Values files that were cloned into the backup.
+    pub files_cloned: usize,
+    /// Values files that were copied into the backup because cloning isn't supported.
+    pub files_copied: usize,
+    pub bytes_copied: u64,
+}
+
+impl Handle {
+    /// Copies the manifest and every values file it refers to into dest_dir, which Handle::new
+    /// can then open. Writers aren't blocked, since the manifest is copied from the same read
+    /// transaction the values are snapshotted in. Values files are cloned where the filesystem
+    /// supports it.
+    pub fn backup_to(&self, dest_dir: impl AsRef<Path>) -> Result<BackupReport> {
+        let dest_dir = dest_dir.as_ref();
+        fs::create_dir_all(dest_dir)?;
+        let dest_manifest = dest_dir.join(MANIFEST_DB_FILE_NAME);
+        if dest_manifest.exists() {
+            bail!("{} already exists", dest_manifest.display());
+        }
+        let mut reader = self.read()?;
+        let values = {
+            let tx = reader.owned_tx.transaction().readonly_transaction();
+            // Expired values are included because they're still in the manifest.
+            let mut stmt = tx.prepare(&format!(
+                "select {} from keys where file_id is not null",
+                value_columns_sql()
+            ))?;
+            let rows = stmt.query_map([], Value::from_row)?;
+            rows.collect::<rusqlite::Result<Vec<_>>>()?
+        };
+        for value in &values {
+            reader.add_value(value)?;
+        }
+        // The backup reads through the reader's connection, so it sees the manifest as of the
+        // reader's snapshot, however much has been committed since. vacuum into can't run inside a
+        // transaction.
+        {
+            let mut dest_conn = Connection::open(&dest_manifest)?;
+            let tx = reader.owned_tx.transaction().readonly_transaction();
+            let result = Backup::new(tx, &mut dest_conn)?
+                .step(-1)
+                .context("copying manifest")?;
+            if result != StepResult::Done {
+                bail!("copying manifest: {:?}", result);
+            }
+        }
+        let snapshot = reader.begin()?;
+        let mut report = BackupReport::default();
+        for (file_id, file_clone) in &snapshot.file_clones {
+            let mut file_clone = file_clone.lock().unwrap();
+            let src_path = match &file_clone.tempdir {
+                Some(tempdir) => file_path(tempdir.path(), file_id),
+                None => file_path(self.dir.path(), file_id),
+            };
+            let dest_path = file_path(dest_dir, file_id);
+            if self.dir.supports_file_cloning() {
+                // This also fails if the backup is on another filesystem.
+                match clonefile(&src_path, &dest_path) {
+                    Ok(()) => {
+                        report.files_cloned += 1;
+                        continue;
+                    }
+                    Err(err) if CloneFileError::is_unsupported(&err) => {}
+                    Err(err) => return Err(err).context("cloning values file"),
+                }
+            }
+            // Only the part of the file that existed when the snapshot was taken is needed.
+            let len = file_clone.len;
+            let file = &mut file_clone.file;
+            file.seek(Start(0))?;
+            let mut dest = File::create(&dest_path)?;
+            report.bytes_copied += io::copy(&mut file.take(len), &mut dest)?;
+            dest.sync_all()?;
+            report.files_copied += 1;
+        }
+        Ok(report)
+    }
+}
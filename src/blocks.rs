@@ -0,0 +1,236 @@
+//! Large values are stored in blocks, so that the parts of a value that aren't being read can be
+//! evicted while the rest of it stays, and parts with the same content can share a block. Each part
+//! of a value starts out in a block at its own location. See value_parts and blocks in
+//! manifest.sql, and Limits::partial_eviction.
+
+use rusqlite::OptionalExtension;
+
+use super::*;
+
+/// The length of the parts values are stored in, except for the last part of a value, which can be
+/// shorter. It's a multiple of the checksum chunk length so blocks can be hashed and verified on
+/// their own, and of filesystem block sizes so blocks can be punched on their own.
+pub(crate) const BLOCK_LEN: u64 = 1 << 20;
+
+/// Whether a value is stored in blocks when it's committed. Only Handles with partial eviction
+/// store values in blocks. Compressed and encrypted values can't be read in part, and values with
+/// a single part have nothing to gain. The result is kept in keys.in_blocks.
+pub(crate) fn in_blocks(length: u64, encoded: bool, partial_eviction: bool) -> bool {
+    partial_eviction && !encoded && length >= 2 * BLOCK_LEN
+}
+
+/// The parts of a value of length stored in blocks, as offsets within the value.
+pub(crate) fn part_ranges(length: u64) -> impl Iterator<Item = Range<u64>> {
+    (0..length)
+        .step_by(BLOCK_LEN as usize)
+        .map(move |start| start..min(start + BLOCK_LEN, length))
+}
+
+/// Inserts the parts of the value of a key that was just inserted. Parts that duplicate a block
+/// found before the commit share it if it's still there, and the rest get a block of their own.
+/// Returns the locations of the parts that share another block, which need punching.
+pub(crate) fn insert_parts(
+    tx: &rusqlite::Transaction<'_>,
+    key_id: i64,
+    pw: &PendingWrite,
+) -> rusqlite::Result<Vec<NonzeroValueLocation>> {
+    let mut duplicated = vec![];
+    for (index, range) in part_ranges(pw.value_length).enumerate() {
+        let location = NonzeroValueLocation {
+            file_id: pw.value_file_id,
+            file_offset: pw.value_file_offset + range.start,
+            length: range.end - range.start,
+        };
+        let block_hash = pw
+            .checksums
+            .as_ref()
+            .map(|checksums| checksums.range_hash(range.start, location.length));
+        let existing = match (
+            pw.duplicate_blocks.get(index).copied().flatten(),
+            block_hash,
+        ) {
+            (Some(duplicate), Some(block_hash)) => existing_block(tx, &duplicate, block_hash)?,
+            _ => None,
+        };
+        let block_id: i64 = match existing {
+            Some(block_id) => {
+                debug!(?location, block_id, "deduplicated block");
+                // The block is as recently used as the value now sharing it.
+                tx.prepare_cached(
+                    "update blocks set last_used=max(last_used, \
+                        (select last_used from keys where key_id=?)) \
+                    where block_id=?",
+                )?
+                .execute(params![key_id, block_id])?;
+                duplicated.push(location);
+                block_id
+            }
+            None => tx
+                .prepare_cached(
+                    "insert into blocks (file_id, file_offset, block_length, block_hash, last_used) \
+                    values (?, ?, ?, ?, (select last_used from keys where key_id=?)) \
+                    returning block_id",
+                )?
+                .query_row(
+                    params![
+                        location.file_id,
+                        location.file_offset,
+                        location.length,
+                        block_hash,
+                        key_id
+                    ],
+                    |row| row.get(0),
+                )?,
+        };
+        tx.prepare_cached(
+            "insert into value_parts (key_id, part_offset, part_length, block_id) \
+            values (?, ?, ?, ?)",
+        )?
+        .execute(params![key_id, range.start, location.length, block_id])?;
+    }
+    Ok(duplicated)
+}
+
+/// The block at a location found by dedup::find_duplicate_blocks, if it still has the same content.
+/// Blocks aren't rewritten in place while they exist.
+fn existing_block(
+    tx: &rusqlite::Transaction<'_>,
+    location: &NonzeroValueLocation,
+    block_hash: i64,
+) -> rusqlite::Result<Option<i64>> {
+    tx.prepare_cached(
+        "select block_id from blocks \
+        where file_id=? and file_offset=? and block_length=? and block_hash=?",
+    )?
+    .query_row(
+        params![
+            location.file_id,
+            location.file_offset,
+            location.length,
+            block_hash
+        ],
+        |row| row.get(0),
+    )
+    .optional()
+}
+
+/// Removes the blocks released in the transaction so far, and returns their locations to be
+/// punched. Blocks are released by triggers when nothing refers to them anymore.
+pub(crate) fn take_released(
+    tx: &rusqlite::Transaction<'_>,
+) -> rusqlite::Result<Vec<NonzeroValueLocation>> {
+    tx.prepare_cached("delete from released_blocks returning file_id, file_offset, block_length")?
+        .query_map([], |row| {
+            Ok(NonzeroValueLocation {
+                file_id: row.get(0)?,
+                file_offset: row.get(1)?,
+                length: row.get(2)?,
+            })
+        })?
+        .collect()
+}
+
+/// The parts of a key's value, if it's stored in blocks, ordered by offset.
+pub(crate) fn value_parts(
+    tx: &rusqlite::Transaction<'_>,
+    key_id: i64,
+) -> rusqlite::Result<Vec<ValuePart>> {
+    tx.prepare_cached(
+        "select part_offset, part_length, block_id, blocks.file_id, blocks.file_offset \
+        from value_parts left join blocks using (block_id) \
+        where key_id=? order by part_offset",
+    )?
+    .query_map([key_id], |row| {
+        let part_offset: u64 = row.get(0)?;
+        let block_id: Option<i64> = row.get(2)?;
+        Ok(ValuePart {
+            range: part_offset..part_offset + row.get::<_, u64>(1)?,
+            block: match block_id {
+                Some(block_id) => Some(BlockLocation {
+                    block_id,
+                    file_id: row.get(3)?,
+                    file_offset: row.get(4)?,
+                }),
+                None => None,
+            },
+        })
+    })?
+    .collect()
+}
+
+/// A part of a value stored in blocks, as a reader found it.
+#[derive(Debug, Clone)]
+pub(crate) struct ValuePart {
+    /// Offsets within the value.
+    pub(crate) range: Range<u64>,
+    /// None if the part was evicted.
+    pub(crate) block: Option<BlockLocation>,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct BlockLocation {
+    pub(crate) block_id: i64,
+    pub(crate) file_id: FileId,
+    pub(crate) file_offset: u64,
+}
+
+/// Where the parts of a snapshot value stored in blocks are, with the snapshot's clones of the
+/// files they're in.
+#[derive(Debug)]
+pub(crate) struct BlockMap {
+    parts: Vec<ValuePart>,
+    files: FileCloneCache,
+    // The evicted parts, merged into ranges.
+    missing: Vec<Range<u64>>,
+}
+
+impl BlockMap {
+    pub(crate) fn new(parts: Vec<ValuePart>, file_clones: &FileCloneCache) -> Self {
+        let files = parts
+            .iter()
+            .filter_map(|part| part.block.as_ref())
+            .map(|block| (block.file_id, Arc::clone(&file_clones[&block.file_id])))
+            .collect();
+        let mut missing: Vec<Range<u64>> = vec![];
+        for part in parts.iter().filter(|part| part.block.is_none()) {
+            match missing.last_mut() {
+                Some(last) if last.end == part.range.start => last.end = part.range.end,
+                _ => missing.push(part.range.clone()),
+            }
+        }
+        Self {
+            parts,
+            files,
+            missing,
+        }
+    }
+
+    pub(crate) fn parts(&self) -> &[ValuePart] {
+        &self.parts
+    }
+
+    pub(crate) fn missing(&self) -> &[Range<u64>] {
+        &self.missing
+    }
+
+    /// The part containing the value offset, if it's in the value.
+    pub(crate) fn part_at(&self, offset: u64) -> Option<&ValuePart> {
+        let index = self.parts.partition_point(|part| part.range.end <= offset);
+        self.parts.get(index)
+    }
+
+    pub(crate) fn file(&self, block: &BlockLocation) -> &Arc<Mutex<FileClone>> {
+        &self.files[&block.file_id]
+    }
+
+    /// Whether every part is in the block at its own location, so the value can be read as it was
+    /// written.
+    pub(crate) fn in_place(&self, location: &NonzeroValueLocation) -> bool {
+        self.parts.iter().all(|part| {
+            part.block.as_ref().is_some_and(|block| {
+                block.file_id == location.file_id
+                    && block.file_offset == location.file_offset + part.range.start
+            })
+        })
+    }
+}
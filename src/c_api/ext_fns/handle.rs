@@ -139,7 +139,7 @@ pub extern "C" fn possum_single_read_at(
         started.elapsed(),
         read_buf.len(),
         offset,
-        value.length(),
+        value.uncompressed_length(),
         r_nbyte,
         rust_key.escape_ascii(),
     );
@@ -175,6 +175,11 @@ pub extern "C" fn possum_reader_new(
 ) -> PossumError {
     let handle = unwrap_possum_handle(handle).clone();
     let reader = unsafe { reader.as_mut() }.unwrap();
+    // Taken before the transaction, as in Handle::read.
+    let clone_lock = match CloneLock::shared(&handle.read().unwrap().dir) {
+        Ok(ok) => ok,
+        Err(err) => return err.into(),
+    };
     let owned_tx_res = handle.start_transaction(
         // This is copied from Handle::start_writable_transaction_with_behaviour and Handle::read
         // until I make proper abstractions.
@@ -190,6 +195,9 @@ pub extern "C" fn possum_reader_new(
     let rust_reader = Reader {
         owned_tx,
         reads: Default::default(),
+        blocks: Default::default(),
+        checksums: Default::default(),
+        _clone_lock: clone_lock,
     };
     *reader = Box::into_raw(Box::new(PossumReader {
         rust_reader: Some(rust_reader),
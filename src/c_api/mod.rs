@@ -50,7 +50,7 @@ where
     fn from(value: V) -> Self {
         let value = value.as_ref();
         Self {
-            size: value.length(),
+            size: value.uncompressed_length(),
             last_used: value.last_used().into(),
         }
     }
@@ -87,7 +87,7 @@ fn items_list_to_c(
             },
             stat: PossumStat {
                 last_used: item.value.last_used().into(),
-                size: item.value.length(),
+                size: item.value.uncompressed_length(),
             },
         };
         unsafe {
@@ -115,6 +115,9 @@ impl From<Error> for PossumError {
             Error::Io(_) => IoError,
             Error::Anyhow(_) => AnyhowError,
             Error::UnsupportedFilesystem => UnsupportedFilesystem,
+            Error::Corrupt { .. } => CorruptValue,
+            Error::PartiallyEvicted { .. } => PartiallyEvicted,
+            Error::ReadOnly => ReadOnly,
         }
     }
 }
@@ -126,14 +129,23 @@ impl From<rusqlite::Error> for PossumError {
 }
 
 impl From<io::Error> for PossumError {
-    fn from(_value: io::Error) -> Self {
-        IoError
+    fn from(value: io::Error) -> Self {
+        // Reads return corruption and partial eviction through io::Error.
+        match value.get_ref().and_then(|inner| inner.downcast_ref()) {
+            Some(Error::Corrupt { .. }) => CorruptValue,
+            Some(Error::PartiallyEvicted { .. }) => PartiallyEvicted,
+            _ => IoError,
+        }
     }
 }
 
 impl From<anyhow::Error> for PossumError {
-    fn from(_value: anyhow::Error) -> Self {
-        AnyhowError
+    fn from(value: anyhow::Error) -> Self {
+        // Writers fail on read-only Handles through anyhow.
+        match value.downcast_ref() {
+            Some(Error::ReadOnly) => ReadOnly,
+            _ => AnyhowError,
+        }
     }
 }
 
@@ -159,6 +171,7 @@ impl From<PossumLimits> for handle::Limits {
                 otherwise => Some(otherwise),
             },
             disable_hole_punching: from.disable_hole_punching,
+            ..Default::default()
         }
     }
 }
@@ -46,6 +46,9 @@ pub enum PossumError {
     IoError,
     AnyhowError,
     UnsupportedFilesystem,
+    CorruptValue,
+    PartiallyEvicted,
+    ReadOnly,
 }
 // TODO: Merge the C and Rust error types.
 // pub use crate::Error as PossumError;
@@ -0,0 +1,547 @@
+//! Checks a directory for inconsistencies between the manifest and the files it manages, and
+//! optionally repairs them.
+
+use seekhole::{file_regions, RegionType};
+use walk::EntryType;
+
+use super::*;
+
+#[derive(Debug, Default, Clone)]
+pub struct CheckOptions {
+    /// Fix the issues found. Keys with damaged values are deleted, unreferenced data is punched,
+    /// stray files are removed, and sums are recomputed. Values of deleted keys aren't punched
+    /// until the next check, in case they overlap with something else.
+    pub repair: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IssueKind {
+    /// A key refers to a values file that doesn't exist.
+    MissingValuesFile {
+        key: Vec<u8>,
+        location: NonzeroValueLocation,
+    },
+    /// A key's value extends past the end of its values file.
+    ValuePastEof {
+        key: Vec<u8>,
+        location: NonzeroValueLocation,
+        file_len: u64,
+    },
+    /// Two keys refer to overlapping parts of a values file.
+    OverlappingValues { file_id: FileId, keys: [Vec<u8>; 2] },
+    /// Whole blocks of a values file contain data that no key refers to.
+    UnreferencedData {
+        file_id: FileId,
+        offset: u64,
+        length: u64,
+    },
+    /// A hole has been punched in a value that's still referenced.
+    HoleInValue {
+        key: Vec<u8>,
+        location: NonzeroValueLocation,
+        hole_offset: u64,
+    },
+    /// A snapshot dir or snapshot value that's not in use.
+    StraySnapshot { path: PathBuf },
+    /// A file left over from testing file clone support.
+    StrayCloneTest { path: PathBuf },
+    /// A row in the sums table doesn't match the keys table.
+    SumMismatch {
+        name: String,
+        stored: u64,
+        actual: u64,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Issue {
+    pub kind: IssueKind,
+    pub repaired: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct CheckReport {
+    pub issues: Vec<Issue>,
+    /// Values files that couldn't be checked for holes and unreferenced data because they're locked
+    /// by writers or the value puncher, or when repairing, because readers are taking snapshots.
+    pub files_in_use: Vec<FileId>,
+}
+
+impl CheckReport {
+    /// Whether there are no issues left unrepaired.
+    pub fn is_clean(&self) -> bool {
+        self.issues.iter().all(|issue| issue.repaired)
+    }
+
+    fn push(&mut self, kind: IssueKind, repaired: bool) {
+        warn!(?kind, repaired, "check issue");
+        self.issues.push(Issue { kind, repaired });
+    }
+}
+
+/// A key and where its value is, for the key being checked.
+pub(crate) struct KeyValue {
+    pub(crate) key_id: i64,
+    pub(crate) key: Vec<u8>,
+    pub(crate) location: NonzeroValueLocation,
+}
+
+impl KeyValue {
+    pub(crate) fn end(&self) -> u64 {
+        self.location.file_offset + self.location.length
+    }
+}
+
+impl Handle {
+    /// Checks the directory for damage, and repairs it if options.repair is set. Values files that
+    /// are in use by this or other Handles are only partially checked.
+    pub fn check(&self, options: CheckOptions) -> Result<CheckReport> {
+        if options.repair {
+            self.check_writable()?;
+        }
+        let mut report = CheckReport::default();
+        let mut bad_keys = vec![];
+        // Values files are created before any keys refer to them, so look at the keys first.
+        let mut keys_by_file: HashMap<FileId, Vec<KeyValue>> = Default::default();
+        {
+            let tx = self.start_deferred_transaction_for_read()?;
+            for key_value in all_key_values(&tx.0)? {
+                keys_by_file
+                    .entry(key_value.location.file_id)
+                    .or_default()
+                    .push(key_value);
+            }
+        }
+        let entries = self.walk_dir()?;
+        let values_files: HashSet<FileId> = entries
+            .iter()
+            .filter(|entry| entry.entry_type == EntryType::ValuesFile)
+            .filter_map(|entry| entry.file_id())
+            .collect();
+        for (file_id, key_values) in &keys_by_file {
+            if values_files.contains(file_id) {
+                continue;
+            }
+            for key_value in key_values {
+                report.push(
+                    IssueKind::MissingValuesFile {
+                        key: key_value.key.clone(),
+                        location: key_value.location,
+                    },
+                    options.repair,
+                );
+                bad_keys.push(key_value.location);
+            }
+        }
+        for file_id in values_files {
+            self.check_values_file(file_id, &options, &mut report, &mut bad_keys)
+                .with_context(|| format!("checking values file {}", file_id))?;
+        }
+        for entry in entries {
+            check_stray_entry(&entry, &options, &mut report)?;
+        }
+        self.check_manifest(bad_keys, &options, &mut report)?;
+        Ok(report)
+    }
+
+    fn check_values_file(
+        &self,
+        file_id: FileId,
+        options: &CheckOptions,
+        report: &mut CheckReport,
+        bad_keys: &mut Vec<NonzeroValueLocation>,
+    ) -> Result<()> {
+        // Repairs punch unreferenced data, which readers that started before it was deleted may
+        // still be about to clone or lock. Readers can be kept from taking their snapshots
+        // indefinitely, including by the caller, so the file is reported busy instead of waiting.
+        let _clone_lock = if options.repair {
+            let Some(clone_lock) = CloneLock::try_exclusive(&self.dir)? else {
+                report.files_in_use.push(file_id);
+                return Ok(());
+            };
+            Some(clone_lock)
+        } else {
+            None
+        };
+        let path = file_path(self.dir.path(), file_id);
+        // Only repairs write, and read-only Handles may not have write permission.
+        let mut file = match OpenOptions::new()
+            .read(true)
+            .write(options.repair)
+            .open(&path)
+        {
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+            result => result?,
+        };
+        // This keeps out writers and the value puncher. Readers lock values with shared locks while
+        // they hold the clone lock and expect them to succeed, so an exclusive lock would break
+        // them.
+        if !file.lock_max_segment(LockSharedNonblock)? {
+            report.files_in_use.push(file_id);
+            return Ok(());
+        }
+        // Get the keys again now that nothing can change in the file, so recently deleted values
+        // aren't mistaken for unreferenced data.
+        let (key_values, unstored_ranges, blocks) = {
+            let tx = self.start_deferred_transaction_for_read()?;
+            (
+                file_key_values(&tx.0, file_id)?,
+                file_unstored_ranges(&tx.0, file_id)?,
+                file_blocks(&tx.0, file_id)?,
+            )
+        };
+        let file_len = file.metadata()?.len();
+        let regions = file_regions(&mut file)?;
+        let mut good = Vec::with_capacity(key_values.len());
+        let mut overlapped: Option<&KeyValue> = None;
+        for key_value in &key_values {
+            if key_value.end() > file_len {
+                report.push(
+                    IssueKind::ValuePastEof {
+                        key: key_value.key.clone(),
+                        location: key_value.location,
+                        file_len,
+                    },
+                    options.repair,
+                );
+                bad_keys.push(key_value.location);
+                continue;
+            }
+            // Values are ordered by offset, so only the furthest reaching value so far can overlap.
+            // Keys sharing a deduplicated value have the same location.
+            if let Some(prev) = overlapped {
+                if key_value.location.file_offset < prev.end()
+                    && key_value.location != prev.location
+                {
+                    report.push(
+                        IssueKind::OverlappingValues {
+                            file_id,
+                            keys: [prev.key.clone(), key_value.key.clone()],
+                        },
+                        options.repair,
+                    );
+                    bad_keys.push(prev.location);
+                    bad_keys.push(key_value.location);
+                }
+            }
+            if overlapped.is_none_or(|prev| key_value.end() > prev.end()) {
+                overlapped = Some(key_value);
+            }
+            good.push(key_value);
+        }
+        let mut holes = regions
+            .iter()
+            .filter(|region| region.region_type == RegionType::Hole)
+            .peekable();
+        for key_value in &good {
+            // Values are ordered by offset, so holes that end before this one can be skipped.
+            while holes
+                .next_if(|hole| hole.end <= key_value.location.file_offset)
+                .is_some()
+            {}
+            let location = &key_value.location;
+            let unstored = unstored_ranges.get(&key_value.key_id);
+            while let Some(hole) = holes.peek().filter(|hole| hole.start < key_value.end()) {
+                // Parts that were evicted or share a block elsewhere are expected to be punched.
+                let start = max(hole.start, location.file_offset) - location.file_offset;
+                let end = min(hole.end, key_value.end()) - location.file_offset;
+                if !unstored.is_some_and(|ranges| {
+                    ranges
+                        .iter()
+                        .any(|range| range.start <= start && end <= range.end)
+                }) {
+                    report.push(
+                        IssueKind::HoleInValue {
+                            key: key_value.key.clone(),
+                            location: key_value.location,
+                            hole_offset: hole.start,
+                        },
+                        options.repair,
+                    );
+                    bad_keys.push(key_value.location);
+                    break;
+                }
+                // Holes that reach past this value can be in the following values too.
+                if hole.end > key_value.end() {
+                    break;
+                }
+                holes.next();
+            }
+        }
+        let block_size = self.block_size();
+        // Blocks can outlive the value they were written with, if other values share them.
+        let mut referenced = good
+            .iter()
+            .map(|key_value| key_value.location.file_offset..key_value.end())
+            .chain(blocks)
+            .collect::<Vec<_>>();
+        referenced.sort_unstable_by_key(|range| range.start);
+        let mut referenced = referenced.into_iter().peekable();
+        for data in regions
+            .iter()
+            .filter(|region| region.region_type == RegionType::Data)
+        {
+            let mut offset = data.start;
+            let mut gaps = vec![];
+            while offset < data.end {
+                // Skip what ends before this point.
+                while referenced.next_if(|range| range.end <= offset).is_some() {}
+                let gap_end = match referenced.peek() {
+                    Some(range) => min(range.start, data.end),
+                    None => data.end,
+                };
+                if gap_end > offset {
+                    gaps.push((offset, gap_end));
+                }
+                offset = match referenced.peek() {
+                    Some(range) => max(gap_end, range.end),
+                    None => data.end,
+                };
+            }
+            for (start, end) in gaps {
+                // Only whole blocks can be punched.
+                let start = ceil_multiple(start, block_size);
+                let end = end / block_size * block_size;
+                if end <= start {
+                    continue;
+                }
+                let length = end - start;
+                // Readers that took their snapshots before the data was deleted may still have it
+                // locked.
+                let repaired = options.repair
+                    && file.lock_segment(LockExclusiveNonblock, Some(length), start)?;
+                if repaired {
+                    punchfile(&file, start, length)?;
+                }
+                report.push(
+                    IssueKind::UnreferencedData {
+                        file_id,
+                        offset: start,
+                        length,
+                    },
+                    repaired,
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Deletes bad keys and checks the sums.
+    fn check_manifest(
+        &self,
+        bad_keys: Vec<NonzeroValueLocation>,
+        options: &CheckOptions,
+        report: &mut CheckReport,
+    ) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        let behaviour = if options.repair {
+            TransactionBehavior::Immediate
+        } else {
+            TransactionBehavior::Deferred
+        };
+        let tx = rusqlite::Transaction::new_unchecked(&conn, behaviour)?;
+        if options.repair {
+            // Only delete the keys if they still refer to the same value.
+            let mut stmt = tx.prepare_cached(
+                "delete from keys where file_id=? and file_offset=? and value_length=?",
+            )?;
+            for location in bad_keys {
+                stmt.execute(params![
+                    location.file_id,
+                    location.file_offset,
+                    location.length
+                ])?;
+            }
+        }
+        let block_size: u64 = tx.query_row(
+            "select value from settings where name='block_size'",
+            [],
+            |row| row.get(0),
+        )?;
+        // Values shared by deduplicated keys are counted once, and values stored in blocks are
+        // counted by their blocks.
+        let sums = [
+            (
+                "value_length",
+                tx.query_row(
+                    "select coalesce(sum(value_length), 0) \
+                    + (select coalesce(sum(block_length), 0) from blocks) \
+                    from (select distinct file_id, file_offset, value_length from keys \
+                        where key_id not in (select key_id from value_parts))",
+                    [],
+                    |row| row.get(0),
+                )?,
+            ),
+            (
+                "block_rounded_value_length",
+                tx.query_row(
+                    "select coalesce(sum((value_length+?1-1)/?1*?1), 0) \
+                    + (select coalesce(sum((block_length+?1-1)/?1*?1), 0) from blocks) \
+                    from (select distinct file_id, file_offset, value_length from keys \
+                        where key_id not in (select key_id from value_parts))",
+                    [block_size],
+                    |row| row.get(0),
+                )?,
+            ),
+        ];
+        for (name, actual) in sums {
+            let stored: u64 =
+                tx.query_row("select value from sums where key=?", [name], |row| {
+                    row.get(0)
+                })?;
+            if stored == actual {
+                continue;
+            }
+            if options.repair {
+                tx.execute("update sums set value=? where key=?", params![actual, name])?;
+            }
+            report.push(
+                IssueKind::SumMismatch {
+                    name: name.to_owned(),
+                    stored,
+                    actual,
+                },
+                options.repair,
+            );
+        }
+        tx.commit()?;
+        Ok(())
+    }
+}
+
+fn check_stray_entry(
+    entry: &walk::Entry,
+    options: &CheckOptions,
+    report: &mut CheckReport,
+) -> Result<()> {
+    let path = &entry.path;
+    match entry.entry_type {
+        EntryType::SnapshotDir => {
+            if read_dir(path)?.next().is_some() {
+                return Ok(());
+            }
+            if options.repair {
+                remove_dir(path)?;
+            }
+            report.push(
+                IssueKind::StraySnapshot { path: path.clone() },
+                options.repair,
+            );
+        }
+        EntryType::SnapshotValue => {
+            // Only repairs write, and the directory may not be writable otherwise.
+            let file = match OpenOptions::new()
+                .read(true)
+                .write(options.repair)
+                .open(path)
+            {
+                Err(err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+                result => result?,
+            };
+            // Snapshot values are locked while they're in use.
+            let unused = if options.repair {
+                file.lock_max_segment(LockExclusiveNonblock)?
+            } else {
+                file.unlocked()?
+            };
+            if !unused {
+                return Ok(());
+            }
+            if options.repair {
+                remove_file(path)?;
+                // This is how empty snapshot dirs are normally cleaned up.
+                let _ = remove_dir(path.parent().unwrap());
+            }
+            report.push(
+                IssueKind::StraySnapshot { path: path.clone() },
+                options.repair,
+            );
+        }
+        EntryType::CloneTestFile => {
+            if options.repair {
+                remove_file(path)?;
+            }
+            report.push(
+                IssueKind::StrayCloneTest { path: path.clone() },
+                options.repair,
+            );
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+const KEY_VALUE_COLUMNS: &str = "key, file_id, file_offset, value_length, key_id";
+
+fn key_value_from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<KeyValue> {
+    Ok(KeyValue {
+        key_id: row.get(4)?,
+        key: row.get(0)?,
+        location: NonzeroValueLocation {
+            file_id: row.get(1)?,
+            file_offset: row.get(2)?,
+            length: row.get(3)?,
+        },
+    })
+}
+
+fn all_key_values(tx: &rusqlite::Transaction<'_>) -> rusqlite::Result<Vec<KeyValue>> {
+    tx.prepare(&format!(
+        "select {} from keys where file_id is not null order by file_id, file_offset",
+        KEY_VALUE_COLUMNS
+    ))?
+    .query_map([], key_value_from_row)?
+    .collect()
+}
+
+/// The ranges of the values in a file that aren't stored where the value is, by key. They're parts
+/// of values stored in blocks that were evicted or share a block elsewhere, and are expected to be
+/// punched.
+pub(crate) fn file_unstored_ranges(
+    tx: &rusqlite::Transaction<'_>,
+    file_id: FileId,
+) -> rusqlite::Result<HashMap<i64, Vec<Range<u64>>>> {
+    let mut unstored: HashMap<i64, Vec<Range<u64>>> = Default::default();
+    let mut stmt = tx.prepare_cached(
+        "select key_id, part_offset, part_length \
+        from keys join value_parts using (key_id) left join blocks using (block_id) \
+        where keys.file_id=?1 and not (\
+            blocks.file_id is ?1 and blocks.file_offset is keys.file_offset+part_offset\
+        ) order by key_id, part_offset",
+    )?;
+    let mut rows = stmt.query([file_id])?;
+    while let Some(row) = rows.next()? {
+        let start: u64 = row.get(1)?;
+        let end = start + row.get::<_, u64>(2)?;
+        let ranges = unstored.entry(row.get(0)?).or_default();
+        match ranges.last_mut() {
+            Some(last) if last.end == start => last.end = end,
+            _ => ranges.push(start..end),
+        }
+    }
+    Ok(unstored)
+}
+
+/// The blocks in a file, as ranges of the file ordered by offset.
+fn file_blocks(
+    tx: &rusqlite::Transaction<'_>,
+    file_id: FileId,
+) -> rusqlite::Result<Vec<Range<u64>>> {
+    tx.prepare_cached(
+        "select file_offset, file_offset+block_length from blocks where file_id=? order by file_offset",
+    )?
+    .query_map([file_id], |row| Ok(row.get(0)?..row.get(1)?))?
+    .collect()
+}
+
+pub(crate) fn file_key_values(
+    tx: &rusqlite::Transaction<'_>,
+    file_id: FileId,
+) -> rusqlite::Result<Vec<KeyValue>> {
+    tx.prepare_cached(&format!(
+        "select {} from keys where file_id=? order by file_offset",
+        KEY_VALUE_COLUMNS
+    ))?
+    .query_map([file_id], key_value_from_row)?
+    .collect()
+}
@@ -0,0 +1,187 @@
+//! Checksums of values as stored on disk, for detecting corrupted or wrongly punched values. Values
+//! are checksummed in chunks so that reads of part of a value can be verified without reading the
+//! rest of it.
+
+use std::hash::Hasher;
+
+use twox_hash::XxHash64;
+
+use super::*;
+
+/// Stored bytes covered by each checksum. The last chunk of a value may be shorter.
+pub(crate) const CHECKSUM_CHUNK_LEN: u64 = 64 << 10;
+
+/// The checksums of consecutive chunks of a value, as stored in the manifest.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Checksums(Arc<[u64]>);
+
+impl Checksums {
+    fn chunk_checksum(chunk: &[u8]) -> u64 {
+        let mut hasher = XxHash64::default();
+        hasher.write(chunk);
+        hasher.finish()
+    }
+
+    /// Checks stored bytes starting at offset, which must be the start of a chunk. bytes must end
+    /// at the end of a chunk or the value. Returns the index of the first chunk that doesn't
+    /// match.
+    pub(crate) fn verify(&self, offset: u64, bytes: &[u8]) -> std::result::Result<(), u64> {
+        assert_eq!(offset % CHECKSUM_CHUNK_LEN, 0);
+        let first_chunk = offset / CHECKSUM_CHUNK_LEN;
+        for (index, chunk) in bytes.chunks(CHECKSUM_CHUNK_LEN as usize).enumerate() {
+            let chunk_index = first_chunk + index as u64;
+            let expected = usize::try_from(chunk_index)
+                .ok()
+                .and_then(|index| self.0.get(index));
+            if expected != Some(&Self::chunk_checksum(chunk)) {
+                return Err(chunk_index);
+            }
+        }
+        Ok(())
+    }
+
+    /// Checksums length bytes of a file from offset.
+    pub(crate) fn of_stored(file: &File, offset: u64, length: u64) -> io::Result<Self> {
+        let mut checksummer = Checksummer::default();
+        let mut buf = vec![];
+        let mut pos = 0;
+        while pos < length {
+            buf.resize(min(CHECKSUM_CHUNK_LEN, length - pos) as usize, 0);
+            file.read_exact_at(offset + pos, &mut buf)?;
+            checksummer.update(&buf);
+            pos += buf.len() as u64;
+        }
+        checksummer
+            .finish()
+            .ok_or_else(|| io::Error::new(InvalidInput, "empty value"))
+    }
+
+    /// A hash of all the checksums, used to find values with the same stored bytes.
+    pub(crate) fn content_hash(&self) -> i64 {
+        let mut hasher = XxHash64::default();
+        for checksum in self.0.iter() {
+            hasher.write_u64(*checksum);
+        }
+        // Stored as a signed sqlite integer.
+        hasher.finish() as i64
+    }
+
+    /// Like content_hash, for the chunks of length bytes from offset, which must be the start of a
+    /// chunk. Used to find blocks with the same stored bytes.
+    pub(crate) fn range_hash(&self, offset: u64, length: u64) -> i64 {
+        assert_eq!(offset % CHECKSUM_CHUNK_LEN, 0);
+        let first = (offset / CHECKSUM_CHUNK_LEN) as usize;
+        let end = ceil_multiple(offset + length, CHECKSUM_CHUNK_LEN) / CHECKSUM_CHUNK_LEN;
+        let mut hasher = XxHash64::default();
+        for checksum in &self.0[first..min(end as usize, self.0.len())] {
+            hasher.write_u64(*checksum);
+        }
+        hasher.finish() as i64
+    }
+}
+
+/// The checksums stored for a key's value, if it has any.
+pub(crate) fn key_checksums(
+    tx: &rusqlite::Transaction<'_>,
+    key_id: i64,
+) -> rusqlite::Result<Option<Checksums>> {
+    tx.prepare_cached("select checksums from keys where key_id=?")?
+        .query_row([key_id], |row| row.get(0))
+}
+
+impl ToSql for Checksums {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(self
+            .0
+            .iter()
+            .flat_map(|checksum| checksum.to_le_bytes())
+            .collect::<Vec<_>>()
+            .into())
+    }
+}
+
+impl FromSql for Checksums {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        let blob = value.as_blob()?;
+        if blob.len() % 8 != 0 {
+            return Err(FromSqlError::InvalidBlobSize {
+                expected_size: blob.len() / 8 * 8,
+                blob_size: blob.len(),
+            });
+        }
+        Ok(Self(
+            blob.chunks_exact(8)
+                .map(|bytes| u64::from_le_bytes(bytes.try_into().unwrap()))
+                .collect(),
+        ))
+    }
+}
+
+/// Computes the checksums of a value as it's written.
+#[derive(Default)]
+pub(crate) struct Checksummer {
+    hasher: XxHash64,
+    // Bytes hashed in the current chunk.
+    chunk_len: u64,
+    checksums: Vec<u64>,
+}
+
+impl Debug for Checksummer {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Checksummer")
+            .field("chunk_len", &self.chunk_len)
+            .field("checksums", &self.checksums.len())
+            .finish()
+    }
+}
+
+impl Checksummer {
+    pub(crate) fn update(&mut self, mut buf: &[u8]) {
+        while !buf.is_empty() {
+            let n = min(buf.len() as u64, CHECKSUM_CHUNK_LEN - self.chunk_len) as usize;
+            self.hasher.write(&buf[..n]);
+            self.chunk_len += n as u64;
+            buf = &buf[n..];
+            if self.chunk_len == CHECKSUM_CHUNK_LEN {
+                self.end_chunk();
+            }
+        }
+    }
+
+    fn end_chunk(&mut self) {
+        let hasher = std::mem::take(&mut self.hasher);
+        self.checksums.push(hasher.finish());
+        self.chunk_len = 0;
+    }
+
+    /// Returns None if nothing was written, since empty values aren't stored.
+    pub(crate) fn finish(mut self) -> Option<Checksums> {
+        if self.chunk_len != 0 {
+            self.end_chunk();
+        }
+        if self.checksums.is_empty() {
+            return None;
+        }
+        Some(Checksums(self.checksums.into()))
+    }
+}
+
+/// Writes to a values file, checksumming what's written if there's a checksummer.
+pub(crate) struct ChecksummedWriter<'a> {
+    pub(crate) file: &'a mut File,
+    pub(crate) checksummer: Option<&'a mut Checksummer>,
+}
+
+impl Write for ChecksummedWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.file.write(buf)?;
+        if let Some(checksummer) = &mut self.checksummer {
+            checksummer.update(&buf[..n]);
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
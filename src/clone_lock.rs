@@ -0,0 +1,61 @@
+//! Readers look up values without the manifest write lock, so something else has to stop values
+//! being punched, truncated or removed before a reader has cloned or locked them. Readers hold a
+//! shared lock on the clone lock file from their first lookup until their snapshot is taken.
+//! Anything that destroys values data, or takes exclusive locks on it, holds an exclusive lock
+//! while it does, and puts off its work if it can't get one. Values deleted before then can't be
+//! seen by readers that start after, and readers that saw them have finished taking their
+//! snapshots.
+
+use super::*;
+
+pub(crate) const CLONE_LOCK_FILE_NAME: &str = "clone.lock";
+
+/// A lock on a directory's clone lock file. It's released when dropped.
+#[derive(Debug)]
+pub(crate) struct CloneLock {
+    // None if the file doesn't exist and the Handle is read-only.
+    _file: Option<File>,
+}
+
+impl CloneLock {
+    /// Creates the lock file if it doesn't exist, so read-only Handles can lock it too.
+    pub(crate) fn create_file(dir: &Dir) -> io::Result<()> {
+        Self::open_for_exclusive(dir).map(drop)
+    }
+
+    fn open_for_exclusive(dir: &Dir) -> io::Result<File> {
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(dir.path().join(CLONE_LOCK_FILE_NAME))
+    }
+
+    /// Waits for a shared lock, for a reader.
+    pub(crate) fn shared(dir: &Dir) -> io::Result<Self> {
+        let file = match OpenOptions::new()
+            .read(true)
+            .open(dir.path().join(CLONE_LOCK_FILE_NAME))
+        {
+            // Read-write Handles create it when they open, and nothing is destroyed without it.
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                debug!("no clone lock file");
+                return Ok(Self { _file: None });
+            }
+            result => result?,
+        };
+        assert!(file.lock_max_segment(LockShared)?);
+        Ok(Self { _file: Some(file) })
+    }
+
+    /// Keeps new readers from starting, if no reader is still taking its snapshot. Otherwise it
+    /// returns None instead of waiting, since a Reader can be held open indefinitely.
+    pub(crate) fn try_exclusive(dir: &Dir) -> io::Result<Option<Self>> {
+        let file = Self::open_for_exclusive(dir)?;
+        if !file.lock_max_segment(LockExclusiveNonblock)? {
+            return Ok(None);
+        }
+        Ok(Some(Self { _file: Some(file) }))
+    }
+}
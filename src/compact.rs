@@ -0,0 +1,343 @@
+//! Reclaims space from values files that hole punching can't, by copying their live values into a
+//! new values file and removing the old one.
+
+use check::{file_key_values, file_unstored_ranges, KeyValue};
+use walk::EntryType;
+
+use super::*;
+
+#[derive(Debug, Clone)]
+pub struct CompactOptions {
+    /// Values files with less than this fraction of their disk allocation referenced by keys are
+    /// compacted.
+    pub max_live_ratio: f64,
+}
+
+impl Default for CompactOptions {
+    fn default() -> Self {
+        Self {
+            max_live_ratio: 0.5,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct CompactReport {
+    /// Values files that had their values moved out.
+    pub files_compacted: Vec<FileId>,
+    /// Compacted values files that were removed. The others still had readers and are removed by
+    /// a later compaction.
+    pub files_removed: Vec<FileId>,
+    pub values_moved: usize,
+    pub bytes_moved: u64,
+    /// Candidates that were skipped because they're locked by writers or the value puncher.
+    pub files_in_use: Vec<FileId>,
+}
+
+impl Handle {
+    /// Moves the values out of values files that are mostly unreferenced. This is for filesystems
+    /// that don't support hole punching, or when it's disabled in the limits.
+    pub fn compact(&self, options: CompactOptions) -> Result<CompactReport> {
+        self.check_writable()?;
+        let mut report = CompactReport::default();
+        let live_bytes: HashMap<FileId, u64> = {
+            let tx = self.start_deferred_transaction_for_read()?;
+            // Values stored in blocks are counted by their blocks, since evicted parts aren't
+            // allocated, and shared blocks are elsewhere.
+            let mut stmt = tx.0.prepare(
+                "select file_id, sum(length) from (\
+                    select file_id, value_length as length from keys \
+                    where file_id is not null and key_id not in (select key_id from value_parts) \
+                    union all select file_id, block_length from blocks\
+                ) group by file_id",
+            )?;
+            let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+            rows.collect::<rusqlite::Result<_>>()?
+        };
+        for entry in self.walk_dir()? {
+            if entry.entry_type != EntryType::ValuesFile {
+                continue;
+            }
+            let Some(file_id) = entry.file_id() else {
+                continue;
+            };
+            let allocated = match path_disk_allocation(&entry.path) {
+                Err(err) if err.kind() == ErrorKind::NotFound => continue,
+                result => result?,
+            };
+            if allocated == 0 {
+                continue;
+            }
+            let live = live_bytes.get(&file_id).copied().unwrap_or_default();
+            if live as f64 >= allocated as f64 * options.max_live_ratio {
+                continue;
+            }
+            self.compact_values_file(file_id, &mut report)
+                .with_context(|| format!("compacting values file {}", file_id))?;
+        }
+        Ok(report)
+    }
+
+    fn compact_values_file(&self, file_id: FileId, report: &mut CompactReport) -> Result<()> {
+        // Stop this Handle from writing to the file, and release its lock on it.
+        drop(self.exclusive_files.lock().unwrap().remove(&file_id));
+        let path = file_path(self.dir.path(), file_id);
+        let src = match OpenOptions::new().read(true).write(true).open(&path) {
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+            result => result?,
+        };
+        // This keeps out writers and the value puncher, but not readers.
+        if !src.lock_max_segment(LockSharedNonblock)? {
+            report.files_in_use.push(file_id);
+            return Ok(());
+        }
+        // Nothing can be added to the file now, so these are the only values to move.
+        let (key_values, unstored_ranges, other_blocks) = {
+            let tx = self.start_deferred_transaction_for_read()?;
+            let key_values = file_key_values(&tx.0, file_id)?;
+            let other_blocks = file_other_blocks(&tx.0, file_id, &key_values)?;
+            (
+                key_values,
+                file_unstored_ranges(&tx.0, file_id)?,
+                other_blocks,
+            )
+        };
+        let mut punch_unstored = false;
+        if !key_values.is_empty() || !other_blocks.is_empty() {
+            punch_unstored = self.move_values(
+                &src,
+                file_id,
+                &key_values,
+                &unstored_ranges,
+                &other_blocks,
+                report,
+            )?;
+        }
+        report.files_compacted.push(file_id);
+        if self.remove_compacted_file(&src, file_id, &path)? {
+            report.files_removed.push(file_id);
+        }
+        // The value puncher takes the clone lock, which would keep the file from being removed.
+        if punch_unstored {
+            self.wake_value_puncher();
+        }
+        Ok(())
+    }
+
+    /// Copies the values and other blocks into an exclusive file and points their keys and blocks
+    /// at the copies. Returns whether punches were queued for the copies.
+    fn move_values(
+        &self,
+        mut src: &File,
+        file_id: FileId,
+        key_values: &[KeyValue],
+        unstored_ranges: &HashMap<i64, Vec<Range<u64>>>,
+        other_blocks: &[OtherBlock],
+        report: &mut CompactReport,
+    ) -> Result<bool> {
+        let mut exclusive_file = self.get_exclusive_file()?;
+        // The exclusive file lock would have prevented the shared lock on the source.
+        assert_ne!(exclusive_file.id, file_id);
+        let dst_id = exclusive_file.id;
+        let mut new_offsets = Vec::with_capacity(key_values.len());
+        let mut new_block_offsets = Vec::with_capacity(other_blocks.len());
+        {
+            // Writing through a separate file lets the copy use copy_file_range, which clones
+            // blocks on filesystems that support it. Exclusive files are opened for appending, which
+            // rules it out.
+            #[cfg(unix)]
+            let mut dst = OpenOptions::new()
+                .write(true)
+                .open(file_path(self.dir.path(), dst_id))?;
+            #[cfg(unix)]
+            dst.seek(Start(exclusive_file.next_write_offset()?))?;
+            // Windows file locks apply to writes through other handles.
+            #[cfg(not(unix))]
+            let mut dst = &mut exclusive_file.inner;
+            for (index, key_value) in key_values.iter().enumerate() {
+                let location = &key_value.location;
+                // Keys sharing a deduplicated value are adjacent, and the value is copied once.
+                if index != 0 && key_values[index - 1].location == *location {
+                    new_offsets.push(*new_offsets.last().unwrap());
+                    continue;
+                }
+                new_offsets.push(dst.stream_position()?);
+                src.seek(Start(location.file_offset))?;
+                let copied = io::copy(&mut src.take(location.length), &mut dst)?;
+                if copied != location.length {
+                    bail!("value at {:?} ends early", location);
+                }
+                report.bytes_moved += copied;
+            }
+            for block in other_blocks {
+                new_block_offsets.push(dst.stream_position()?);
+                src.seek(Start(block.file_offset))?;
+                let copied = io::copy(&mut src.take(block.length), &mut dst)?;
+                if copied != block.length {
+                    bail!("block {} ends early", block.block_id);
+                }
+                report.bytes_moved += copied;
+            }
+        }
+        exclusive_file.inner.seek(End(0))?;
+        if self.durability().sync_values() {
+            exclusive_file.inner.sync_data()?;
+        }
+        let conn = self.conn.lock().unwrap();
+        let tx = rusqlite::Transaction::new_unchecked(&conn, TransactionBehavior::Immediate)?;
+        // Parts of moved values that aren't stored with them were copied as zeroes, and are punched
+        // again.
+        let mut unstored_locations = vec![];
+        {
+            // Keys that were deleted or replaced since they were read are left alone, and their
+            // copies become garbage in the new file.
+            let mut stmt = tx.prepare_cached(
+                "update keys set file_id=?, file_offset=? \
+                where file_id=? and file_offset=? and value_length=?",
+            )?;
+            for (index, (key_value, new_offset)) in key_values.iter().zip(new_offsets).enumerate() {
+                let location = &key_value.location;
+                // The first key sharing a value moves all of them.
+                if index != 0 && key_values[index - 1].location == *location {
+                    continue;
+                }
+                let moved = stmt.execute(params![
+                    dst_id,
+                    new_offset,
+                    file_id,
+                    location.file_offset,
+                    location.length
+                ])?;
+                report.values_moved += moved;
+                if moved == 0 {
+                    continue;
+                }
+                // The blocks at the value's own location move with it.
+                tx.prepare_cached(
+                    "update blocks set file_id=?1, file_offset=file_offset-?3+?4 \
+                    where file_id=?2 and file_offset>=?3 and file_offset<?3+?5",
+                )?
+                .execute(params![
+                    dst_id,
+                    file_id,
+                    location.file_offset,
+                    new_offset,
+                    location.length
+                ])?;
+                // The value puncher only punches whole parts of values that are still referenced.
+                for range in unstored_ranges.get(&key_value.key_id).into_iter().flatten() {
+                    unstored_locations.extend(
+                        (range.start..range.end)
+                            .step_by(blocks::BLOCK_LEN as usize)
+                            .map(|start| NonzeroValueLocation {
+                                file_id: dst_id,
+                                file_offset: new_offset + start,
+                                length: min(blocks::BLOCK_LEN, range.end - start),
+                            }),
+                    );
+                }
+            }
+            // Blocks that were released since they were read are left alone like keys.
+            let mut stmt = tx.prepare_cached(
+                "update blocks set file_id=?, file_offset=? \
+                where block_id=? and file_id=? and file_offset=?",
+            )?;
+            for (block, new_offset) in other_blocks.iter().zip(new_block_offsets) {
+                stmt.execute(params![
+                    dst_id,
+                    new_offset,
+                    block.block_id,
+                    file_id,
+                    block.file_offset
+                ])?;
+            }
+        }
+        let punch_unstored =
+            !unstored_locations.is_empty() && !self.instance_limits.disable_hole_punching;
+        if punch_unstored {
+            punch_queue::enqueue(&tx, &unstored_locations)?;
+        }
+        // Readers may lock the copies as soon as the keys refer to them.
+        if !exclusive_file.committed()? {
+            bail!("committing exclusive file {}", dst_id);
+        }
+        tx.commit()?;
+        drop(conn);
+        {
+            let mut clones = self.clones.lock().unwrap();
+            clones.remove(&file_id);
+            clones.remove(&dst_id);
+        }
+        // See BatchWriter::return_exclusive_files_to_handle.
+        if !flocking() {
+            let mut exclusive_files = self.exclusive_files.lock().unwrap();
+            assert!(exclusive_files.insert(dst_id, exclusive_file).is_none());
+        }
+        Ok(punch_unstored)
+    }
+
+    /// Removes a values file if nothing refers to it and no reader has it locked.
+    fn remove_compacted_file(&self, file: &File, file_id: FileId, path: &Path) -> Result<bool> {
+        // Readers lock values before releasing the clone lock, so the exclusive lock is taken with
+        // it held too. Otherwise a reader could fail to lock a value it was just given.
+        let Some(_clone_lock) = CloneLock::try_exclusive(&self.dir)? else {
+            return Ok(false);
+        };
+        let conn = self.conn.lock().unwrap();
+        let tx = rusqlite::Transaction::new_unchecked(&conn, TransactionBehavior::Immediate)?;
+        let referenced: bool = tx.query_row(
+            "select exists(select 1 from keys where file_id=?1) \
+            or exists(select 1 from blocks where file_id=?1)",
+            [file_id],
+            |row| row.get(0),
+        )?;
+        if referenced || !file.lock_max_segment(LockExclusiveNonblock)? {
+            return Ok(false);
+        }
+        remove_file(path)?;
+        Ok(true)
+    }
+}
+
+/// A block in a values file outside the values of the file's keys. They're left when a value stored
+/// in blocks is deleted while other values share some of its blocks.
+#[derive(Debug)]
+struct OtherBlock {
+    block_id: i64,
+    file_offset: u64,
+    length: u64,
+}
+
+fn file_other_blocks(
+    tx: &rusqlite::Transaction<'_>,
+    file_id: FileId,
+    key_values: &[KeyValue],
+) -> rusqlite::Result<Vec<OtherBlock>> {
+    let mut stmt = tx.prepare_cached(
+        "select block_id, file_offset, block_length from blocks where file_id=? order by file_offset",
+    )?;
+    let blocks = stmt.query_map([file_id], |row| {
+        Ok(OtherBlock {
+            block_id: row.get(0)?,
+            file_offset: row.get(1)?,
+            length: row.get(2)?,
+        })
+    })?;
+    let mut other_blocks = vec![];
+    // Key values are ordered by offset too.
+    let mut values = key_values.iter().peekable();
+    for block in blocks {
+        let block = block?;
+        while values
+            .next_if(|value| value.end() <= block.file_offset)
+            .is_some()
+        {}
+        if values
+            .peek()
+            .is_none_or(|value| value.location.file_offset > block.file_offset)
+        {
+            other_blocks.push(block);
+        }
+    }
+    Ok(other_blocks)
+}
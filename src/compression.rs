@@ -0,0 +1,172 @@
+//! Transparent per-value compression. Compressed values are stored on disk compressed, and the
+//! codec and uncompressed length are recorded with the key. Limits and usage count the compressed
+//! bytes on disk.
+
+use super::*;
+
+/// The compression used for a value, as stored in the manifest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub enum Codec {
+    Zstd,
+}
+
+impl Codec {
+    /// The name used in the manifest and on the command line.
+    pub fn name(self) -> &'static str {
+        use Codec::*;
+        match self {
+            Zstd => "zstd",
+        }
+    }
+}
+
+impl Display for Codec {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl std::str::FromStr for Codec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        use Codec::*;
+        [Zstd]
+            .into_iter()
+            .find(|codec| codec.name() == s)
+            .ok_or_else(|| anyhow!("unknown codec {s:?}"))
+    }
+}
+
+impl ToSql for Codec {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        self.name().to_sql()
+    }
+}
+
+impl FromSql for Codec {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        value
+            .as_str()?
+            .parse()
+            .map_err(|err: anyhow::Error| FromSqlError::Other(err.into()))
+    }
+}
+
+/// How a compressed value is stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Compression {
+    pub codec: Codec,
+    pub uncompressed_length: u64,
+}
+
+impl Compression {
+    pub(crate) fn from_column_values(
+        codec: Option<Codec>,
+        uncompressed_length: Option<u64>,
+    ) -> Option<Self> {
+        Some(Self {
+            codec: codec?,
+            uncompressed_length: uncompressed_length.unwrap(),
+        })
+    }
+
+    /// Decompresses the on-disk bytes of a value.
+    pub(crate) fn decompress(&self, compressed: &[u8]) -> io::Result<Vec<u8>> {
+        let capacity = to_usize_io(self.uncompressed_length)?;
+        let decompressed = match self.codec {
+            Codec::Zstd => zstd::bulk::decompress(compressed, capacity)?,
+        };
+        if decompressed.len() != capacity {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "decompressed {} bytes, expected {}",
+                    decompressed.len(),
+                    capacity
+                ),
+            ));
+        }
+        Ok(decompressed)
+    }
+
+    /// Decompresses the bytes at pos in the uncompressed value into buf, reading the on-disk bytes
+    /// from stored. What comes before pos is decompressed and discarded, so memory use doesn't
+    /// grow with the value.
+    pub(crate) fn decompress_at(
+        &self,
+        stored: impl Read,
+        pos: u64,
+        buf: &mut [u8],
+    ) -> io::Result<usize> {
+        if pos >= self.uncompressed_length {
+            return Ok(0);
+        }
+        let mut decoder = match self.codec {
+            Codec::Zstd => zstd::stream::read::Decoder::new(stored)?,
+        };
+        if io::copy(&mut (&mut decoder).take(pos), &mut io::sink())? != pos {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+        let len = min(buf.len() as u64, self.uncompressed_length - pos) as usize;
+        decoder.read_exact(&mut buf[..len])?;
+        Ok(len)
+    }
+}
+
+/// Compresses the bytes written to a ValueWriter before they reach the values file.
+pub(crate) struct ValueEncoder {
+    codec: Codec,
+    encoder: zstd::stream::write::Encoder<'static, Vec<u8>>,
+    uncompressed_length: u64,
+}
+
+impl Debug for ValueEncoder {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ValueEncoder")
+            .field("codec", &self.codec)
+            .field("uncompressed_length", &self.uncompressed_length)
+            .finish()
+    }
+}
+
+impl ValueEncoder {
+    pub(crate) fn new(codec: Codec) -> io::Result<Self> {
+        let encoder = match codec {
+            Codec::Zstd => zstd::stream::write::Encoder::new(vec![], 0)?,
+        };
+        Ok(Self {
+            codec,
+            encoder,
+            uncompressed_length: 0,
+        })
+    }
+
+    pub(crate) fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    /// Compresses buf, and writes any output that's ready to file.
+    pub(crate) fn write_all(&mut self, buf: &[u8], file: &mut impl Write) -> io::Result<()> {
+        self.encoder.write_all(buf)?;
+        self.uncompressed_length += buf.len() as u64;
+        let output = self.encoder.get_mut();
+        file.write_all(output)?;
+        output.clear();
+        Ok(())
+    }
+
+    /// Writes the rest of the compressed value to file. Returns None if nothing was written, so
+    /// empty values stay zero-length.
+    pub(crate) fn finish(self, file: &mut impl Write) -> io::Result<Option<Compression>> {
+        if self.uncompressed_length == 0 {
+            return Ok(None);
+        }
+        file.write_all(&self.encoder.finish()?)?;
+        Ok(Some(Compression {
+            codec: self.codec,
+            uncompressed_length: self.uncompressed_length,
+        }))
+    }
+}
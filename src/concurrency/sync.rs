@@ -35,7 +35,7 @@ pub struct Mutex<T>(InnerMutex<T>);
 unsafe impl<T> StableDeref for MutexGuard<'_, T> {}
 
 impl<T> Mutex<T> {
-    pub fn lock(&self) -> LockResult<MutexGuard<T>> {
+    pub fn lock(&self) -> LockResult<MutexGuard<'_, T>> {
         // This is super dumb. There's a map_result in std::sync::poison that I can't get at that
         // does the same thing I think.
         match self.0.lock() {
@@ -46,4 +46,7 @@ impl<T> Mutex<T> {
     pub fn new(t: T) -> Self {
         Self(InnerMutex::new(t))
     }
+    pub fn into_inner(self) -> LockResult<T> {
+        self.0.into_inner()
+    }
 }
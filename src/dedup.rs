@@ -0,0 +1,243 @@
+//! Deduplication of values by content. Commits through a Handle with dedup_values set hash the
+//! values being written, and keys whose values match an existing value share its location. The
+//! copy that was just written is hole punched, and a shared value is only punched once the last key
+//! referring to it is deleted. Values stored in blocks are deduplicated a block at a time instead,
+//! and a shared block is punched once no part refers to it.
+
+use super::*;
+
+/// Sets the content hash of a pending write, checksumming it first if it was written without
+/// checksums. Encrypted values are left alone since their random nonces mean stored bytes never
+/// match.
+pub(crate) fn hash_pending_write(dir: &Path, pw: &mut PendingWrite) -> io::Result<()> {
+    if pw.value_length == 0 || pw.nonce.is_some() {
+        return Ok(());
+    }
+    let checksums = match &pw.checksums {
+        Some(checksums) => checksums,
+        None => {
+            let file = File::open(file_path(dir, pw.value_file_id))?;
+            pw.checksums.insert(Checksums::of_stored(
+                &file,
+                pw.value_file_offset,
+                pw.value_length,
+            )?)
+        }
+    };
+    pw.content_hash = Some(checksums.content_hash());
+    Ok(())
+}
+
+/// Keys whose values could be shared by a pending write. The parameters are the pending write's
+/// content hash, value length, codec and uncompressed length.
+const DUPLICATE_KEYS_WHERE_SQL: &str = "content_hash=? and value_length=? and codec is ? \
+    and uncompressed_length is ? and nonce is null";
+
+/// Finds an existing value, or one written earlier in the same batch, with the same stored bytes as
+/// a hashed pending write. This reads the candidates in full, so it's done before the manifest is
+/// locked for the commit, and the commit checks the value is still there with duplicate_unchanged.
+pub(crate) fn find_duplicate(
+    handle: &Handle,
+    earlier: &[PendingWrite],
+    pw: &PendingWrite,
+) -> Result<Option<NonzeroValueLocation>> {
+    let Some(content_hash) = pw.content_hash else {
+        return Ok(None);
+    };
+    let mut candidates = handle
+        .conn
+        .lock()
+        .unwrap()
+        .prepare_cached(&format!(
+            "select distinct file_id, file_offset from keys where {DUPLICATE_KEYS_WHERE_SQL}"
+        ))?
+        .query_map(
+            params![
+                content_hash,
+                pw.value_length,
+                pw.compression.map(|compression| compression.codec),
+                pw.compression
+                    .map(|compression| compression.uncompressed_length),
+            ],
+            |row| {
+                Ok(NonzeroValueLocation {
+                    file_id: row.get(0)?,
+                    file_offset: row.get(1)?,
+                    length: pw.value_length,
+                })
+            },
+        )?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    // Earlier writes in the batch are inserted first, where they end up after their own dedup.
+    candidates.extend(
+        earlier
+            .iter()
+            .filter(|other| {
+                other.content_hash == Some(content_hash)
+                    && other.value_length == pw.value_length
+                    && other.compression == pw.compression
+            })
+            .map(|other| {
+                other.duplicate_of.unwrap_or(NonzeroValueLocation {
+                    file_id: other.value_file_id,
+                    file_offset: other.value_file_offset,
+                    length: other.value_length,
+                })
+            }),
+    );
+    let written = NonzeroValueLocation {
+        file_id: pw.value_file_id,
+        file_offset: pw.value_file_offset,
+        length: pw.value_length,
+    };
+    first_duplicate(handle.dir.path(), candidates, &written)
+}
+
+/// Like find_duplicate, for each part of a hashed pending write stored in blocks. A part can share
+/// an existing block, or a part written earlier in the batch, including earlier in the same value.
+/// The commit checks each block is still there when it inserts the parts. See
+/// blocks::insert_parts.
+pub(crate) fn find_duplicate_blocks(
+    handle: &Handle,
+    earlier: &[PendingWrite],
+    pw: &PendingWrite,
+) -> Result<Vec<Option<NonzeroValueLocation>>> {
+    let Some(checksums) = &pw.checksums else {
+        return Ok(vec![]);
+    };
+    // The hashes and locations of the parts written before each part.
+    let mut written: Vec<(i64, NonzeroValueLocation)> = earlier
+        .iter()
+        .filter(|other| other.in_blocks)
+        .filter_map(|other| Some((other, other.checksums.as_ref()?)))
+        .flat_map(|(other, checksums)| {
+            blocks::part_ranges(other.value_length).map(|range| {
+                let length = range.end - range.start;
+                (
+                    checksums.range_hash(range.start, length),
+                    NonzeroValueLocation {
+                        file_id: other.value_file_id,
+                        file_offset: other.value_file_offset + range.start,
+                        length,
+                    },
+                )
+            })
+        })
+        .collect();
+    let mut duplicates = vec![];
+    for range in blocks::part_ranges(pw.value_length) {
+        let part = NonzeroValueLocation {
+            file_id: pw.value_file_id,
+            file_offset: pw.value_file_offset + range.start,
+            length: range.end - range.start,
+        };
+        let block_hash = checksums.range_hash(range.start, part.length);
+        let mut candidates = handle
+            .conn
+            .lock()
+            .unwrap()
+            .prepare_cached(
+                "select file_id, file_offset from blocks where block_hash=? and block_length=?",
+            )?
+            .query_map(params![block_hash, part.length], |row| {
+                Ok(NonzeroValueLocation {
+                    file_id: row.get(0)?,
+                    file_offset: row.get(1)?,
+                    length: part.length,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        candidates.extend(
+            written
+                .iter()
+                .filter(|(hash, other)| *hash == block_hash && other.length == part.length)
+                .map(|(_, other)| *other),
+        );
+        duplicates.push(first_duplicate(handle.dir.path(), candidates, &part)?);
+        written.push((block_hash, part));
+    }
+    Ok(duplicates)
+}
+
+/// The first candidate with the same stored bytes as what was written.
+fn first_duplicate(
+    dir: &Path,
+    candidates: Vec<NonzeroValueLocation>,
+    written: &NonzeroValueLocation,
+) -> Result<Option<NonzeroValueLocation>> {
+    for existing in candidates {
+        match same_stored_bytes(dir, &existing, written) {
+            Ok(true) => return Ok(Some(existing)),
+            Ok(false) => {}
+            // Nothing is locked, so the candidate may have been deleted and punched or removed.
+            Err(err) if matches!(err.kind(), ErrorKind::NotFound | ErrorKind::UnexpectedEof) => {}
+            Err(err) => return Err(err).context("comparing duplicate values"),
+        }
+    }
+    Ok(None)
+}
+
+/// Whether a duplicate found by find_duplicate is still referred to by a key with the same content.
+/// Values aren't rewritten in place while a key refers to them, so it still has the same bytes.
+pub(crate) fn duplicate_unchanged(
+    tx: &rusqlite::Transaction<'_>,
+    pw: &PendingWrite,
+    duplicate: &NonzeroValueLocation,
+) -> rusqlite::Result<bool> {
+    let Some(content_hash) = pw.content_hash else {
+        return Ok(false);
+    };
+    tx.prepare_cached(&format!(
+        "select exists(select 1 from keys \
+        where {DUPLICATE_KEYS_WHERE_SQL} and file_id=? and file_offset=?)"
+    ))?
+    .query_row(
+        params![
+            content_hash,
+            pw.value_length,
+            pw.compression.map(|compression| compression.codec),
+            pw.compression
+                .map(|compression| compression.uncompressed_length),
+            duplicate.file_id,
+            duplicate.file_offset,
+        ],
+        |row| row.get(0),
+    )
+}
+
+/// Compares the stored bytes of two values or blocks of the same length, since hashes can collide.
+fn same_stored_bytes(
+    dir: &Path,
+    a: &NonzeroValueLocation,
+    b: &NonzeroValueLocation,
+) -> io::Result<bool> {
+    assert_eq!(a.length, b.length);
+    let a_file = File::open(file_path(dir, a.file_id))?;
+    let b_file = File::open(file_path(dir, b.file_id))?;
+    let mut a_buf = vec![];
+    let mut b_buf = vec![];
+    let mut pos = 0;
+    while pos < a.length {
+        let len = min(CHECKSUM_CHUNK_LEN, a.length - pos) as usize;
+        a_buf.resize(len, 0);
+        b_buf.resize(len, 0);
+        a_file.read_exact_at(a.file_offset + pos, &mut a_buf)?;
+        b_file.read_exact_at(b.file_offset + pos, &mut b_buf)?;
+        if a_buf != b_buf {
+            return Ok(false);
+        }
+        pos += len as u64;
+    }
+    Ok(true)
+}
+
+/// Whether any key refers to a location. Values of deleted keys are only punched if not.
+pub(crate) fn location_referenced(
+    tx: &rusqlite::Transaction<'_>,
+    location: &NonzeroValueLocation,
+) -> rusqlite::Result<bool> {
+    tx.prepare_cached("select exists(select 1 from keys where file_id=? and file_offset=?)")?
+        .query_row(params![location.file_id, location.file_offset], |row| {
+            row.get(0)
+        })
+}
@@ -31,7 +31,7 @@ impl Dir {
             Some(some) => some,
             None => {
                 let src = tempfile::NamedTempFile::new_in(&path_buf)?;
-                let dst_path = random_file_name_in_dir(&path_buf, ".clone_test-");
+                let dst_path = random_file_name_in_dir(&path_buf, CLONE_TEST_FILE_NAME_PREFIX);
                 assert!(!dst_path.exists());
                 let clone_res = clonefile(src.path(), &dst_path);
                 let _ = std::fs::remove_file(&dst_path);
@@ -55,6 +55,20 @@ impl Dir {
         })
     }
 
+    /// Opens an existing directory without creating anything in it. Files aren't cloned out of
+    /// directories opened this way.
+    pub(crate) fn open_read_only(path_buf: PathBuf) -> Result<Self> {
+        if !path_buf.is_dir() {
+            bail!("{} is not a directory", path_buf.display());
+        }
+        let block_size = path_min_hole_size(&path_buf)?;
+        Ok(Self {
+            path_buf,
+            block_size,
+            supports_file_cloning: false,
+        })
+    }
+
     pub fn path(&self) -> &Path {
         &self.path_buf
     }
@@ -67,6 +81,18 @@ impl Dir {
         self.supports_file_cloning
     }
 
+    /// Syncs the directory's entries, so that files created in it survive a power loss.
+    pub(crate) fn sync(&self) -> io::Result<()> {
+        cfg_if! {
+            if #[cfg(unix)] {
+                open_dir_as_file(self)?.sync_all()
+            } else {
+                // Windows doesn't support syncing directories, and NTFS journals its metadata.
+                Ok(())
+            }
+        }
+    }
+
     /// Walks the underlying files in the possum directory.
     pub fn walk_dir(&self) -> Result<Vec<walk::Entry>> {
         crate::walk::walk_dir(self)
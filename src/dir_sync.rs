@@ -0,0 +1,177 @@
+//! Copies the differences between two directories, such as to keep a standby cache warm.
+
+use super::*;
+
+#[derive(Debug, Default, Clone)]
+pub struct SyncOptions {
+    /// Only keys starting with this are compared.
+    pub prefix: Vec<u8>,
+    /// Delete keys in the destination that aren't in the source.
+    pub delete: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct SyncReport {
+    /// Values that were missing or different in the destination.
+    pub values_transferred: usize,
+    /// Transferred values that share blocks with the source through file cloning.
+    pub values_cloned: usize,
+    pub bytes_copied: u64,
+    pub values_deleted: usize,
+    pub values_unchanged: usize,
+    /// Values that were partially evicted in the source, so they couldn't be transferred.
+    pub values_skipped: usize,
+}
+
+impl Handle {
+    /// Makes the keys in dst match this Handle's. Values are compared by length, expiry and
+    /// content, and transferred values keep their last_used time. Values are cloned if the
+    /// directories share a filesystem that supports it.
+    pub fn sync_to(&self, dst: &Handle, options: &SyncOptions) -> Result<SyncReport> {
+        let mut report = SyncReport::default();
+        let mut src_reader = self.read()?;
+        let src_items = src_reader.list_items(&options.prefix)?;
+        for item in &src_items {
+            src_reader.add_value(&item.value)?;
+        }
+        let src_snapshot = src_reader.begin()?;
+        // Partially evicted values can't be copied. Whatever dst has for them is left alone.
+        let (src_items, partially_evicted): (Vec<_>, Vec<_>) = src_items
+            .into_iter()
+            .partition(|item| src_snapshot.value(&item.value).missing_ranges().is_empty());
+        report.values_skipped = partially_evicted.len();
+        let mut dst_reader = dst.read()?;
+        let mut dst_items: HashMap<Vec<u8>, Value> = dst_reader
+            .list_items(&options.prefix)?
+            .into_iter()
+            .map(|item| (item.key, item.value))
+            .collect();
+        for item in &partially_evicted {
+            dst_items.remove(&item.key);
+        }
+        for item in &src_items {
+            if let Some(dst_value) = dst_items.get(&item.key) {
+                dst_reader.add_value(dst_value)?;
+            }
+        }
+        let dst_snapshot = dst_reader.begin()?;
+        let mut transfers: HashMap<Option<FileId>, Vec<(Vec<u8>, Value)>> = Default::default();
+        for item in src_items {
+            if let Some(dst_value) = dst_items.remove(&item.key) {
+                let dst_value = dst_snapshot.value(&dst_value);
+                if dst_value.uncompressed_length() == item.value.uncompressed_length()
+                    && dst_value.expires() == item.value.expires()
+                    && dst_value.missing_ranges().is_empty()
+                    && src_snapshot
+                        .value(&item.value)
+                        .view(|src_bytes| dst_value.view(|dst_bytes| src_bytes == dst_bytes))??
+                {
+                    report.values_unchanged += 1;
+                    continue;
+                }
+            }
+            // Values with parts stored elsewhere are copied, since cloning their file misses them.
+            let file_id = if src_snapshot.value(&item.value).stored_in_place() {
+                item.value.file_id().copied()
+            } else {
+                None
+            };
+            transfers
+                .entry(file_id)
+                .or_default()
+                .push((item.key, item.value));
+        }
+        for (file_id, values) in transfers {
+            report.values_transferred += values.len();
+            let mut writer = dst.new_writer()?;
+            // Encrypted values are copied so they're decrypted here and re-encrypted with dst's
+            // key, if it has one.
+            let encrypted = values.iter().any(|(_, value)| value.nonce().is_some());
+            let cloned = match file_id {
+                Some(file_id) if !encrypted => {
+                    let file_clone = &src_snapshot.file_clones[&file_id];
+                    let mut file_clone = file_clone.lock().unwrap();
+                    writer.new_value().try_clone_file(&mut file_clone.file)?
+                }
+                _ => None,
+            };
+            match cloned {
+                Some(mut cloned) => {
+                    report.values_cloned += values.len();
+                    let cloned_len = cloned.exclusive_file.next_write_offset()?;
+                    let cloned_file_id = cloned.exclusive_file.id;
+                    let extents = values
+                        .iter()
+                        .map(|(_, value)| (value.file_offset().unwrap(), value.length()))
+                        .collect();
+                    let values = values
+                        .into_iter()
+                        .map(|(key, value)| {
+                            let checksums = src_snapshot.value(value).checksums().cloned();
+                            (key, value, checksums)
+                        })
+                        .collect();
+                    writer.stage_cloned_values(cloned, values);
+                    writer.commit()?;
+                    if !dst.instance_limits.disable_hole_punching {
+                        dst.punch_unreferenced(cloned_file_id, extents, cloned_len)?;
+                    }
+                }
+                None => {
+                    for (key, value) in values {
+                        let mut value_writer = writer.new_value().begin()?;
+                        if let Some(compression) = value.compression() {
+                            value_writer.compress(compression.codec)?;
+                        }
+                        src_snapshot
+                            .value(&value)
+                            .view(|bytes| value_writer.write_all(bytes))??;
+                        report.bytes_copied += value.length();
+                        writer.stage_write_inner(
+                            key,
+                            value_writer,
+                            value.expires(),
+                            Some(value.last_used()),
+                        )?;
+                    }
+                    writer.commit()?;
+                }
+            }
+        }
+        if options.delete && !dst_items.is_empty() {
+            let mut tx = dst.start_immediate_transaction()?;
+            for key in dst_items.keys() {
+                if tx.delete_key(key)?.is_some() {
+                    report.values_deleted += 1;
+                }
+            }
+            tx.commit()?.complete();
+        }
+        Ok(report)
+    }
+
+    /// Punches the whole blocks of a file that aren't covered by extents, up to len. Used when
+    /// other values came with values that were cloned in.
+    fn punch_unreferenced(
+        &self,
+        file_id: FileId,
+        mut extents: Vec<(u64, u64)>,
+        len: u64,
+    ) -> Result<()> {
+        let file = OpenOptions::new()
+            .write(true)
+            .open(file_path(self.dir.path(), file_id))?;
+        let block_size = self.block_size();
+        extents.sort_unstable();
+        let mut gap_start = 0;
+        for (offset, length) in extents.into_iter().chain([(len, 0)]) {
+            let start = ceil_multiple(gap_start, block_size);
+            let end = offset / block_size * block_size;
+            if end > start {
+                punchfile(&file, start, end - start)?;
+            }
+            gap_start = max(gap_start, offset + length);
+        }
+        Ok(())
+    }
+}
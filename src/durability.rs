@@ -0,0 +1,64 @@
+//! How much of a commit is guaranteed to survive a power loss or OS crash.
+
+use super::*;
+
+/// Set per Handle with Handle::set_durability. Process crashes never lose commits at any level.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub enum Durability {
+    /// Nothing is synced. A power loss can corrupt the manifest. This is the original behaviour.
+    #[default]
+    None,
+    /// The manifest is synced at checkpoints so that it can't be corrupted, but recent commits may
+    /// be lost, and keys can refer to value data that never reached the disk.
+    Manifest,
+    /// Values files are synced before the manifest commits, and the manifest is synced on every
+    /// commit. Committed writes survive a power loss.
+    Full,
+}
+
+impl Durability {
+    /// The name used on the command line.
+    pub fn name(self) -> &'static str {
+        use Durability::*;
+        match self {
+            None => "none",
+            Manifest => "manifest",
+            Full => "full",
+        }
+    }
+
+    /// The sqlite synchronous pragma value for the manifest connection.
+    pub(crate) fn synchronous(self) -> &'static str {
+        use Durability::*;
+        // The manifest is in WAL mode, where normal can lose commits but not corrupt the database.
+        match self {
+            None => "off",
+            Manifest => "normal",
+            Full => "full",
+        }
+    }
+
+    /// Whether values files need to be synced before the keys referring to them are committed.
+    pub(crate) fn sync_values(self) -> bool {
+        self == Durability::Full
+    }
+}
+
+impl Display for Durability {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl std::str::FromStr for Durability {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        use Durability::*;
+        [None, Manifest, Full]
+            .into_iter()
+            .find(|durability| durability.name() == s)
+            .ok_or_else(|| anyhow!("unknown durability {s:?}"))
+    }
+}
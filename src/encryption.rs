@@ -0,0 +1,248 @@
+//! Optional encryption of values at rest. Values are split into chunks that are sealed separately
+//! with XChaCha20-Poly1305, so reads at an offset only decrypt the chunks they cover. Each value
+//! has a random nonce stored with its key, and the chunk index and whether it's the last chunk are
+//! bound into each chunk, so chunks can't be reordered or truncated without detection.
+
+use std::borrow::Cow;
+
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+
+use super::*;
+
+/// Plaintext bytes per chunk.
+const CHUNK_LEN: u64 = 64 << 10;
+const TAG_LEN: u64 = 16;
+const SEALED_CHUNK_LEN: u64 = CHUNK_LEN + TAG_LEN;
+
+/// The per-value part of the nonce. The chunk index makes up the rest.
+pub type ValueNonce = [u8; 20];
+
+/// A 256-bit key for encrypting values. Every Handle on a directory with encrypted values must use
+/// the same key.
+#[derive(Clone)]
+pub struct EncryptionKey([u8; 32]);
+
+impl EncryptionKey {
+    pub fn new(bytes: [u8; 32]) -> Self {
+        Self(bytes)
+    }
+}
+
+impl From<[u8; 32]> for EncryptionKey {
+    fn from(bytes: [u8; 32]) -> Self {
+        Self::new(bytes)
+    }
+}
+
+impl Debug for EncryptionKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        // Keep keys out of logs.
+        f.write_str("EncryptionKey(..)")
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct ValueCipher(XChaCha20Poly1305);
+
+impl Debug for ValueCipher {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ValueCipher")
+    }
+}
+
+impl ValueCipher {
+    pub(crate) fn new(key: &EncryptionKey) -> Self {
+        Self(XChaCha20Poly1305::new(&key.0.into()))
+    }
+
+    fn chunk_nonce(nonce: &ValueNonce, chunk: u64) -> io::Result<XNonce> {
+        let chunk = u32::try_from(chunk).map_err(|_| io::Error::other("value too large"))?;
+        let mut chunk_nonce = XNonce::default();
+        chunk_nonce[..nonce.len()].copy_from_slice(nonce);
+        chunk_nonce[nonce.len()..].copy_from_slice(&chunk.to_be_bytes());
+        Ok(chunk_nonce)
+    }
+
+    fn seal(
+        &self,
+        nonce: &ValueNonce,
+        chunk: u64,
+        last: bool,
+        plaintext: &[u8],
+    ) -> io::Result<Vec<u8>> {
+        let payload = Payload {
+            msg: plaintext,
+            aad: &[last as u8],
+        };
+        self.0
+            .encrypt(&Self::chunk_nonce(nonce, chunk)?, payload)
+            .map_err(|_| io::Error::other("encrypting value"))
+    }
+
+    fn open(
+        &self,
+        nonce: &ValueNonce,
+        chunk: u64,
+        last: bool,
+        sealed: &[u8],
+    ) -> io::Result<Vec<u8>> {
+        let payload = Payload {
+            msg: sealed,
+            aad: &[last as u8],
+        };
+        self.0
+            .decrypt(&Self::chunk_nonce(nonce, chunk)?, payload)
+            .map_err(|_| {
+                io::Error::new(
+                    ErrorKind::InvalidData,
+                    "decrypting value failed, the key is wrong or the value is corrupt",
+                )
+            })
+    }
+
+    /// Decrypts all the sealed bytes of a value.
+    pub(crate) fn decrypt(&self, nonce: &ValueNonce, sealed: &[u8]) -> io::Result<Vec<u8>> {
+        let mut plaintext = Vec::with_capacity(to_usize_io(plaintext_length(sealed.len() as u64))?);
+        let chunks = sealed.chunks(SEALED_CHUNK_LEN as usize);
+        let last_chunk = chunks.len().saturating_sub(1);
+        for (index, chunk) in chunks.enumerate() {
+            plaintext.extend(self.open(nonce, index as u64, index == last_chunk, chunk)?);
+        }
+        Ok(plaintext)
+    }
+
+    /// Decrypts the plaintext at pos into buf, reading the chunks it covers with read_sealed.
+    /// sealed_len is the length of the value on disk.
+    pub(crate) fn read_at(
+        &self,
+        nonce: &ValueNonce,
+        sealed_len: u64,
+        pos: u64,
+        buf: &mut [u8],
+        read_sealed: impl Fn(u64, &mut [u8]) -> io::Result<()>,
+    ) -> io::Result<usize> {
+        let plaintext_len = plaintext_length(sealed_len);
+        let mut pos = pos;
+        let mut n = 0;
+        let mut sealed = vec![];
+        while n < buf.len() && pos < plaintext_len {
+            let chunk = pos / CHUNK_LEN;
+            let sealed_offset = chunk * SEALED_CHUNK_LEN;
+            let sealed_chunk_len = min(SEALED_CHUNK_LEN, sealed_len - sealed_offset);
+            sealed.resize(to_usize_io(sealed_chunk_len)?, 0);
+            read_sealed(sealed_offset, &mut sealed)?;
+            let last = sealed_offset + sealed_chunk_len == sealed_len;
+            let plaintext = self.open(nonce, chunk, last, &sealed)?;
+            let start = (pos - chunk * CHUNK_LEN) as usize;
+            let copied = min(buf.len() - n, plaintext.len() - start);
+            buf[n..n + copied].copy_from_slice(&plaintext[start..start + copied]);
+            n += copied;
+            pos += copied as u64;
+        }
+        Ok(n)
+    }
+}
+
+/// Decrypts the bytes of a value as stored on disk if it's encrypted.
+pub(crate) fn decrypt_stored<'a>(
+    cipher: Option<(&ValueCipher, &ValueNonce)>,
+    stored: &'a [u8],
+) -> io::Result<Cow<'a, [u8]>> {
+    Ok(match cipher {
+        Some((cipher, nonce)) => Cow::Owned(cipher.decrypt(nonce, stored)?),
+        None => Cow::Borrowed(stored),
+    })
+}
+
+/// The length of the plaintext of a value that's sealed_length bytes on disk.
+pub(crate) fn plaintext_length(sealed_length: u64) -> u64 {
+    sealed_length.saturating_sub(sealed_length.div_ceil(SEALED_CHUNK_LEN) * TAG_LEN)
+}
+
+/// Encrypts the bytes written to a ValueWriter before they reach the values file.
+#[derive(Debug)]
+pub(crate) struct ValueEncryptor {
+    cipher: ValueCipher,
+    nonce: ValueNonce,
+    chunk: u64,
+    // The plaintext of the current chunk. It's sealed once more is written, since the last chunk
+    // is sealed differently.
+    buffer: Vec<u8>,
+}
+
+impl ValueEncryptor {
+    pub(crate) fn new(cipher: ValueCipher) -> Self {
+        let mut nonce = ValueNonce::default();
+        rand::thread_rng().fill(&mut nonce);
+        Self {
+            cipher,
+            nonce,
+            chunk: 0,
+            buffer: vec![],
+        }
+    }
+
+    pub(crate) fn into_cipher(self) -> ValueCipher {
+        self.cipher
+    }
+
+    /// Whether nothing has been written.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.chunk == 0 && self.buffer.is_empty()
+    }
+
+    pub(crate) fn write_all(&mut self, mut buf: &[u8], file: &mut impl Write) -> io::Result<()> {
+        while !buf.is_empty() {
+            if self.buffer.len() as u64 == CHUNK_LEN {
+                file.write_all(
+                    &self
+                        .cipher
+                        .seal(&self.nonce, self.chunk, false, &self.buffer)?,
+                )?;
+                self.buffer.clear();
+                self.chunk += 1;
+            }
+            let n = min(buf.len(), CHUNK_LEN as usize - self.buffer.len());
+            self.buffer.extend_from_slice(&buf[..n]);
+            buf = &buf[n..];
+        }
+        Ok(())
+    }
+
+    /// Seals the last chunk. Returns the value's nonce, or None if nothing was written so empty
+    /// values stay zero-length.
+    pub(crate) fn finish(self, file: &mut impl Write) -> io::Result<Option<ValueNonce>> {
+        if self.is_empty() {
+            return Ok(None);
+        }
+        file.write_all(
+            &self
+                .cipher
+                .seal(&self.nonce, self.chunk, true, &self.buffer)?,
+        )?;
+        Ok(Some(self.nonce))
+    }
+}
+
+/// Writes to a values file, encrypting first if there's an encryptor.
+pub(crate) struct StoredValueWriter<'a, W> {
+    pub(crate) file: &'a mut W,
+    pub(crate) encryptor: Option<&'a mut ValueEncryptor>,
+}
+
+impl<W: Write> Write for StoredValueWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match &mut self.encryptor {
+            None => self.file.write(buf),
+            Some(encryptor) => {
+                encryptor.write_all(buf, self.file)?;
+                Ok(buf.len())
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
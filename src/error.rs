@@ -17,6 +17,19 @@ pub enum Error {
     Anyhow(#[from] anyhow::Error),
     #[error("unsupported filesystem")]
     UnsupportedFilesystem,
+    /// A value's stored bytes don't match its checksums.
+    #[error("value at offset {file_offset} in {file_id} is corrupt in chunk {chunk}")]
+    Corrupt {
+        file_id: FileId,
+        file_offset: u64,
+        chunk: u64,
+    },
+    /// Part of a value was dropped by partial eviction. The ranges are offsets within the value.
+    #[error("value is partially evicted, missing {missing:?}")]
+    PartiallyEvicted { missing: Vec<Range<u64>> },
+    /// The Handle was opened read-only.
+    #[error("handle is read-only")]
+    ReadOnly,
 }
 
 use Error::*;
@@ -45,7 +58,11 @@ impl FileAlreadyExistsError for Error {
 impl Error {
     pub fn root_cause(&self) -> &(dyn std::error::Error + 'static) {
         match self {
-            NoSuchKey | UnsupportedFilesystem => self,
+            NoSuchKey
+            | UnsupportedFilesystem
+            | Corrupt { .. }
+            | PartiallyEvicted { .. }
+            | ReadOnly => self,
             Sqlite(inner) => inner,
             Anyhow(inner) => inner.root_cause(),
             _ => unimplemented!(),
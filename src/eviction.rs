@@ -0,0 +1,258 @@
+//! Policies for choosing which values to evict when limits are exceeded.
+
+use rusqlite::OptionalExtension;
+
+use super::*;
+
+/// Determines the order values are evicted in by Transaction::evict_values.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub enum EvictionPolicy {
+    /// Least recently used first. This is the original behaviour.
+    #[default]
+    Lru,
+    /// Least frequently used first, breaking ties by least recently used. New values start just
+    /// above the least used existing value so they aren't evicted before they can be read. Only
+    /// reads that update last_used count as a use, so reads within the same millisecond are
+    /// counted once.
+    Lfu,
+    /// Largest product of value length and time since last use first. Large idle values are
+    /// evicted before small ones that were used around the same time.
+    SizeWeighted,
+}
+
+impl EvictionPolicy {
+    /// The name used in the manifest settings and on the command line.
+    pub fn name(self) -> &'static str {
+        use EvictionPolicy::*;
+        match self {
+            Lru => "lru",
+            Lfu => "lfu",
+            SizeWeighted => "size-weighted",
+        }
+    }
+
+    /// An order by clause over the keys table that puts the next value to evict first.
+    pub(crate) fn victim_order_sql(self) -> &'static str {
+        use EvictionPolicy::*;
+        match self {
+            Lru => "last_used",
+            Lfu => "use_count, last_used",
+            // The age is offset by one so recently used values are still ordered by size. sqlite
+            // switches to floating point if this overflows.
+            SizeWeighted => {
+                "(cast(unixepoch('subsec')*1e3 as integer) - last_used + 1) * value_length desc"
+            }
+        }
+    }
+}
+
+impl Display for EvictionPolicy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl std::str::FromStr for EvictionPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        use EvictionPolicy::*;
+        [Lru, Lfu, SizeWeighted]
+            .into_iter()
+            .find(|policy| policy.name() == s)
+            .ok_or_else(|| anyhow!("unknown eviction policy {s:?}"))
+    }
+}
+
+impl ToSql for EvictionPolicy {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        self.name().to_sql()
+    }
+}
+
+impl FromSql for EvictionPolicy {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        value
+            .as_str()?
+            .parse()
+            .map_err(|err: anyhow::Error| FromSqlError::Other(err.into()))
+    }
+}
+
+/// The most values the background evictor deletes in a single transaction.
+pub(crate) const BACKGROUND_EVICTION_BATCH_VALUES: usize = 64;
+
+/// How often the background evictor checks free space, when there's a free space limit.
+pub(crate) const FREE_SPACE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The wait before the background evictor retries after failing. It doubles with each consecutive
+/// failure up to MAX_BACKGROUND_RETRY_INTERVAL.
+pub(crate) const MIN_BACKGROUND_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+pub(crate) const MAX_BACKGROUND_RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Work for a Handle's background evictor thread.
+#[derive(Debug)]
+pub(crate) enum BackgroundWork {
+    Evict(BackgroundEviction),
+    /// Reads were recorded in the Handle's touches. See touches::Touches::record.
+    FlushTouches,
+    /// Keys with an expiry were written, so expired keys need deleting even if nothing else
+    /// commits. Carries the Handle's limits' disable_hole_punching.
+    SweepExpired {
+        disable_hole_punching: bool,
+    },
+}
+
+/// Passes a Handle's limits to the background evictor.
+#[derive(Debug)]
+pub(crate) struct BackgroundEviction {
+    pub limits: Limits,
+    /// Evict down to the low watermark.
+    pub over_high_watermark: bool,
+}
+
+impl BackgroundEviction {
+    pub(crate) fn low_watermark(&self) -> Option<u64> {
+        if !self.over_high_watermark {
+            return None;
+        }
+        let high_watermark = self.limits.high_watermark?;
+        Some(
+            self.limits
+                .low_watermark
+                .map_or(high_watermark, |low| low.min(high_watermark)),
+        )
+    }
+
+    pub(crate) fn checks_free_space(&self) -> bool {
+        usage::checks_free_space(&self.limits)
+    }
+
+    /// Whether usage has to be measured to enforce the limits, since commits don't measure it. See
+    /// UsageAccounting::Measured.
+    pub(crate) fn measures_usage(&self) -> bool {
+        self.limits.usage_accounting == UsageAccounting::Measured
+            && (self.limits.high_watermark.is_some() || self.limits.max_value_length_sum.is_some())
+    }
+
+    /// How often the limits need checking without a request from a commit, if at all.
+    pub(crate) fn check_interval(&self) -> Option<Duration> {
+        let free_space = self
+            .checks_free_space()
+            .then_some(FREE_SPACE_CHECK_INTERVAL);
+        let measured = self
+            .measures_usage()
+            .then_some(usage::MEASURED_USAGE_REFRESH_INTERVAL);
+        free_space.into_iter().chain(measured).min()
+    }
+}
+
+/// How many victims evict_values selects per query. Some policies order by expressions that can't
+/// use an index, so each query sorts the whole keys table.
+const VICTIM_BATCH_LEN: usize = 256;
+
+/// What evict_values removed.
+#[derive(Debug, Default)]
+pub(crate) struct Evicted {
+    /// Locations that nothing refers to anymore, to be punched.
+    pub(crate) locations: Vec<NonzeroValueLocation>,
+    /// Values and blocks evicted.
+    pub(crate) count: u64,
+    pub(crate) bytes: u64,
+}
+
+impl Evicted {
+    /// Counts the eviction once its transaction has committed.
+    pub(crate) fn record(&self, stats: &stats::Counters) {
+        stats::Counters::add(&stats.evictions, self.count);
+        stats::Counters::add(&stats.bytes_evicted, self.bytes);
+    }
+
+    /// Takes the blocks released so far, counting their bytes as freed.
+    fn take_released(&mut self, tx: &rusqlite::Transaction<'_>) -> rusqlite::Result<()> {
+        for location in blocks::take_released(tx)? {
+            self.bytes += location.length;
+            self.locations.push(location);
+        }
+        Ok(())
+    }
+}
+
+/// Deletes values in the order given by policy until at least target_bytes of values have been
+/// freed, or max_values have been deleted. Values shared with keys that remain free nothing, and
+/// neither do blocks shared with other values. With partial_eviction, blocks that were last used
+/// before the next value to be deleted are evicted first, except for the first block of a value,
+/// which keeps its key. Each block counts as a value against max_values. The locations returned
+/// still need to be hole punched.
+pub(crate) fn evict_values(
+    tx: &rusqlite::Transaction<'_>,
+    policy: EvictionPolicy,
+    target_bytes: u64,
+    max_values: Option<usize>,
+    partial_eviction: bool,
+) -> rusqlite::Result<Evicted> {
+    let mut evicted = Evicted::default();
+    let done = |evicted: &Evicted| {
+        evicted.bytes >= target_bytes || max_values.is_some_and(|max| evicted.count >= max as u64)
+    };
+    let mut select_victims = tx.prepare_cached(&format!(
+        "select {} from keys order by {} limit ?",
+        value_columns_sql(),
+        policy.victim_order_sql()
+    ))?;
+    'evict: while !done(&evicted) {
+        let batch_len = max_values.map_or(VICTIM_BATCH_LEN, |max| {
+            min(max - evicted.count as usize, VICTIM_BATCH_LEN)
+        });
+        let victims = select_victims
+            .query_map([batch_len], Value::from_row)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        if victims.is_empty() {
+            break;
+        }
+        for victim in victims {
+            if partial_eviction {
+                while let Some(block_id) = cold_block(tx, victim.last_used)? {
+                    info!(block_id, "evicting block");
+                    tx.prepare_cached("update value_parts set block_id=null where block_id=?")?
+                        .execute([block_id])?;
+                    evicted.take_released(tx)?;
+                    evicted.count += 1;
+                    if done(&evicted) {
+                        break 'evict;
+                    }
+                }
+            }
+            tx.prepare_cached("delete from keys where key_id=?")?
+                .execute([victim.key_id])?;
+            info!("evicting {:?}", &victim);
+            if victim.in_blocks() {
+                evicted.take_released(tx)?;
+            } else if let Nonzero(location) = victim.location {
+                // A value shared with other keys is only freed when its last key is evicted.
+                if !dedup::location_referenced(tx, &location)? {
+                    evicted.bytes += location.length;
+                    evicted.locations.push(location);
+                }
+            }
+            evicted.count += 1;
+            if done(&evicted) {
+                break 'evict;
+            }
+        }
+    }
+    Ok(evicted)
+}
+
+/// The least recently used block that was last used before a value, and isn't the first block of
+/// any value.
+fn cold_block(tx: &rusqlite::Transaction<'_>, before: Timestamp) -> rusqlite::Result<Option<i64>> {
+    tx.prepare_cached(
+        "select block_id from blocks where last_used < ? and not exists (\
+            select 1 from value_parts where block_id=blocks.block_id and part_offset=0\
+        ) order by last_used limit 1",
+    )?
+    .query_row([before], |row| row.get(0))
+    .optional()
+}
@@ -51,16 +51,23 @@ impl ExclusiveFile {
         self.inner.set_len(offset)
     }
 
+    /// Truncates anything written after the last commit, such as by writers that were dropped
+    /// without committing. Returns the bytes removed.
+    pub(crate) fn revert_uncommitted(&mut self) -> io::Result<u64> {
+        let end = self.inner.seek(End(0))?;
+        if end <= self.last_committed_offset {
+            return Ok(0);
+        }
+        self.revert_to_offset(self.last_committed_offset)?;
+        Ok(end - self.last_committed_offset)
+    }
+
     pub(crate) fn new(dir: impl AsRef<Path>) -> anyhow::Result<ExclusiveFile> {
         for _ in 0..10 {
             let id = FileId::random();
             let path = dir.as_ref().join(id.values_file_path());
             debug!(?path, "opening new exclusive file");
-            let file = Self::new_open_options().create(true).open(path);
-            let file = match file {
-                Ok(file) => file,
-                Err(err) => return Err(err.into()),
-            };
+            let file = Self::new_open_options().create(true).open(path)?;
             if let Some(exclusive_file) = Self::from_file(file, id)? {
                 return Ok(exclusive_file);
             }
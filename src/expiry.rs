@@ -0,0 +1,49 @@
+//! Deleting keys once they expire. Expired keys are hidden from readers straight away, and deleted
+//! by the next write transaction, or in the background as they expire.
+
+use super::*;
+
+/// Deletes up to max_values keys that have expired, soonest first, and returns the locations of
+/// their values that no other key refers to. Blocks released by the deletes are left for
+/// blocks::take_released.
+pub(crate) fn delete_expired(
+    tx: &rusqlite::Transaction,
+    max_values: Option<u64>,
+) -> rusqlite::Result<Vec<NonzeroValueLocation>> {
+    let expired = tx
+        .prepare_cached(&format!(
+            "delete from keys where key_id in ( \
+                select key_id from keys \
+                where expires <= cast(unixepoch('subsec')*1e3 as integer) \
+                order by expires limit ? \
+            ) returning {}",
+            value_columns_sql()
+        ))?
+        // A negative limit is no limit.
+        .query_map([max_values.map_or(-1, |max| max as i64)], Value::from_row)?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    let mut locations = vec![];
+    for value in expired {
+        debug!("deleting expired {:?}", &value);
+        let Nonzero(location) = value.location else {
+            continue;
+        };
+        // Values stored in blocks are punched as their blocks are released. A value shared with
+        // other keys is only freed when its last key is deleted.
+        if !value.in_blocks() && !dedup::location_referenced(tx, &location)? {
+            locations.push(location);
+        }
+    }
+    // Keys sharing a value may all have expired.
+    locations.sort_unstable();
+    locations.dedup();
+    Ok(locations)
+}
+
+/// When the next key expires, if any key has an expiry.
+pub(crate) fn next_expiry(conn: &Connection) -> rusqlite::Result<Option<SystemTime>> {
+    let millis: Option<i64> = conn
+        .prepare_cached("select min(expires) from keys where expires is not null")?
+        .query_row([], |row| row.get(0))?;
+    Ok(millis.map(|millis| SystemTime::UNIX_EPOCH + Duration::from_millis(millis.max(0) as u64)))
+}
@@ -0,0 +1,79 @@
+//! Singleflight fetching of missing values, coordinated across Handles through the manifest.
+
+use super::*;
+
+/// How long a fetch can be in progress before other Handles assume the fetcher has died and take
+/// over.
+pub const DEFAULT_FETCH_TIMEOUT: Duration = Duration::from_secs(60);
+
+const MIN_FETCH_POLL_INTERVAL: Duration = Duration::from_millis(1);
+const MAX_FETCH_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+impl Handle {
+    /// Returns the value for key, calling fetch to write it if it's missing. Only one Handle, even
+    /// across processes, fetches a given key at a time. The others poll until the fetch completes.
+    pub fn get_or_fetch(
+        &self,
+        key: &[u8],
+        fetch: impl FnOnce(&mut ValueWriter) -> Result<()>,
+    ) -> Result<SnapshotValue<Value>> {
+        self.get_or_fetch_with_timeout(key, DEFAULT_FETCH_TIMEOUT, fetch)
+    }
+
+    /// Like get_or_fetch, but fetches started longer than timeout ago are considered abandoned,
+    /// such as by a crashed process, and this Handle will fetch instead.
+    pub fn get_or_fetch_with_timeout(
+        &self,
+        key: &[u8],
+        timeout: Duration,
+        fetch: impl FnOnce(&mut ValueWriter) -> Result<()>,
+    ) -> Result<SnapshotValue<Value>> {
+        let fetch_id: i64 = rand::random();
+        let mut poll_interval = MIN_FETCH_POLL_INTERVAL;
+        loop {
+            let mut reader = self.read()?;
+            if let Some(value) = reader.add(key)? {
+                let snapshot = reader.begin()?;
+                return Ok(snapshot.value(value));
+            }
+            // Reads don't take the write lock, so check again with it before tagging the fetch.
+            drop(reader);
+            let mut tx = self.start_immediate_transaction()?;
+            match tx.read_value(key) {
+                Ok(_) => continue,
+                Err(rusqlite::Error::QueryReturnedNoRows) => {}
+                Err(err) => return Err(err.into()),
+            }
+            if tx
+                .try_start_fetch(key, fetch_id, timeout)
+                .context("tagging fetch")?
+            {
+                tx.commit()?.complete();
+                break;
+            }
+            // Another fetch is in progress. Release the manifest and wait for it.
+            drop(tx);
+            trace!(?poll_interval, "waiting for fetch");
+            thread::sleep(poll_interval);
+            poll_interval = min(poll_interval * 2, MAX_FETCH_POLL_INTERVAL);
+        }
+        debug!(key = %key.escape_ascii(), "fetching");
+        let fetched = (|| {
+            let mut writer = self.new_writer()?;
+            let mut value = writer.new_value().begin()?;
+            fetch(&mut value)?;
+            writer.stage_write(key.to_owned(), value)?;
+            // This clears the fetch tag in the same transaction.
+            writer.commit()
+        })();
+        if let Err(err) = fetched {
+            // Let someone else try without waiting for the timeout.
+            let mut tx = self.start_immediate_transaction()?;
+            tx.end_fetch(key, fetch_id)?;
+            tx.commit()?.complete();
+            return Err(err.context("fetching"));
+        }
+        self.read_single(key)?
+            .context("fetched value missing after commit")
+    }
+}
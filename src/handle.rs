@@ -1,16 +1,107 @@
+use std::time::Instant;
+
 use rusqlite::{TransactionBehavior, TransactionState};
 
 use super::*;
 
-#[derive(Default, Debug)]
+/// Limits on a directory. These are stored in the manifest with Handle::set_persistent_limits, and
+/// can be overridden for a single Handle with Handle::set_instance_limits.
+#[derive(Default, Debug, Clone, PartialEq)]
 #[repr(C)]
 pub struct Limits {
+    /// A hard ceiling on disk usage, as counted by usage_accounting. Commits evict values
+    /// synchronously to stay under it.
     pub max_value_length_sum: Option<u64>,
     // Invert this logic when there are defaults and mutators.
     pub disable_hole_punching: bool,
+    /// Chooses which values are evicted when a limit is exceeded.
+    pub eviction_policy: EvictionPolicy,
+    /// Values are evicted in the background when disk usage exceeds this.
+    pub high_watermark: Option<u64>,
+    /// Background eviction stops once disk usage is at or below this. Defaults to high_watermark.
+    pub low_watermark: Option<u64>,
+    /// How disk usage is counted against the other limits.
+    pub usage_accounting: UsageAccounting,
+    /// Values are evicted when the filesystem containing the directory has less than this many
+    /// bytes available. This is checked on commit, and periodically in the background. It has no
+    /// effect if hole punching is disabled.
+    pub min_free_bytes: Option<u64>,
+    /// Like min_free_bytes, as a percentage of the size of the filesystem.
+    pub min_free_percent: Option<u8>,
+    /// Evict blocks of large values that haven't been read since before the next value to be
+    /// evicted was last used, instead of evicting values whole. The first block of a value is
+    /// kept with its key. Reads of the evicted blocks fail with Error::PartiallyEvicted. Only large
+    /// values written while this is set are stored in blocks. Compressed and encrypted values are
+    /// always evicted whole.
+    pub partial_eviction: bool,
+}
+
+/// Options for opening a Handle with Handle::new_with_options.
+#[derive(Default, Debug, Clone)]
+pub struct HandleOptions {
+    /// Reset manifests that are too old to migrate, deleting all values. Otherwise opening them
+    /// fails.
+    pub allow_destructive_reset: bool,
+    /// Encrypt values written by the Handle with this key, and decrypt values with it when they're
+    /// read. Values written without a key are still readable.
+    pub encryption_key: Option<EncryptionKey>,
+    /// Open the directory without changing it, such as to inspect a cache owned by another user.
+    /// The manifest is opened read-only and must already be at the latest version. Reads don't
+    /// update last_used, and writing fails with Error::ReadOnly. No background threads are
+    /// started. SQLite may still create the manifest's WAL index files if the directory is
+    /// writable.
+    pub read_only: bool,
 }
 
-type DeletedValuesSender = sync::mpsc::SyncSender<Vec<NonzeroValueLocation>>;
+/// Wakes the value puncher after values are added to the punch queue.
+#[derive(Debug, Clone)]
+pub(crate) struct ValuePuncherWaker(sync::mpsc::SyncSender<()>);
+
+impl ValuePuncherWaker {
+    pub(crate) fn wake(&self) {
+        use std::sync::mpsc::TrySendError::*;
+        match self.0.try_send(()) {
+            // The puncher hasn't handled the last wake yet, and will see the new values.
+            Ok(()) | Err(Full(())) => (),
+            Err(Disconnected(())) => error!("waking value puncher: channel disconnected"),
+        }
+    }
+}
+
+/// What Handle::close did, and what it couldn't finish.
+#[derive(Debug, Default)]
+pub struct CloseReport {
+    /// Whether the value puncher made its last pass before the timeout. If it didn't, it keeps
+    /// going until the process exits.
+    pub value_puncher_finished: bool,
+    /// Values still waiting to be punched, because readers had them locked or the puncher didn't
+    /// finish. Any Handle opened on the directory will punch them.
+    pub punch_backlog: PunchBacklog,
+    pub exclusive_files_returned: usize,
+    /// Bytes written to exclusive files that were never committed.
+    pub uncommitted_bytes_truncated: u64,
+    /// The Handle's snapshot dirs that Snapshots are still using. They're removed when the
+    /// Snapshots are dropped.
+    pub snapshot_dirs_in_use: Vec<PathBuf>,
+}
+
+impl CloseReport {
+    /// Whether everything was punched and cleaned up.
+    pub fn is_complete(&self) -> bool {
+        self.value_puncher_finished
+            && self.punch_backlog.values == 0
+            && self.snapshot_dirs_in_use.is_empty()
+    }
+}
+
+/// The outcome of Handle::punch_values.
+#[derive(Debug, Default)]
+pub(crate) struct PunchedValues {
+    /// Values to retry because a reader had them or the clone lock locked, or punching them failed.
+    pub(crate) failed: Vec<NonzeroValueLocation>,
+    /// Values files that were truncated, and the offsets they were truncated to.
+    pub(crate) truncated: Vec<(FileId, u64)>,
+}
 
 /// Provides access to a storage directory. Manages manifest access, file cloning, file writers,
 /// configuration, value eviction etc.
@@ -21,13 +112,75 @@ pub struct Handle {
     pub(crate) dir: Dir,
     pub(crate) clones: Mutex<FileCloneCache>,
     pub(crate) instance_limits: Limits,
-    deleted_values: Option<DeletedValuesSender>,
+    // Shared with the background threads, which apply it to their connections.
+    durability: Arc<Mutex<Durability>>,
+    pub(crate) cipher: Option<ValueCipher>,
+    pub(crate) verify_reads: bool,
+    pub(crate) dedup_values: bool,
+    pub(crate) read_only: bool,
+    pub(crate) stats: Arc<stats::Counters>,
+    pub(crate) touches: Arc<touches::Touches>,
+    value_puncher_waker: Option<ValuePuncherWaker>,
     _value_puncher: Option<thread::JoinHandle<()>>,
     value_puncher_done: ValuePuncherDone,
+    // Started when there's first something for it to do. Never started if the Handle is read-only.
+    pub(crate) background_evictor: Mutex<Option<BackgroundEvictor>>,
+}
+
+/// A Handle's background evictor thread, and how to give it work.
+#[derive(Debug)]
+pub(crate) struct BackgroundEvictor {
+    work: sync::mpsc::Sender<BackgroundWork>,
+    _thread: thread::JoinHandle<()>,
+}
+
+/// A manifest connection for a background thread, kept at its Handle's durability.
+#[derive(Debug)]
+pub(crate) struct BackgroundConn {
+    conn: Connection,
+    durability: Durability,
+}
+
+impl BackgroundConn {
+    pub(crate) fn open(dir: &Dir) -> rusqlite::Result<Self> {
+        let conn = Connection::open(dir.path().join(MANIFEST_DB_FILE_NAME))?;
+        let durability = Durability::default();
+        Handle::retry_while_busy(|| {
+            conn.pragma_update(None, "synchronous", durability.synchronous())
+        })?;
+        Ok(Self { conn, durability })
+    }
+
+    fn open_if_needed<'a>(
+        conn: &'a mut Option<Self>,
+        dir: &Dir,
+        durability: &Mutex<Durability>,
+    ) -> Result<&'a mut Connection> {
+        if conn.is_none() {
+            *conn = Some(Self::open(dir)?);
+        }
+        Ok(conn.as_mut().unwrap().get(durability)?)
+    }
+
+    /// The connection, after applying any change to the Handle's durability.
+    pub(crate) fn get(
+        &mut self,
+        durability: &Mutex<Durability>,
+    ) -> rusqlite::Result<&mut Connection> {
+        let durability = *durability.lock().unwrap();
+        if durability != self.durability {
+            Handle::retry_while_busy(|| {
+                self.conn
+                    .pragma_update(None, "synchronous", durability.synchronous())
+            })?;
+            self.durability = durability;
+        }
+        Ok(&mut self.conn)
+    }
 }
 
 /// 4 bytes stored in the database header https://sqlite.org/fileformat2.html#database_header.
-type ManifestUserVersion = u32;
+pub(crate) type ManifestUserVersion = u32;
 
 impl Handle {
     /// Whether file cloning should be attempted.
@@ -35,9 +188,81 @@ impl Handle {
         self.dir.supports_file_cloning()
     }
 
+    /// Sets the limits for this Handle only. New Handles use the persistent limits.
     pub fn set_instance_limits(&mut self, limits: Limits) -> Result<()> {
         self.instance_limits = limits;
-        self.start_deferred_transaction()?.apply_limits()
+        let mut tx = self.start_immediate_transaction()?;
+        tx.apply_limits()?;
+        tx.commit()?.complete();
+        let over_high_watermark = match self.instance_limits.high_watermark {
+            Some(high_watermark) => self.disk_usage()?.total() > high_watermark,
+            None => false,
+        };
+        // This also passes the new limits to the background evictor.
+        self.request_background_eviction(over_high_watermark);
+        Ok(())
+    }
+
+    /// Sets how much of each commit by this Handle survives a power loss.
+    pub fn set_durability(&mut self, durability: Durability) -> Result<()> {
+        {
+            let conn = self.conn.lock().unwrap();
+            Self::retry_while_busy(|| {
+                conn.pragma_update(None, "synchronous", durability.synchronous())
+            })?;
+        }
+        *self.durability.lock().unwrap() = durability;
+        Ok(())
+    }
+
+    pub fn durability(&self) -> Durability {
+        *self.durability.lock().unwrap()
+    }
+
+    /// Sets whether reads through this Handle's snapshots check values against their checksums.
+    /// Mismatches are returned as Error::Corrupt, wrapped in an io::Error with kind InvalidData
+    /// where the read returns io::Result.
+    pub fn set_verify_reads(&mut self, verify_reads: bool) {
+        self.verify_reads = verify_reads;
+    }
+
+    pub fn verify_reads(&self) -> bool {
+        self.verify_reads
+    }
+
+    /// Sets whether commits through this Handle look for existing values with the same content as
+    /// the values being written. Keys with duplicate values share the existing copy, and the new
+    /// copy is hole punched. Encrypted values are never deduplicated.
+    pub fn set_dedup_values(&mut self, dedup_values: bool) {
+        self.dedup_values = dedup_values;
+    }
+
+    pub fn dedup_values(&self) -> bool {
+        self.dedup_values
+    }
+
+    /// Disk usage as counted by the instance limits' usage accounting.
+    pub fn disk_usage(&self) -> Result<DiskUsage> {
+        let tx = self.start_deferred_transaction_for_read()?;
+        usage::disk_usage(&tx.0, &self.dir, self.instance_limits.usage_accounting)
+    }
+
+    /// The limits stored in the manifest, which every new Handle on the directory starts with.
+    pub fn persistent_limits(&self) -> Result<Limits> {
+        Ok(settings::load_limits(&self.conn.lock().unwrap())?)
+    }
+
+    /// Stores limits in the manifest and applies them to this Handle. Handles that are already
+    /// open elsewhere keep their limits until they're reopened.
+    pub fn set_persistent_limits(&mut self, limits: Limits) -> Result<()> {
+        self.check_writable()?;
+        {
+            let conn = self.conn.lock().unwrap();
+            let tx = rusqlite::Transaction::new_unchecked(&conn, TransactionBehavior::Immediate)?;
+            settings::store_limits(&tx, &limits)?;
+            tx.commit()?;
+        }
+        self.set_instance_limits(limits)
     }
 
     pub fn dir(&self) -> &Dir {
@@ -62,11 +287,12 @@ impl Handle {
             return Ok(file);
         }
         trace!("here");
-        let ret = ExclusiveFile::new(&self.dir);
-        if let Ok(file) = &ret {
-            debug!("created new exclusive file {}", file.id);
+        let file = ExclusiveFile::new(&self.dir)?;
+        debug!("created new exclusive file {}", file.id);
+        if self.durability().sync_values() {
+            self.dir.sync().context("syncing dir")?;
         }
-        ret
+        Ok(file)
     }
 
     fn open_existing_exclusive_file(&self) -> Result<Option<ExclusiveFile>> {
@@ -91,9 +317,13 @@ impl Handle {
     }
 
     // Expected manifest sqlite user version field value.
-    const USER_VERSION: u32 = 3;
+    const USER_VERSION: ManifestUserVersion = migrations::latest_version();
 
     pub fn new(dir: PathBuf) -> Result<Self> {
+        Self::new_with_options(dir, Default::default())
+    }
+
+    pub fn new_with_options(dir: PathBuf, options: HandleOptions) -> Result<Self> {
         let sqlite_version = rusqlite::version_number();
         // TODO: Why?
         if sqlite_version < 3042000 {
@@ -103,10 +333,26 @@ impl Handle {
                 "3.42"
             );
         }
+        if options.read_only {
+            return Self::new_read_only(dir, options);
+        }
         let dir = Dir::new(dir).context("new Dir")?;
+        CloneLock::create_file(&dir).context("creating clone lock file")?;
         let mut conn = Connection::open(dir.path().join(MANIFEST_DB_FILE_NAME))?;
-        Self::init_sqlite_conn(&mut conn, &dir)?;
-        let (deleted_values, receiver) = sync::mpsc::sync_channel(10);
+        Self::init_sqlite_conn(&mut conn, &dir, &options)?;
+        {
+            // This only writes if the block size changed, which should be once per directory.
+            let tx = rusqlite::Transaction::new_unchecked(&conn, TransactionBehavior::Deferred)?;
+            usage::set_block_size(&tx, dir.block_size())?;
+            tx.commit()?;
+        }
+        let instance_limits = settings::load_limits(&conn).context("loading limits")?;
+        let (value_puncher_waker, receiver) = sync::mpsc::sync_channel(1);
+        let value_puncher_waker = ValuePuncherWaker(value_puncher_waker);
+        let stats: Arc<stats::Counters> = Default::default();
+        let puncher_stats = Arc::clone(&stats);
+        let durability: Arc<Mutex<Durability>> = Default::default();
+        let puncher_durability = Arc::clone(&durability);
         let (value_puncher_done_sender, value_puncher_done) = sync::mpsc::sync_channel(0);
         let value_puncher_done = ValuePuncherDone(Arc::new(Mutex::new(value_puncher_done)));
         let handle = Self {
@@ -114,22 +360,88 @@ impl Handle {
             exclusive_files: Default::default(),
             dir: dir.clone(),
             clones: Default::default(),
-            instance_limits: Default::default(),
-            deleted_values: Some(deleted_values),
-            // Don't wait on this, at least in the Drop handler, because it stays alive until it
-            // succeeds in punching everything.
+            instance_limits,
+            durability,
+            cipher: options.encryption_key.as_ref().map(ValueCipher::new),
+            verify_reads: false,
+            dedup_values: false,
+            read_only: false,
+            stats,
+            touches: Default::default(),
+            value_puncher_waker: Some(value_puncher_waker),
+            // Don't wait on this, at least in the Drop handler, because it makes a last attempt at
+            // punching everything that's queued.
             _value_puncher: Some(thread::spawn(move || -> () {
                 let _value_puncher_done_sender = value_puncher_done_sender;
-                if let Err(err) = Self::value_puncher(dir, receiver) {
-                    error!("value puncher thread failed with {err:?}");
-                }
+                Self::value_puncher(dir, receiver, puncher_stats, puncher_durability)
             })),
             value_puncher_done,
+            background_evictor: Default::default(),
         };
+        // Start watching free space if the persistent limits ask for it.
+        handle.request_background_eviction(false);
+        // Keys written with an expiry by earlier Handles expire even if this one never writes.
+        let expiring = expiry::next_expiry(&handle.conn.lock().unwrap())?.is_some();
+        if expiring {
+            handle.request_expiry_sweep();
+        }
         Ok(handle)
     }
 
-    fn retry_while_busy<T>(mut f: impl FnMut() -> rusqlite::Result<T>) -> rusqlite::Result<T> {
+    fn new_read_only(dir: PathBuf, options: HandleOptions) -> Result<Self> {
+        let dir = Dir::open_read_only(dir).context("opening Dir")?;
+        let conn = Connection::open_with_flags(
+            dir.path().join(MANIFEST_DB_FILE_NAME),
+            OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        )?;
+        let user_version: ManifestUserVersion =
+            conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+        if user_version != Self::USER_VERSION {
+            bail!(
+                "manifest version {} isn't the latest version {}, and can't be migrated read-only",
+                user_version,
+                Self::USER_VERSION
+            );
+        }
+        let instance_limits = settings::load_limits(&conn).context("loading limits")?;
+        // The sender is dropped so waiting for the value puncher returns immediately.
+        let (_, value_puncher_done) = sync::mpsc::sync_channel(0);
+        Ok(Self {
+            conn: Mutex::new(conn),
+            exclusive_files: Default::default(),
+            dir,
+            clones: Default::default(),
+            instance_limits,
+            durability: Default::default(),
+            cipher: options.encryption_key.as_ref().map(ValueCipher::new),
+            verify_reads: false,
+            dedup_values: false,
+            read_only: true,
+            stats: Default::default(),
+            touches: Default::default(),
+            value_puncher_waker: None,
+            _value_puncher: None,
+            value_puncher_done: ValuePuncherDone(Arc::new(Mutex::new(value_puncher_done))),
+            background_evictor: Default::default(),
+        })
+    }
+
+    /// Whether the Handle was opened with HandleOptions::read_only.
+    pub fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Fails with Error::ReadOnly if the Handle is read-only.
+    pub(crate) fn check_writable(&self) -> PubResult<()> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn retry_while_busy<T>(
+        mut f: impl FnMut() -> rusqlite::Result<T>,
+    ) -> rusqlite::Result<T> {
         loop {
             match f() {
                 Err(rusqlite::Error::SqliteFailure(err, _))
@@ -142,15 +454,31 @@ impl Handle {
         }
     }
 
-    fn init_sqlite_conn(conn: &mut Connection, dir: &Dir) -> anyhow::Result<()> {
-        Self::retry_while_busy(|| conn.pragma_update(None, "synchronous", "off"))?;
+    fn init_sqlite_conn(
+        conn: &mut Connection,
+        dir: &Dir,
+        options: &HandleOptions,
+    ) -> anyhow::Result<()> {
+        Self::retry_while_busy(|| {
+            conn.pragma_update(None, "synchronous", Durability::default().synchronous())
+        })?;
 
         let get_user_version = |conn: &Connection| -> Result<ManifestUserVersion, _> {
             conn.pragma_query_value(None, "user_version", |row| row.get(0))
         };
+        let check_user_version = |user_version| {
+            if user_version > Self::USER_VERSION {
+                bail!(
+                    "manifest version {} is newer than the latest supported version {}",
+                    user_version,
+                    Self::USER_VERSION
+                );
+            }
+            Ok(())
+        };
         let user_version: ManifestUserVersion = get_user_version(conn)?;
-        // I'm not sure about the greater than case. Should we return an error?
-        if user_version >= Self::USER_VERSION {
+        check_user_version(user_version)?;
+        if user_version == Self::USER_VERSION {
             return Ok(());
         }
         // This initialization/upgrade process doesn't seem to be safe to perform when there's
@@ -165,16 +493,25 @@ impl Handle {
         // can't use transactions there's no other choice.
         conn.pragma_update(None, "locking_mode", "exclusive")?;
         let user_version = get_user_version(conn)?;
-        if user_version < Self::USER_VERSION {
+        check_user_version(user_version)?;
+        // Version 0 is a new database.
+        if user_version == 0 || user_version < migrations::OLDEST_MIGRATABLE_VERSION {
+            if user_version != 0 && !options.allow_destructive_reset {
+                bail!(
+                    "manifest version {} is too old to migrate, and resetting it would delete every value",
+                    user_version
+                );
+            }
             use rusqlite::config::DbConfig::SQLITE_DBCONFIG_RESET_DATABASE;
             conn.set_db_config(SQLITE_DBCONFIG_RESET_DATABASE, true)?;
             // This can't be done in a transaction, an exclusive one would have been nice.
             conn.execute("vacuum", [])?;
             conn.set_db_config(SQLITE_DBCONFIG_RESET_DATABASE, false)?;
             Self::delete_all_values_files(dir)?;
-            // It might be possible special case doing this from user_version=0 (a brand new database).
             init_manifest_schema(conn)?;
             conn.pragma_update(None, "user_version", Self::USER_VERSION)?;
+        } else if user_version < Self::USER_VERSION {
+            migrations::migrate(conn, user_version)?;
         }
         let mode: String =
             conn.pragma_update_and_check(None, "locking_mode", "normal", |row| row.get(0))?;
@@ -205,6 +542,7 @@ impl Handle {
     }
 
     pub fn cleanup_snapshots(&self) -> PubResult<()> {
+        self.check_writable()?;
         delete_unused_snapshots(self.dir.path()).map_err(Into::into)
     }
 
@@ -213,17 +551,19 @@ impl Handle {
     }
 
     pub fn new_writer(&self) -> Result<BatchWriter<&Handle>> {
+        self.check_writable()?;
         Ok(BatchWriter::new(self))
     }
 
-    pub(crate) fn start_immediate_transaction(&self) -> rusqlite::Result<OwnedTx> {
-        self.start_writable_transaction_with_behaviour(TransactionBehavior::Immediate)
+    pub(crate) fn start_immediate_transaction(&self) -> PubResult<OwnedTx<'_>> {
+        self.check_writable()?;
+        Ok(self.start_writable_transaction_with_behaviour(TransactionBehavior::Immediate)?)
     }
 
     pub(crate) fn start_writable_transaction_with_behaviour(
         &self,
         behaviour: TransactionBehavior,
-    ) -> rusqlite::Result<OwnedTx> {
+    ) -> rusqlite::Result<OwnedTx<'_>> {
         Ok(self
             .start_transaction(|conn, handle| {
                 let tx_res = run_blocking(|| {
@@ -242,7 +582,7 @@ impl Handle {
 
     /// Starts a deferred transaction (the default). There is no guaranteed read-only transaction
     /// mode. There might be pragmas that can limit to read only statements.
-    pub fn start_deferred_transaction_for_read(&self) -> rusqlite::Result<OwnedReadTx> {
+    pub fn start_deferred_transaction_for_read(&self) -> rusqlite::Result<OwnedReadTx<'_>> {
         Ok(self
             .start_transaction(|conn, _handle| {
                 let rtx = conn.transaction_with_behavior(TransactionBehavior::Deferred)?;
@@ -251,20 +591,17 @@ impl Handle {
             .into())
     }
 
-    /// Starts a deferred transaction (the default). This might upgrade to a write transaction if
-    /// appropriate. I'm not sure about the semantics of doing that yet. This might be useful for
-    /// operations that become writes depending on certain conditions, but could violate some
-    /// expectations around locking. TBD.
-    pub(crate) fn start_deferred_transaction(&self) -> rusqlite::Result<OwnedTx> {
-        self.start_writable_transaction_with_behaviour(TransactionBehavior::Deferred)
-    }
-
-    /// Begins a read transaction.
-    pub fn read(&self) -> rusqlite::Result<Reader<OwnedTx>> {
+    /// Begins a read. Lookups use a deferred transaction, so they don't wait for writers. The
+    /// clone lock keeps values the reader can see from being punched until its snapshot is taken.
+    pub fn read(&self) -> PubResult<Reader<OwnedTx<'_>>> {
+        let clone_lock = CloneLock::shared(&self.dir)?;
         let reader = Reader {
             owned_tx: self
-                .start_writable_transaction_with_behaviour(TransactionBehavior::Immediate)?,
+                .start_writable_transaction_with_behaviour(TransactionBehavior::Deferred)?,
             reads: Default::default(),
+            blocks: Default::default(),
+            checksums: Default::default(),
+            _clone_lock: clone_lock,
         };
         Ok(reader)
     }
@@ -282,21 +619,30 @@ impl Handle {
         &self,
         key: Vec<u8>,
         r: impl Read,
+    ) -> Result<(u64, WriteCommitResult)> {
+        self.single_write_from_with_expiry(key, r, None)
+    }
+
+    /// Like single_write_from, but the key is treated as missing after expires.
+    pub fn single_write_from_with_expiry(
+        &self,
+        key: Vec<u8>,
+        r: impl Read,
+        expires: Option<Timestamp>,
     ) -> Result<(u64, WriteCommitResult)> {
         let mut writer = self.new_writer()?;
         let mut value = writer.new_value().begin()?;
         trace!("got value writer");
         let n = value.copy_from(r)?;
-        writer.stage_write(key, value)?;
+        writer.stage_write_with_expiry(key, value, expires)?;
         let commit = writer.commit()?;
         Ok((n, commit))
     }
 
     pub fn single_delete(&self, key: &[u8]) -> PubResult<Option<c_api::PossumStat>> {
-        let mut tx = self.start_deferred_transaction()?;
+        // Immediate, since a deferred transaction can't wait for the write lock once it has read.
+        let mut tx = self.start_immediate_transaction()?;
         let deleted = tx.delete_key(key)?;
-        // Maybe it's okay just to commit anyway, since we have a deferred transaction and sqlite
-        // might know nothing has changed.
         if deleted.is_some() {
             tx.commit()?.complete();
         }
@@ -329,73 +675,179 @@ impl Handle {
             .list_items(prefix)
     }
 
-    /// Punches values in batches with its own dedicated connection and read-only transactions.
+    /// Deletes expired keys in small transactions, so they're reclaimed even if nothing commits.
+    fn delete_expired_in_background(
+        conn: &Connection,
+        disable_hole_punching: bool,
+        value_puncher_waker: &ValuePuncherWaker,
+    ) -> Result<()> {
+        loop {
+            let tx = Self::retry_while_busy(|| {
+                rusqlite::Transaction::new_unchecked(conn, TransactionBehavior::Immediate)
+            })?;
+            let mut expired =
+                expiry::delete_expired(&tx, Some(BACKGROUND_EVICTION_BATCH_VALUES as u64))?;
+            // The keys deleted, including any without values to punch.
+            let done = tx.changes() < BACKGROUND_EVICTION_BATCH_VALUES as u64;
+            expired.append(&mut blocks::take_released(&tx)?);
+            if !disable_hole_punching {
+                punch_queue::enqueue(&tx, &expired)?;
+            }
+            tx.commit()?;
+            if !disable_hole_punching && !expired.is_empty() {
+                value_puncher_waker.wake();
+            }
+            if done {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Has the background evictor delete keys as they expire.
+    pub(crate) fn request_expiry_sweep(&self) {
+        self.send_background_work(BackgroundWork::SweepExpired {
+            disable_hole_punching: self.instance_limits.disable_hole_punching,
+        });
+    }
+
+    /// Punches the values in the punch queue that are due, with its own dedicated connection. It
+    /// makes one last attempt at everything queued once the Handle is dropped, and what's left is
+    /// resumed by the next Handle on the directory. It also recovers values file tails, starting
+    /// soon after the Handle is opened. Failures are logged and retried with backoff, since the
+    /// queue outlives the Handle.
     fn value_puncher(
         dir: Dir,
-        values_receiver: sync::mpsc::Receiver<Vec<NonzeroValueLocation>>,
-    ) -> Result<()> {
-        let manifest_path = dir.path().join(MANIFEST_DB_FILE_NAME);
-        use rusqlite::OpenFlags;
-        let mut conn = Connection::open_with_flags(
-            manifest_path,
-            OpenFlags::SQLITE_OPEN_READ_ONLY
-                | OpenFlags::SQLITE_OPEN_NO_MUTEX
-                | OpenFlags::SQLITE_OPEN_URI,
-        )?;
-        const RETRY_DURATION: Duration = Duration::from_secs(1);
-        let mut pending_values: Vec<_> = Default::default();
-        let mut values_receiver_opt = Some(values_receiver);
-        while values_receiver_opt.is_some() || !pending_values.is_empty() {
-            match &values_receiver_opt {
-                Some(values_receiver) => {
-                    let timeout = if pending_values.is_empty() {
-                        Duration::MAX
-                    } else {
-                        RETRY_DURATION
-                    };
-                    let recv_result = values_receiver.recv_timeout(timeout);
-                    use std::sync::mpsc::RecvTimeoutError;
-                    match recv_result {
-                        Ok(mut values) => {
-                            pending_values.append(&mut values);
-                            // Drain the channel
-                            while let Ok(more_values) = values_receiver.try_recv() {
-                                pending_values.extend(more_values);
-                            }
-                        }
-                        Err(RecvTimeoutError::Timeout) => {}
-                        Err(RecvTimeoutError::Disconnected) => {
-                            // Don't try receiving again.
-                            values_receiver_opt = None;
-                        }
-                    }
+        wakeups: sync::mpsc::Receiver<()>,
+        stats: Arc<stats::Counters>,
+        durability: Arc<Mutex<Durability>>,
+    ) {
+        // Opened again after failures, in case the connection is the problem.
+        let mut conn: Option<BackgroundConn> = None;
+        let mut handle_dropped = false;
+        let mut retry_interval: Option<Duration> = None;
+        let mut next_tail_recovery = Instant::now() + TAIL_RECOVERY_DELAY;
+        loop {
+            let result =
+                BackgroundConn::open_if_needed(&mut conn, &dir, &durability).and_then(|conn| {
+                    Self::punch_queued_values(conn, &dir, handle_dropped, &stats)?;
+                    Ok(punch_queue::next_due(conn)?)
+                });
+            let next_due = match result {
+                Ok(next_due) => {
+                    retry_interval = None;
+                    next_due
                 }
-                None => {
-                    std::thread::sleep(RETRY_DURATION);
+                Err(err) => {
+                    let interval = retry_interval.map_or(MIN_BACKGROUND_RETRY_INTERVAL, |last| {
+                        min(last * 2, MAX_BACKGROUND_RETRY_INTERVAL)
+                    });
+                    error!(retry_interval = ?interval, "punching values failed: {err:?}");
+                    conn = None;
+                    retry_interval = Some(interval);
+                    retry_interval
                 }
+            };
+            if handle_dropped {
+                return;
+            }
+            if next_tail_recovery <= Instant::now() {
+                // Failing to reclaim space shouldn't stop the directory from being used.
+                let result = BackgroundConn::open_if_needed(&mut conn, &dir, &durability)
+                    .and_then(|conn| recovery::recover_tails(conn, &dir));
+                let retry_in = match result {
+                    Ok(Some(_)) => TAIL_RECOVERY_INTERVAL,
+                    Ok(None) => TAIL_RECOVERY_RETRY_INTERVAL,
+                    Err(err) => {
+                        error!("recovering values file tails: {err:?}");
+                        TAIL_RECOVERY_INTERVAL
+                    }
+                };
+                next_tail_recovery = Instant::now() + retry_in;
+            }
+            let timeout = min(
+                next_due.unwrap_or(Duration::MAX),
+                next_tail_recovery.saturating_duration_since(Instant::now()),
+            );
+            use std::sync::mpsc::RecvTimeoutError;
+            match wakeups.recv_timeout(timeout) {
+                Ok(()) | Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => handle_dropped = true,
             }
-            let tx = conn.transaction_with_behavior(TransactionBehavior::Deferred)?;
-            let tx = ReadTransactionOwned(tx);
-            pending_values = Self::punch_values(&dir, pending_values, &tx)?;
-            debug_assert_ne!(tx.0.transaction_state(None)?, TransactionState::Write);
         }
+    }
+
+    fn punch_queued_values(
+        conn: &mut Connection,
+        dir: &Dir,
+        ignore_backoff: bool,
+        stats: &stats::Counters,
+    ) -> Result<()> {
+        // Readers that could still see the deleted values finish cloning or locking them first.
+        let (due, PunchedValues { failed, truncated }) = match CloneLock::try_exclusive(dir)? {
+            Some(_clone_lock) => {
+                let tx = conn.transaction_with_behavior(TransactionBehavior::Deferred)?;
+                let tx = ReadTransactionOwned(tx);
+                let due = punch_queue::due(&tx.0, ignore_backoff)?;
+                let punched = Self::punch_values(dir, due.clone(), &tx)?;
+                debug_assert_ne!(tx.0.transaction_state(None)?, TransactionState::Write);
+                (due, punched)
+            }
+            None => {
+                // A Reader can be kept from taking its snapshot indefinitely, so the punches are
+                // retried with backoff instead of waiting for it.
+                let due = punch_queue::due(conn, ignore_backoff)?;
+                debug!(
+                    values = due.len(),
+                    "clone lock busy, retrying punches later"
+                );
+                let punched = PunchedValues {
+                    failed: due.clone(),
+                    truncated: vec![],
+                };
+                (due, punched)
+            }
+        };
+        let punched = due
+            .into_iter()
+            .filter(|location| !failed.contains(location))
+            .collect::<Vec<_>>();
+        let tx = Self::retry_while_busy(|| {
+            rusqlite::Transaction::new_unchecked(conn, TransactionBehavior::Immediate)
+        })?;
+        punch_queue::finish(&tx, &punched, &failed, &truncated)?;
+        let backlog = punch_queue::backlog(&tx)?;
+        tx.commit()?;
+        stats::Counters::add(&stats.punches, punched.len() as u64);
+        stats::Counters::add(&stats.failed_punches, failed.len() as u64);
+        stats::Counters::set(&stats.punch_queue_values, backlog.values);
+        stats::Counters::set(&stats.punch_queue_bytes, backlog.bytes);
         Ok(())
     }
 
-    /// Starts a read transaction to determine punch boundaries. Since punching is never expanded to
-    /// offsets above the targeted values, ongoing writes should not be affected.
+    /// Uses the read transaction to determine punch boundaries. Since punching is never expanded to
+    /// offsets above the targeted values, ongoing writes should not be affected. Values should be
+    /// ordered by file and offset.
     pub(crate) fn punch_values(
         dir: &Dir,
         values: Vec<NonzeroValueLocation>,
         transaction: &ReadTransactionOwned,
-    ) -> PubResult<Vec<NonzeroValueLocation>> {
-        let mut failed = Vec::with_capacity(values.len());
+    ) -> PubResult<PunchedValues> {
+        let mut punched = PunchedValues::default();
         for v in values {
             let NonzeroValueLocation {
                 file_id,
                 file_offset,
                 length,
             } = &v;
+            if punched
+                .truncated
+                .iter()
+                .any(|(truncated_id, end)| truncated_id == file_id && file_offset >= end)
+            {
+                // Already gone. The file may have been written to since, which the transaction
+                // can't see, so it must not be punched again.
+                continue;
+            }
             let value_length = length;
             let msg = format!(
                 "deleting value at {:?} {} {}",
@@ -403,36 +855,387 @@ impl Handle {
             );
             debug!("{}", msg);
             // self.handle.clones.lock().unwrap().remove(&file_id);
-            if !punch_value(PunchValueOptions {
+            let constraints =
+                if transaction.is_released_part(file_id, *file_offset, *value_length)? {
+                    // A part of a value that's still referenced, which was evicted or shares a
+                    // block elsewhere. Nothing around it can be punched.
+                    PunchValueConstraints {
+                        greedy_start: false,
+                        greedy_end: false,
+                        allow_truncate: false,
+                        allow_remove: false,
+                        ..Default::default()
+                    }
+                } else if transaction.range_overlaps_value(file_id, *file_offset, *value_length)? {
+                    // The file was truncated and written over since the value was queued.
+                    debug!("{} overlaps a value, skipping", msg);
+                    continue;
+                } else {
+                    Default::default()
+                };
+            match punch_value(PunchValueOptions {
                 dir: dir.path(),
                 file_id,
                 offset: *file_offset,
                 length: *value_length,
                 tx: transaction,
                 block_size: dir.block_size(),
-                constraints: Default::default(),
-            })
-            .context(msg)?
-            {
-                failed.push(v);
+                constraints,
+            }) {
+                Ok(PunchOutcome::Locked) => punched.failed.push(v),
+                Ok(PunchOutcome::Punched) => {}
+                Ok(PunchOutcome::Truncated(end)) => punched.truncated.push((*file_id, end)),
+                // Retried with backoff like a locked value, so one bad location doesn't hold up
+                // the rest of the queue.
+                Err(err) => {
+                    error!("{msg}: {err:?}");
+                    punched.failed.push(v);
+                }
             }
         }
-        Ok(failed)
+        Ok(punched)
     }
 
-    pub(crate) fn send_values_for_delete(&self, values: Vec<NonzeroValueLocation>) {
-        use std::sync::mpsc::TrySendError::*;
-        let sender = self.deleted_values.as_ref().unwrap();
-        match sender.try_send(values) {
-            Ok(()) => (),
-            Err(Disconnected(values)) => {
-                error!("sending {values:?}: channel disconnected");
+    /// Sends work to the background evictor, starting it if it isn't running.
+    fn send_background_work(&self, work: BackgroundWork) {
+        let Some(value_puncher_waker) = &self.value_puncher_waker else {
+            // Read-only Handles have nothing for it to do.
+            return;
+        };
+        let mut background_evictor = self.background_evictor.lock().unwrap();
+        let background_evictor = background_evictor.get_or_insert_with(|| {
+            let (work, requests) = sync::mpsc::channel();
+            let dir = self.dir.clone();
+            let value_puncher_waker = value_puncher_waker.clone();
+            let stats = Arc::clone(&self.stats);
+            let touches = Arc::clone(&self.touches);
+            let durability = Arc::clone(&self.durability);
+            let thread = thread::spawn(move || {
+                Self::background_evictor(
+                    dir,
+                    requests,
+                    value_puncher_waker,
+                    stats,
+                    touches,
+                    durability,
+                )
+            });
+            BackgroundEvictor {
+                work,
+                _thread: thread,
+            }
+        });
+        if background_evictor.work.send(work).is_err() {
+            error!("background evictor disconnected");
+        }
+    }
+
+    /// Evicts values down to the low watermark, or until there's enough free space, in small
+    /// transactions on a dedicated connection so writers don't wait on eviction unless
+    /// max_value_length_sum is exceeded. Free space is checked periodically with the latest limits
+    /// received. It also applies the Handle's touches, before evicting and whenever they're due.
+    /// Failures are logged and retried with backoff, so limits keep being enforced.
+    fn background_evictor(
+        dir: Dir,
+        requests: sync::mpsc::Receiver<BackgroundWork>,
+        value_puncher_waker: ValuePuncherWaker,
+        stats: Arc<stats::Counters>,
+        touches: Arc<touches::Touches>,
+        durability: Arc<Mutex<Durability>>,
+    ) {
+        // Opened again after failures, in case the connection is the problem.
+        let mut conn: Option<BackgroundConn> = None;
+        let mut latest: Option<BackgroundEviction> = None;
+        let mut retry_interval: Option<Duration> = None;
+        // The last measurement of the directory, for Measured usage accounting.
+        let mut measured: Option<(Instant, DiskUsage)> = None;
+        // Set once keys with an expiry are written: whether to punch expired values.
+        let mut sweep_expired: Option<bool> = None;
+        // When the next key expires. None if no key has an expiry, or it needs checking again.
+        let mut next_expiry: Option<SystemTime> = None;
+        let mut check_expiry = false;
+        loop {
+            let timeout = latest
+                .as_ref()
+                .and_then(BackgroundEviction::check_interval)
+                .unwrap_or(Duration::MAX);
+            let timeout = min(timeout, touches.due_in().unwrap_or(Duration::MAX));
+            let timeout = min(timeout, retry_interval.unwrap_or(Duration::MAX));
+            let timeout = min(
+                timeout,
+                next_expiry.map_or(Duration::MAX, |next_expiry| {
+                    next_expiry
+                        .duration_since(SystemTime::now())
+                        .unwrap_or_default()
+                }),
+            );
+            use std::sync::mpsc::RecvTimeoutError;
+            let mut evict = false;
+            match requests.recv_timeout(timeout) {
+                Ok(work) => {
+                    for work in std::iter::once(work).chain(requests.try_iter()) {
+                        let newer = match work {
+                            BackgroundWork::Evict(newer) => newer,
+                            BackgroundWork::FlushTouches => continue,
+                            BackgroundWork::SweepExpired {
+                                disable_hole_punching,
+                            } => {
+                                sweep_expired = Some(disable_hole_punching);
+                                check_expiry = true;
+                                continue;
+                            }
+                        };
+                        evict = true;
+                        match &mut latest {
+                            Some(request) => {
+                                request.over_high_watermark |= newer.over_high_watermark;
+                                request.limits = newer.limits;
+                            }
+                            None => latest = Some(newer),
+                        }
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => evict = latest.is_some(),
+                // The Handle is gone.
+                Err(RecvTimeoutError::Disconnected) => {
+                    let result = BackgroundConn::open_if_needed(&mut conn, &dir, &durability)
+                        .and_then(|conn| Self::apply_touches(conn, &touches));
+                    if let Err(err) = result {
+                        error!("applying touches: {err:?}");
+                    }
+                    return;
+                }
+            }
+            let result = (|| {
+                let conn = BackgroundConn::open_if_needed(&mut conn, &dir, &durability)?;
+                if let Some(disable_hole_punching) = sweep_expired {
+                    if check_expiry || next_expiry.is_some_and(|next| next <= SystemTime::now()) {
+                        Self::delete_expired_in_background(
+                            conn,
+                            disable_hole_punching,
+                            &value_puncher_waker,
+                        )?;
+                        next_expiry = expiry::next_expiry(conn)?;
+                        check_expiry = false;
+                    }
+                }
+                // Eviction goes by last_used, so it needs to see recent reads.
+                if evict || touches.due_in() == Some(Duration::ZERO) {
+                    Self::apply_touches(conn, &touches)?;
+                }
+                let Some(request) = latest.as_mut().filter(|_| evict) else {
+                    return Ok(());
+                };
+                Self::evict_in_background(
+                    conn,
+                    &dir,
+                    request,
+                    &mut measured,
+                    &value_puncher_waker,
+                    &stats,
+                )?;
+                // The watermarks are checked again when a commit exceeds the high watermark.
+                request.over_high_watermark = false;
+                anyhow::Ok(())
+            })();
+            retry_interval = match result {
+                Ok(()) => None,
+                Err(err) => {
+                    let retry_interval = retry_interval
+                        .map_or(MIN_BACKGROUND_RETRY_INTERVAL, |last| {
+                            min(last * 2, MAX_BACKGROUND_RETRY_INTERVAL)
+                        });
+                    error!(?retry_interval, "background eviction failed: {err:?}");
+                    conn = None;
+                    Some(retry_interval)
+                }
+            };
+        }
+    }
+
+    /// Evicts to the limits in request. Measured usage is reused from measured until it's
+    /// MEASURED_USAGE_REFRESH_INTERVAL old, less what has been evicted since.
+    fn evict_in_background(
+        conn: &Connection,
+        dir: &Dir,
+        request: &mut BackgroundEviction,
+        measured: &mut Option<(Instant, DiskUsage)>,
+        value_puncher_waker: &ValuePuncherWaker,
+        stats: &stats::Counters,
+    ) -> Result<()> {
+        let limits = request.limits.clone();
+        loop {
+            let tx = Self::retry_while_busy(|| {
+                rusqlite::Transaction::new_unchecked(conn, TransactionBehavior::Immediate)
+            })?;
+            let usage = match measured {
+                _ if limits.usage_accounting != UsageAccounting::Measured => {
+                    usage::disk_usage(&tx, dir, limits.usage_accounting)?
+                }
+                Some((measured_at, usage))
+                    if measured_at.elapsed() < usage::MEASURED_USAGE_REFRESH_INTERVAL =>
+                {
+                    *usage
+                }
+                _ => {
+                    let usage = usage::disk_usage(&tx, dir, limits.usage_accounting)?;
+                    *measured = Some((Instant::now(), usage));
+                    usage
+                }
+            };
+            // Commits only see the high watermark exceeded as they count usage.
+            if limits
+                .high_watermark
+                .is_some_and(|high_watermark| usage.total() > high_watermark)
+            {
+                request.over_high_watermark = true;
+            }
+            let mut target = 0;
+            if let Some(low_watermark) = request.low_watermark() {
+                target = usage.total().saturating_sub(low_watermark);
+            }
+            // Likewise for the maximum.
+            if let Some(max_value_length_sum) = limits.max_value_length_sum {
+                target = max(target, usage.total().saturating_sub(max_value_length_sum));
+            }
+            target = max(
+                target,
+                usage::free_space_shortfall(dir, &limits, || Ok(punch_queue::backlog(&tx)?.bytes))?,
+            );
+            target = min(target, usage.values);
+            if target == 0 {
+                return Ok(());
+            }
+            let evicted = eviction::evict_values(
+                &tx,
+                limits.eviction_policy,
+                target,
+                Some(BACKGROUND_EVICTION_BATCH_VALUES),
+                limits.partial_eviction,
+            )?;
+            if !limits.disable_hole_punching {
+                punch_queue::enqueue(&tx, &evicted.locations)?;
+            }
+            tx.commit()?;
+            evicted.record(stats);
+            if evicted.count == 0 {
+                // The sums count values that aren't in keys.
+                warn!(?usage, target, "no values could be evicted");
+                return Ok(());
             }
-            Err(Full(values)) => {
-                warn!("channel full while sending values. blocking.");
-                sender.send(values).unwrap()
+            if let Some((_, usage)) = measured {
+                // The space is freed once the values are punched.
+                usage.values = usage.values.saturating_sub(evicted.bytes);
             }
+            if !limits.disable_hole_punching {
+                value_puncher_waker.wake();
+            }
+        }
+    }
+
+    fn apply_touches(conn: &Connection, touches: &touches::Touches) -> Result<()> {
+        if touches.due_in().is_none() {
+            return Ok(());
         }
+        let tx = Self::retry_while_busy(|| {
+            rusqlite::Transaction::new_unchecked(conn, TransactionBehavior::Immediate)
+        })?;
+        touches.apply(&tx)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Wakes the background evictor to apply touches, after touches::Touches::record asks for it.
+    pub(crate) fn wake_touch_flush(&self) {
+        self.send_background_work(BackgroundWork::FlushTouches);
+    }
+
+    /// Passes the instance limits to the background evictor, and wakes it if the high watermark
+    /// was exceeded, or free space or usage needs checking.
+    pub(crate) fn request_background_eviction(&self, over_high_watermark: bool) {
+        let request = BackgroundEviction {
+            limits: self.instance_limits.clone(),
+            over_high_watermark,
+        };
+        if !over_high_watermark && request.check_interval().is_none() {
+            return;
+        }
+        self.send_background_work(BackgroundWork::Evict(request));
+    }
+
+    /// Statistics about this Handle since it was opened.
+    pub fn stats(&self) -> Stats {
+        let clone_cache_files = self.clones.lock().unwrap().len() as u64;
+        self.stats.snapshot(clone_cache_files)
+    }
+
+    /// The values waiting to be punched in the directory, by any Handle.
+    pub fn punch_backlog(&self) -> PubResult<PunchBacklog> {
+        Ok(punch_queue::backlog(
+            &self.start_deferred_transaction_for_read()?.0,
+        )?)
+    }
+
+    /// Wakes the value puncher after values are added to the punch queue.
+    pub(crate) fn wake_value_puncher(&self) {
+        self.value_puncher_waker.as_ref().unwrap().wake()
+    }
+
+    /// Closes the Handle, waiting up to timeout for the value puncher to punch everything that's
+    /// queued. Dropping a Handle doesn't wait, so processes that exit soon after deleting values
+    /// should close it instead. Exclusive files are released with their uncommitted writes
+    /// truncated, and the Handle's snapshot dirs are removed unless Snapshots are still using them.
+    pub fn close(self, timeout: Duration) -> PubResult<CloseReport> {
+        let deadline = Instant::now() + timeout;
+        let Handle {
+            conn,
+            exclusive_files,
+            clones,
+            value_puncher_waker,
+            value_puncher_done,
+            background_evictor,
+            ..
+        } = self;
+        let mut report = CloseReport::default();
+        for (_, mut exclusive_file) in exclusive_files.into_inner().unwrap() {
+            report.uncommitted_bytes_truncated += exclusive_file
+                .revert_uncommitted()
+                .with_context(|| format!("reverting exclusive file {}", exclusive_file.id))?;
+            report.exclusive_files_returned += 1;
+        }
+        let mut snapshot_dirs: Vec<PathBuf> = clones
+            .into_inner()
+            .unwrap()
+            .into_values()
+            .filter_map(|clone| {
+                let clone = clone.lock().unwrap();
+                Some(clone.tempdir.as_ref()?.path().to_owned())
+            })
+            .collect();
+        snapshot_dirs.sort();
+        snapshot_dirs.dedup();
+        // Tempdirs are removed when the last clone in them is dropped.
+        report.snapshot_dirs_in_use = snapshot_dirs
+            .into_iter()
+            .filter(|path| path.exists())
+            .collect();
+        // The background evictor holds a waker too, so it has to go before the value puncher sees
+        // the Handle is gone and makes its last pass. It applies the Handle's remaining touches on
+        // the way out, so they're in the manifest once the value puncher finishes.
+        drop(background_evictor);
+        drop(value_puncher_waker);
+        use std::sync::mpsc::RecvTimeoutError;
+        report.value_puncher_finished = matches!(
+            value_puncher_done
+                .0
+                .lock()
+                .unwrap()
+                .recv_timeout(deadline.saturating_duration_since(Instant::now())),
+            Err(RecvTimeoutError::Disconnected)
+        );
+        let conn = conn.into_inner().unwrap();
+        report.punch_backlog = punch_queue::backlog(&conn)?;
+        Ok(report)
     }
 
     /// Returns something that can be used to test if the value puncher routine for this Handle has returned.
@@ -441,7 +1244,7 @@ impl Handle {
     }
 
     pub fn move_prefix(&self, from: &[u8], to: &[u8]) -> Result<()> {
-        let mut tx = self.start_deferred_transaction()?;
+        let mut tx = self.start_immediate_transaction()?;
         let items = tx.list_items(from)?;
         let mut to_vec = to.to_vec();
         for item in items {
@@ -454,7 +1257,7 @@ impl Handle {
     }
 
     pub fn delete_prefix(&self, prefix: impl AsRef<[u8]>) -> PubResult<()> {
-        let mut tx = self.start_deferred_transaction()?;
+        let mut tx = self.start_immediate_transaction()?;
         for item in tx.list_items(prefix.as_ref())? {
             tx.delete_key(&item.key)?;
         }
@@ -467,21 +1270,16 @@ use item::Item;
 
 use crate::c_api::{PossumHandle, PossumHandleRc};
 use crate::dir::Dir;
+use crate::eviction::{
+    BackgroundEviction, BackgroundWork, BACKGROUND_EVICTION_BATCH_VALUES,
+    MAX_BACKGROUND_RETRY_INTERVAL, MIN_BACKGROUND_RETRY_INTERVAL,
+};
 use crate::owned_cell::{MutOwnedCell, OwnedCell};
 use crate::ownedtx::{OwnedReadTx, OwnedTxInner};
+use crate::recovery::{TAIL_RECOVERY_DELAY, TAIL_RECOVERY_INTERVAL, TAIL_RECOVERY_RETRY_INTERVAL};
 use crate::tx::ReadTransaction;
 use crate::walk::EntryType;
 
-impl Drop for Handle {
-    fn drop(&mut self) {
-        // self.deleted_values.take();
-        // if let Some(join_handle) = self.value_puncher.take() {
-        //     join_handle.thread().unpark();
-        //     join_handle.join().unwrap()
-        // }
-    }
-}
-
 #[derive(Debug)]
 pub struct ValuePuncherDone(Arc<Mutex<sync::mpsc::Receiver<()>>>);
 
@@ -512,8 +1310,11 @@ impl<'h, T> StartTransaction<'h, T> for &'h Handle {
         self,
         make_tx: impl FnOnce(&'h mut Connection, Self::TxHandle) -> rusqlite::Result<T>,
     ) -> rusqlite::Result<Self::Owned> {
+        let start = Instant::now();
         let guard = self.conn.lock().unwrap();
-        MutOwnedCell::try_make(guard, |conn| make_tx(conn, self))
+        let owned = MutOwnedCell::try_make(guard, |conn| make_tx(conn, self))?;
+        self.stats.record_manifest_wait(start.elapsed());
+        Ok(owned)
     }
 }
 
@@ -531,9 +1332,12 @@ impl<'h, T> StartTransaction<'h, T> for PossumHandleRc {
         OwnedCell::try_make(self, |handle_lock| {
             let handle_guard = Rc::new(handle_lock.read().unwrap());
             OwnedCell::try_make(handle_guard.clone(), |handle| {
-                MutOwnedCell::try_make(handle.conn.lock().unwrap(), |conn| {
+                let start = Instant::now();
+                let owned = MutOwnedCell::try_make(handle.conn.lock().unwrap(), |conn| {
                     make_tx(conn, handle_guard)
-                })
+                })?;
+                handle.stats.record_manifest_wait(start.elapsed());
+                Ok(owned)
             })
         })
     }
@@ -1,7 +1,7 @@
 #![allow(clippy::unused_unit)]
 
 use std::borrow::Borrow;
-use std::cmp::min;
+use std::cmp::{max, min};
 use std::collections::{BTreeSet, HashMap, HashSet};
 use std::ffi::{OsStr, OsString};
 use std::fmt::{Debug, Display, Formatter};
@@ -9,63 +9,106 @@ use std::fs::{read_dir, remove_dir, remove_file, File, OpenOptions};
 use std::io::SeekFrom::{End, Start};
 use std::io::{ErrorKind, Read, Seek, Write};
 use std::num::TryFromIntError;
-use std::ops::{Deref, DerefMut};
+use std::ops::{Deref, DerefMut, Range};
 use std::path::{Path, PathBuf};
 use std::rc::Rc;
 use std::sync::OnceLock;
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 use std::{fs, io, str};
 
 use anyhow::{anyhow, bail, Context, Result};
+pub use archive::ARCHIVE_MAGIC;
+pub use backup::BackupReport;
+use blocks::{BlockLocation, BlockMap};
 use cfg_if::cfg_if;
+pub use check::{CheckOptions, CheckReport, Issue, IssueKind};
+pub use checksum::Checksums;
+use checksum::{ChecksummedWriter, Checksummer, CHECKSUM_CHUNK_LEN};
 use chrono::NaiveDateTime;
+pub use compact::{CompactOptions, CompactReport};
+use compression::ValueEncoder;
+pub use compression::{Codec, Compression};
+pub use dir_sync::{SyncOptions, SyncReport};
+pub use durability::Durability;
+pub use encryption::{EncryptionKey, ValueNonce};
+use encryption::{StoredValueWriter, ValueCipher, ValueEncryptor};
 use env::flocking;
 pub use error::*;
+pub use eviction::EvictionPolicy;
 use exclusive_file::ExclusiveFile;
+pub use fetch::DEFAULT_FETCH_TIMEOUT;
 use file_id::FileId;
-pub use handle::Handle;
+use handle::ManifestUserVersion;
+pub use handle::{CloseReport, Handle, HandleOptions, Limits};
 use memmap2::Mmap;
 use num::Integer;
 use ownedtx::OwnedTx;
 use positioned_io::ReadAt;
 use rand::Rng;
+pub use recovery::TailRecoveryReport;
 use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, ValueRef};
 use rusqlite::Error::QueryReturnedNoRows;
-use rusqlite::{params, CachedStatement, Connection, Statement, TransactionBehavior};
+use rusqlite::{params, CachedStatement, Connection, OpenFlags, Statement, TransactionBehavior};
 use stable_deref_trait::StableDeref;
 use sys::*;
 use tempfile::TempDir;
 #[cfg(test)]
 pub use test_log::test;
 use tracing::*;
+pub use usage::{DiskUsage, UsageAccounting};
 use ErrorKind::InvalidInput;
 
 use crate::item::Item;
 use crate::walk::walk_dir;
 use crate::ValueLocation::{Nonzero, ZeroLength};
 
+mod archive;
+mod backup;
+mod blocks;
 mod c_api;
+mod check;
+mod checksum;
+mod clone_lock;
+mod compact;
+mod compression;
 mod cpathbuf;
+mod dedup;
 mod dir;
+mod dir_sync;
+mod durability;
+mod encryption;
 mod error;
+mod eviction;
 mod exclusive_file;
+mod expiry;
+mod fetch;
 mod file_id;
 pub(crate) mod handle;
 mod item;
+mod migrations;
 mod owned_cell;
+mod punch_queue;
 pub mod sys;
 #[cfg(feature = "testing")]
 pub mod testing;
 #[cfg(test)]
 mod tests;
 mod tx;
+mod usage;
 pub use tx::*;
 mod ownedtx;
 pub mod walk;
 pub use dir::*;
 pub mod env;
 mod reader;
+mod recovery;
+mod settings;
+mod stats;
+mod touches;
+use clone_lock::CloneLock;
+pub use punch_queue::PunchBacklog;
 use reader::Reader;
+pub use stats::Stats;
 
 // Concurrency-related stuff that's replaced by loom or shuttle.
 pub mod concurrency;
@@ -113,6 +156,20 @@ struct PendingWrite {
     value_file_offset: u64,
     value_length: u64,
     value_file_id: FileId,
+    expires: Option<Timestamp>,
+    /// Defaults to the time of the commit.
+    last_used: Option<Timestamp>,
+    compression: Option<Compression>,
+    nonce: Option<ValueNonce>,
+    checksums: Option<Checksums>,
+    /// Set at commit when deduplicating.
+    content_hash: Option<i64>,
+    /// An existing value with the same stored bytes, found at commit before the manifest is locked.
+    duplicate_of: Option<NonzeroValueLocation>,
+    /// Like duplicate_of, for each part of a value stored in blocks. Empty if none were looked for.
+    duplicate_blocks: Vec<Option<NonzeroValueLocation>>,
+    /// Whether the value is stored in blocks. Set at commit, see blocks::in_blocks.
+    in_blocks: bool,
 }
 
 const MANIFEST_SCHEMA_SQL: &str = include_str!("../manifest.sql");
@@ -141,13 +198,22 @@ where
     // for a discussion on efficient ways to copy values that could be supported.
     /// Clone an entire file in. If cloning fails, this will fall back to copying the provided file.
     /// Its file position may be altered.
-    pub fn clone_file(self, file: &mut File) -> PubResult<ValueWriter> {
+    pub fn clone_file(mut self, file: &mut File) -> PubResult<ValueWriter> {
+        match self.try_clone_file(file)? {
+            Some(value_writer) => Ok(value_writer),
+            None => self.copy_file(file),
+        }
+    }
+
+    /// Clones an entire file in, or returns None if the filesystem doesn't support cloning it.
+    pub(crate) fn try_clone_file(&mut self, file: &mut File) -> PubResult<Option<ValueWriter>> {
+        // Cloned blocks can't be encrypted in place, so they're encrypted as they're copied in.
         if !self
             .batch
             .handle
-            .with_handle(Handle::dir_supports_file_cloning)
+            .with_handle(|handle| handle.dir_supports_file_cloning() && handle.cipher.is_none())
         {
-            return self.copy_file(file);
+            return Ok(None);
         }
         let dst_path = loop {
             let dst_path = self
@@ -156,7 +222,7 @@ where
                 .with_handle(|handle| handle.dir.path().join(FileId::random().values_file_path()));
             match fclonefile_noflags(file, &dst_path) {
                 Err(err) if CloneFileError::is_unsupported(&err) => {
-                    return self.copy_file(file);
+                    return Ok(None);
                 }
                 Err(err) if err.is_file_already_exists() => continue,
                 Err(err) => return Err(err.into()),
@@ -167,10 +233,14 @@ where
         // someone else could open it before us. In that case we probably want to punch out the part
         // we cloned and move on.
         let exclusive_file = ExclusiveFile::open(dst_path)?.unwrap();
-        Ok(ValueWriter {
+        Ok(Some(ValueWriter {
             exclusive_file,
             value_file_offset: 0,
-        })
+            encoder: None,
+            encryptor: None,
+            // The clone isn't read, so it's not checksummed.
+            checksummer: None,
+        }))
     }
 
     /// Assigns an exclusive file for writing, and copies the entire source file.
@@ -185,9 +255,16 @@ where
     /// Assign an exclusive file for writing a value.
     pub fn begin(self) -> PubResult<ValueWriter> {
         let mut exclusive_file = self.batch.get_exclusive_file()?;
+        let cipher = self
+            .batch
+            .handle
+            .with_handle(|handle| handle.cipher.clone());
         Ok(ValueWriter {
             value_file_offset: exclusive_file.next_write_offset()?,
             exclusive_file,
+            encoder: None,
+            encryptor: cipher.map(ValueEncryptor::new),
+            checksummer: Some(Default::default()),
         })
     }
 }
@@ -197,41 +274,117 @@ where
 pub struct ValueWriter {
     exclusive_file: ExclusiveFile,
     value_file_offset: u64,
+    // Some if the value is being compressed.
+    encoder: Option<ValueEncoder>,
+    // Some if the Handle encrypts values.
+    encryptor: Option<ValueEncryptor>,
+    // Some unless the value is written some way other than through the ValueWriter.
+    checksummer: Option<Checksummer>,
+}
+
+/// How a value was stored, known once its ValueWriter is finished.
+pub(crate) struct ValueEncoding {
+    compression: Option<Compression>,
+    nonce: Option<ValueNonce>,
 }
 
 impl ValueWriter {
+    /// Writes directly to the file, bypassing compression. Not available if the Handle encrypts
+    /// values. The value won't have checksums, since what's written to the file isn't seen.
     pub fn get_file(&mut self) -> Result<&mut File> {
+        if self.encryptor.is_some() {
+            bail!("values are encrypted");
+        }
+        self.checksummer = None;
         Ok(&mut self.exclusive_file.inner)
     }
 
+    /// Compresses the bytes written from here on with codec. Readers decompress the value
+    /// transparently. This must be called before anything is written.
+    pub fn compress(&mut self, codec: Codec) -> PubResult<()> {
+        if self.value_length()? != 0 || self.encryptor.as_ref().is_some_and(|e| !e.is_empty()) {
+            return Err(anyhow!("compression must be set before writing the value").into());
+        }
+        self.encoder = Some(ValueEncoder::new(codec)?);
+        Ok(())
+    }
+
     pub fn copy_from(&mut self, mut value: impl Read) -> PubResult<u64> {
         let value_file_offset = self.exclusive_file.next_write_offset()?;
-        let value_length = match std::io::copy(&mut value, &mut self.exclusive_file.inner) {
+        let codec = self.encoder.as_ref().map(ValueEncoder::codec);
+        let value_length = match std::io::copy(&mut value, self) {
             Ok(ok) => ok,
             Err(err) => {
                 self.exclusive_file
                     .inner
                     .seek(Start(value_file_offset))
                     .expect("should rewind failed copy");
+                if let Some(codec) = codec {
+                    self.encoder = Some(ValueEncoder::new(codec)?);
+                }
+                if let Some(encryptor) = self.encryptor.take() {
+                    self.encryptor = Some(ValueEncryptor::new(encryptor.into_cipher()));
+                }
+                if self.checksummer.is_some() {
+                    self.checksummer = Some(Default::default());
+                }
                 return Err(err.into());
             }
         };
         Ok(value_length)
     }
 
+    /// The number of bytes written to the values file so far. For compressed or encrypted values
+    /// this is the length on disk, and it's only complete once the value is staged.
     pub fn value_length(&mut self) -> io::Result<u64> {
         Ok(self.exclusive_file.next_write_offset()? - self.value_file_offset)
     }
+
+    /// Flushes the remainder of a compressed or encrypted value to the file.
+    fn finish_encoding(&mut self) -> io::Result<(ValueEncoding, Option<Checksums>)> {
+        let mut file = ChecksummedWriter {
+            file: &mut self.exclusive_file.inner,
+            checksummer: self.checksummer.as_mut(),
+        };
+        let compression = match self.encoder.take() {
+            None => None,
+            Some(encoder) => encoder.finish(&mut StoredValueWriter {
+                file: &mut file,
+                encryptor: self.encryptor.as_mut(),
+            })?,
+        };
+        let nonce = match self.encryptor.take() {
+            None => None,
+            Some(encryptor) => encryptor.finish(&mut file)?,
+        };
+        Ok((
+            ValueEncoding { compression, nonce },
+            self.checksummer.take().and_then(Checksummer::finish),
+        ))
+    }
 }
 
 impl Write for ValueWriter {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        let file = &mut self.exclusive_file.inner;
-        file.write(buf)
+        let mut file = ChecksummedWriter {
+            file: &mut self.exclusive_file.inner,
+            checksummer: self.checksummer.as_mut(),
+        };
+        let mut stored = StoredValueWriter {
+            file: &mut file,
+            encryptor: self.encryptor.as_mut(),
+        };
+        match &mut self.encoder {
+            None => stored.write(buf),
+            Some(encoder) => {
+                encoder.write_all(buf, &mut stored)?;
+                Ok(buf.len())
+            }
+        }
     }
 
     fn flush(&mut self) -> io::Result<()> {
-        // This makes no sense until commit.
+        // Values are synced at commit, if the Handle's durability requires it.
         Ok(())
     }
 }
@@ -250,6 +403,9 @@ where
 {
     handle: H,
     exclusive_files: Vec<ExclusiveFile>,
+    // Duplicates of exclusive files reused for another value. They share the file's lock, so it's
+    // kept even if the ValueWriter that took the file is dropped with staged values in it.
+    reused_files: Vec<File>,
     pending_writes: Vec<PendingWrite>,
     value_renames: Vec<ValueRename>,
 }
@@ -262,6 +418,7 @@ where
         Self {
             handle,
             exclusive_files: Default::default(),
+            reused_files: Default::default(),
             pending_writes: Default::default(),
             value_renames: Default::default(),
         }
@@ -283,6 +440,26 @@ impl FromSql for Timestamp {
     }
 }
 
+impl ToSql for Timestamp {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        // Same representation as the manifest uses for last_used.
+        Ok(self.0.and_utc().timestamp_millis().into())
+    }
+}
+
+impl From<SystemTime> for Timestamp {
+    fn from(value: SystemTime) -> Self {
+        Self(chrono::DateTime::<chrono::Utc>::from(value).naive_utc())
+    }
+}
+
+impl Timestamp {
+    /// A Timestamp the given duration from now. Useful for setting value expiry.
+    pub fn after(duration: Duration) -> Self {
+        (SystemTime::now() + duration).into()
+    }
+}
+
 // This may only be public for external tests.
 pub const LAST_USED_RESOLUTION: Duration = Duration::from_millis(1);
 
@@ -304,7 +481,18 @@ impl WriteCommitResult {
     }
 }
 
-const VALUE_COLUMN_NAMES: &[&str] = &["file_id", "file_offset", "value_length", "last_used"];
+const VALUE_COLUMN_NAMES: &[&str] = &[
+    "file_id",
+    "file_offset",
+    "value_length",
+    "last_used",
+    "expires",
+    "codec",
+    "uncompressed_length",
+    "nonce",
+    "key_id",
+    "in_blocks",
+];
 
 fn value_columns_sql() -> &'static str {
     static ONCE: OnceLock<String> = OnceLock::new();
@@ -318,13 +506,39 @@ where
     fn get_exclusive_file(&mut self) -> Result<ExclusiveFile> {
         if let Some(ef) = self.exclusive_files.pop() {
             debug!("reusing exclusive file from writer");
+            self.reused_files.push(ef.inner.try_clone()?);
             return Ok(ef);
         }
         self.handle.with_handle(Handle::get_exclusive_file)
     }
 
-    pub fn stage_write(&mut self, key: Vec<u8>, mut value: ValueWriter) -> anyhow::Result<()> {
-        let value_length = match value.value_length() {
+    pub fn stage_write(&mut self, key: Vec<u8>, value: ValueWriter) -> anyhow::Result<()> {
+        self.stage_write_with_expiry(key, value, None)
+    }
+
+    /// Stages a write for a key that will be treated as missing once expires has passed. Expired
+    /// values are deleted and hole punched on subsequent manifest writes.
+    pub fn stage_write_with_expiry(
+        &mut self,
+        key: Vec<u8>,
+        value: ValueWriter,
+        expires: Option<Timestamp>,
+    ) -> anyhow::Result<()> {
+        self.stage_write_inner(key, value, expires, None)
+    }
+
+    /// Stages a write that keeps the last_used time of a value from elsewhere, such as an archive.
+    pub(crate) fn stage_write_inner(
+        &mut self,
+        key: Vec<u8>,
+        mut value: ValueWriter,
+        expires: Option<Timestamp>,
+        last_used: Option<Timestamp>,
+    ) -> anyhow::Result<()> {
+        let value_length = match value
+            .finish_encoding()
+            .and_then(|encoding| Ok((value.value_length()?, encoding)))
+        {
             Ok(ok) => ok,
             Err(err) => {
                 if let Err(err) = value
@@ -338,6 +552,10 @@ where
                 return Err(err.into());
             }
         };
+        let (value_length, (ValueEncoding { compression, nonce }, checksums)) = value_length;
+        self.handle.with_handle(|handle| {
+            stats::Counters::add(&handle.stats.bytes_written, value_length);
+        });
         let exclusive_file = value.exclusive_file;
         let value_file_id = exclusive_file.id;
         self.exclusive_files.push(exclusive_file);
@@ -346,11 +564,48 @@ where
             value_file_offset: value.value_file_offset,
             value_length,
             value_file_id,
+            expires,
+            last_used,
+            compression,
+            nonce,
+            checksums,
+            content_hash: None,
+            duplicate_of: None,
+            duplicate_blocks: vec![],
+            in_blocks: false,
         });
         Ok(())
     }
 
-    pub fn new_value(&mut self) -> BeginWriteValue<H> {
+    /// Stages writes for values that are already in the file, at their locations in the source
+    /// the file was cloned from.
+    pub(crate) fn stage_cloned_values(
+        &mut self,
+        cloned: ValueWriter,
+        values: Vec<(Vec<u8>, Value, Option<Checksums>)>,
+    ) {
+        let value_file_id = cloned.exclusive_file.id;
+        self.exclusive_files.push(cloned.exclusive_file);
+        for (key, value, checksums) in values {
+            self.pending_writes.push(PendingWrite {
+                key,
+                value_file_offset: value.file_offset().unwrap(),
+                value_length: value.length(),
+                value_file_id,
+                expires: value.expires(),
+                last_used: Some(value.last_used()),
+                compression: value.compression(),
+                nonce: value.nonce(),
+                checksums,
+                content_hash: None,
+                duplicate_of: None,
+                duplicate_blocks: vec![],
+                in_blocks: false,
+            });
+        }
+    }
+
+    pub fn new_value(&mut self) -> BeginWriteValue<'_, H> {
         BeginWriteValue { batch: self }
     }
 
@@ -371,35 +626,79 @@ where
                 assert!(ef.downgrade_lock()?);
             }
         }
+        if self
+            .handle
+            .with_handle(|handle| handle.durability().sync_values())
+        {
+            // Keys mustn't refer to values that could be lost.
+            for ef in &self.exclusive_files {
+                ef.inner.sync_data().context("syncing values file")?;
+                self.handle
+                    .with_handle(|handle| stats::Counters::add(&handle.stats.values_file_syncs, 1));
+            }
+        }
+        let partial_eviction = self
+            .handle
+            .with_handle(|handle| handle.instance_limits.partial_eviction);
+        for pw in &mut self.pending_writes {
+            pw.in_blocks = blocks::in_blocks(
+                pw.value_length,
+                pw.compression.is_some() || pw.nonce.is_some(),
+                partial_eviction,
+            );
+        }
+        // Hash and compare before locking the manifest, since values written without checksums and
+        // the duplicates of values are read back.
+        if self.handle.with_handle(|handle| handle.dedup_values) {
+            for index in 0..self.pending_writes.len() {
+                let (earlier, pw) = self.pending_writes.split_at_mut(index);
+                let pw = &mut pw[0];
+                self.handle.with_handle(|handle| {
+                    dedup::hash_pending_write(handle.dir.path(), pw)
+                        .context("hashing value for deduplication")?;
+                    if pw.in_blocks {
+                        pw.duplicate_blocks = dedup::find_duplicate_blocks(handle, earlier, pw)?;
+                    } else {
+                        pw.duplicate_of = dedup::find_duplicate(handle, earlier, pw)?;
+                    }
+                    anyhow::Ok(())
+                })?;
+            }
+        }
         let write_commit_res = self.handle.with_handle(|handle| {
             let mut transaction: OwnedTx = handle.start_immediate_transaction()?;
             let mut write_commit_res = WriteCommitResult { count: 0 };
-            for pw in self.pending_writes.drain(..) {
+            let bytes: u64 = self.pending_writes.iter().map(|pw| pw.value_length).sum();
+            for mut pw in self.pending_writes.drain(..) {
                 before_write();
                 transaction.delete_key(&pw.key)?;
+                transaction.dedup_value(&mut pw)?;
                 transaction.insert_key(pw)?;
                 write_commit_res.count += 1;
             }
             for vr in self.value_renames.drain(..) {
                 transaction.rename_value(&vr.value, vr.new_key)?;
             }
+            // Readers may lock the values as soon as the keys refer to them.
+            for ef in &mut self.exclusive_files {
+                if !ef.committed()? {
+                    bail!("committing exclusive file {}", ef.id);
+                }
+            }
             // TODO: On error here, rewind the exclusive to undo any writes that just occurred.
             let work = transaction.commit().context("commit transaction")?;
             work.complete();
+            stats::Counters::add(
+                &handle.stats.values_committed,
+                write_commit_res.count as u64,
+            );
+            stats::Counters::add(&handle.stats.bytes_committed, bytes);
             anyhow::Ok(write_commit_res)
         })?;
-        self.flush_exclusive_files();
+        self.return_exclusive_files_to_handle();
         Ok(write_commit_res)
     }
 
-    /// Flush Writer's exclusive files and return them to the Handle pool.
-    fn flush_exclusive_files(&mut self) {
-        for ef in &mut self.exclusive_files {
-            ef.committed().unwrap();
-        }
-        self.return_exclusive_files_to_handle()
-    }
-
     fn return_exclusive_files_to_handle(&mut self) {
         // When we're flocking, we can't have writers and readers at the same time and still be
         // able to punch values asynchronously.
@@ -430,7 +729,13 @@ type ValueLength = u64;
 #[derive(Debug, Clone, PartialEq, Copy)]
 pub struct Value {
     pub location: ValueLocation,
+    // Identifies the key the value was read for, since deduplicated values share a location.
+    key_id: i64,
     last_used: Timestamp,
+    expires: Option<Timestamp>,
+    compression: Option<Compression>,
+    nonce: Option<ValueNonce>,
+    in_blocks: bool,
 }
 
 /// Storage location info for a non-zero-length value.
@@ -489,15 +794,9 @@ impl Deref for Value {
 
 impl Value {
     fn from_row(row: &rusqlite::Row) -> rusqlite::Result<Self> {
-        Self::from_column_values(row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)
-    }
-
-    fn from_column_values(
-        file_id: Option<FileId>,
-        file_offset: Option<u64>,
-        length: ValueLength,
-        last_used: Timestamp,
-    ) -> rusqlite::Result<Self> {
+        let file_id: Option<FileId> = row.get(0)?;
+        let file_offset: Option<u64> = row.get(1)?;
+        let length: ValueLength = row.get(2)?;
         let location = if length == 0 {
             assert_eq!(file_id, None);
             assert_eq!(file_offset, None);
@@ -511,13 +810,47 @@ impl Value {
         };
         Ok(Value {
             location,
-            last_used,
+            key_id: row.get(8)?,
+            last_used: row.get(3)?,
+            expires: row.get(4)?,
+            compression: Compression::from_column_values(row.get(5)?, row.get(6)?),
+            nonce: row.get(7)?,
+            in_blocks: row.get(9)?,
         })
     }
 
     pub fn last_used(&self) -> Timestamp {
         self.last_used
     }
+
+    /// When the value stops being visible to readers, if ever.
+    pub fn expires(&self) -> Option<Timestamp> {
+        self.expires
+    }
+
+    /// How the value is compressed on disk, if it is.
+    pub fn compression(&self) -> Option<Compression> {
+        self.compression
+    }
+
+    /// The nonce the value was encrypted with, if it's encrypted.
+    pub fn nonce(&self) -> Option<ValueNonce> {
+        self.nonce
+    }
+
+    /// Whether the value is stored in blocks. See blocks::in_blocks.
+    pub(crate) fn in_blocks(&self) -> bool {
+        self.in_blocks
+    }
+
+    /// The length of the value as readers see it. length() is the length on disk.
+    pub fn uncompressed_length(&self) -> u64 {
+        match (self.compression, self.nonce) {
+            (Some(compression), _) => compression.uncompressed_length,
+            (None, Some(_)) => encryption::plaintext_length(self.length()),
+            (None, None) => self.length(),
+        }
+    }
 }
 
 impl AsRef<Value> for Value {
@@ -541,13 +874,31 @@ impl AsRef<Snapshot> for Snapshot {
 #[derive(Debug)]
 pub struct Snapshot {
     file_clones: HashMap<FileId, Arc<Mutex<FileClone>>>,
+    // For reading encrypted values.
+    cipher: Option<ValueCipher>,
+    verify_reads: bool,
+    // Where the parts of the snapshot's values stored in blocks are, by key.
+    blocks: HashMap<i64, Arc<BlockMap>>,
+    // The checksums of the snapshot's values, by key. Kept out of Value so it stays Copy.
+    checksums: HashMap<i64, Checksums>,
+    // Where reads of blocks are recorded. None for read-only Handles.
+    touches: Option<Arc<touches::Touches>>,
 }
 
 #[derive(Debug)]
 pub struct SnapshotValue<V> {
     value: V,
-    // This is Some if value is Nonzero.
+    // This is Some if value is Nonzero, unless it's stored in blocks and none are in its own file.
     cloned_file: Option<Arc<Mutex<FileClone>>>,
+    cipher: Option<ValueCipher>,
+    verify_reads: bool,
+    // Set if the value is stored in blocks.
+    blocks: Option<Arc<BlockMap>>,
+    checksums: Option<Checksums>,
+    touches: Option<Arc<touches::Touches>>,
+    // Filled on first view of a compressed or encrypted value. Also holds the parts of a value
+    // stored in blocks that aren't all in place, once viewed.
+    decoded: OnceLock<Vec<u8>>,
 }
 
 impl<V> Deref for SnapshotValue<V> {
@@ -567,17 +918,54 @@ impl Snapshot {
             cloned_file: value
                 .as_ref()
                 .file_id()
-                .map(|file_id| Arc::clone(self.file_clones.get(file_id).unwrap())),
+                .and_then(|file_id| self.file_clones.get(file_id))
+                .map(Arc::clone),
+            blocks: self.blocks.get(&value.as_ref().key_id).map(Arc::clone),
+            checksums: self.checksums.get(&value.as_ref().key_id).cloned(),
+            touches: self.touches.clone(),
             value,
+            cipher: self.cipher.clone(),
+            verify_reads: self.verify_reads,
+            decoded: OnceLock::new(),
         }
     }
 }
 
+/// Reads a snapshot value's stored bytes from the start, decrypted, for decompressing them.
+struct StoredReader<'a, V> {
+    value: &'a SnapshotValue<V>,
+    pos: u64,
+}
+
+impl<V> Read for StoredReader<'_, V>
+where
+    V: AsRef<Value>,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.value.read_stored_at(self.pos, buf)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
 impl<V> ReadAt for SnapshotValue<V>
 where
     V: AsRef<Value>,
 {
-    fn read_at(&self, pos: u64, mut buf: &mut [u8]) -> io::Result<usize> {
+    fn read_at(&self, pos: u64, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(blocks) = &self.blocks {
+            return self.read_blocks_at(blocks, pos, buf);
+        }
+        if let Some(decoded) = self.decoded.get() {
+            return decoded.read_at(pos, buf);
+        }
+        if let Some(compression) = self.value.as_ref().compression() {
+            let stored = StoredReader {
+                value: self,
+                pos: 0,
+            };
+            return compression.decompress_at(stored, pos, buf);
+        }
         if false {
             // TODO: Create a thiserror or io::Error for non-usize pos.
             // let pos = usize::try_from(pos).expect("pos should be usize");
@@ -588,35 +976,7 @@ where
             // dbg!(buf.split_at(n).0);
             Ok(n)
         } else {
-            match self.value.as_ref().location {
-                ValueLocation::ZeroLength => Ok(0),
-                Nonzero(NonzeroValueLocation {
-                    file_offset,
-                    length,
-                    ..
-                }) => {
-                    if pos >= length {
-                        return Ok(0);
-                    }
-                    let available = length - pos;
-                    buf = buf
-                        .split_at_mut(min(buf.len() as u64, available) as usize)
-                        .0;
-                    let mut file_clone = self.file_clone().unwrap().lock().unwrap();
-                    let file = &mut file_clone.file;
-                    let file_offset = file_offset + pos;
-                    // Getting lazy: Using positioned-io's ReadAt because it works on Windows.
-                    let res = file.read_at(file_offset, buf);
-                    debug!(
-                        ?file,
-                        ?file_offset,
-                        len = buf.len(),
-                        ?res,
-                        "snapshot value read_at"
-                    );
-                    res
-                }
-            }
+            self.read_stored_at(pos, buf)
         }
     }
 }
@@ -629,8 +989,289 @@ where
         self.cloned_file.as_ref()
     }
 
+    /// Reads the value's stored bytes at pos, decrypting them if the value is encrypted, and
+    /// checking them if reads are verified.
+    fn read_stored_at(&self, pos: u64, mut buf: &mut [u8]) -> io::Result<usize> {
+        let value = self.value.as_ref();
+        if let Some((cipher, nonce)) = self.decryption()? {
+            return cipher.read_at(nonce, value.length(), pos, buf, |offset, sealed| {
+                self.read_stored_exact_at(offset, sealed)
+            });
+        }
+        if self.verifies() {
+            if pos >= value.length() {
+                return Ok(0);
+            }
+            let n = min(buf.len() as u64, value.length() - pos) as usize;
+            self.read_stored_exact_at(pos, &mut buf[..n])?;
+            return Ok(n);
+        }
+        match value.location {
+            ValueLocation::ZeroLength => Ok(0),
+            Nonzero(NonzeroValueLocation {
+                file_offset,
+                length,
+                ..
+            }) => {
+                if pos >= length {
+                    return Ok(0);
+                }
+                let available = length - pos;
+                buf = buf
+                    .split_at_mut(min(buf.len() as u64, available) as usize)
+                    .0;
+                let mut file_clone = self.file_clone().unwrap().lock().unwrap();
+                let file = &mut file_clone.file;
+                let file_offset = file_offset + pos;
+                // Getting lazy: Using positioned-io's ReadAt because it works on Windows.
+                let res = file.read_at(file_offset, buf);
+                debug!(
+                    ?file,
+                    ?file_offset,
+                    len = buf.len(),
+                    ?res,
+                    "snapshot value read_at"
+                );
+                res
+            }
+        }
+    }
+
+    /// Calls f with the value's bytes. Compressed values are decompressed into a buffer that's
+    /// kept for the life of the SnapshotValue.
     pub fn view<R>(&self, f: impl FnOnce(&[u8]) -> R) -> io::Result<R> {
+        match self.decoded()? {
+            Some(decoded) => Ok(f(decoded)),
+            None => self.verified_raw_view(f),
+        }
+    }
+
+    /// Checksums of the value as stored on disk. None for empty values, and values written
+    /// without going through a ValueWriter, such as by cloning.
+    pub fn checksums(&self) -> Option<&Checksums> {
+        self.checksums.as_ref()
+    }
+
+    /// Checks the value's stored bytes against its checksums. Returns false if the value has no
+    /// checksums to check.
+    pub fn verify(&self) -> PubResult<bool> {
+        let Some(checksums) = self.checksums() else {
+            return Ok(false);
+        };
+        self.raw_view(|bytes| checksums.verify(0, bytes))?
+            .map_err(|chunk| self.corrupt(chunk))?;
+        Ok(true)
+    }
+
+    /// Whether reads should be checked against checksums.
+    fn verifies(&self) -> bool {
+        self.verify_reads && self.checksums.is_some()
+    }
+
+    fn corrupt(&self, chunk: u64) -> Error {
+        let location = self.value.as_ref().location.into_non_zero().unwrap();
+        Error::Corrupt {
+            file_id: location.file_id,
+            file_offset: location.file_offset,
+            chunk,
+        }
+    }
+
+    fn corrupt_io_error(&self, chunk: u64) -> io::Error {
+        io::Error::new(ErrorKind::InvalidData, self.corrupt(chunk))
+    }
+
+    /// The ranges of the value dropped by partial eviction, as offsets within the value. Reads
+    /// that need them fail with Error::PartiallyEvicted, wrapped in an io::Error with kind
+    /// NotFound.
+    pub fn missing_ranges(&self) -> &[Range<u64>] {
+        self.blocks
+            .as_deref()
+            .map(BlockMap::missing)
+            .unwrap_or_default()
+    }
+
+    /// Whether all of the value's stored bytes are at its location, so cloning the file it's in
+    /// transfers it. Parts of values stored in blocks can share blocks elsewhere.
+    pub(crate) fn stored_in_place(&self) -> bool {
+        match (&self.blocks, self.value.as_ref().location) {
+            (Some(blocks), Nonzero(location)) => blocks.in_place(&location),
+            _ => true,
+        }
+    }
+
+    /// Records a read of a block, like reads of keys are recorded.
+    fn touch(&self, block: &BlockLocation) {
+        if let Some(touches) = &self.touches {
+            touches.record_block(block, SystemTime::now().into());
+        }
+    }
+
+    /// Where the value's stored byte at offset is in the snapshot. Parts of values stored in blocks
+    /// can be in other files, and the read is recorded against the block.
+    fn stored_at(&self, offset: u64) -> io::Result<(&Arc<Mutex<FileClone>>, u64)> {
+        let Some(blocks) = &self.blocks else {
+            let file_offset = self.value.as_ref().file_offset().unwrap();
+            return Ok((self.file_clone().unwrap(), file_offset + offset));
+        };
+        let part = blocks.part_at(offset).unwrap();
+        let Some(block) = &part.block else {
+            return Err(self.partially_evicted_io_error());
+        };
+        self.touch(block);
+        Ok((
+            blocks.file(block),
+            block.file_offset + offset - part.range.start,
+        ))
+    }
+
+    /// Reads a value stored in blocks a part at a time. Reads stop short of evicted parts, so
+    /// everything before them can be read.
+    fn read_blocks_at(&self, blocks: &BlockMap, mut pos: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let mut n = 0;
+        while n < buf.len() {
+            let Some(part) = blocks.part_at(pos) else {
+                break;
+            };
+            if part.block.is_none() && n != 0 {
+                break;
+            }
+            let len = min((buf.len() - n) as u64, part.range.end - pos) as usize;
+            let part_buf = &mut buf[n..n + len];
+            if self.verifies() {
+                self.read_stored_exact_at(pos, part_buf)?;
+            } else {
+                let (file_clone, file_offset) = self.stored_at(pos)?;
+                file_clone
+                    .lock()
+                    .unwrap()
+                    .file
+                    .read_exact_at(file_offset, part_buf)?;
+            }
+            n += len;
+            pos += len as u64;
+        }
+        Ok(n)
+    }
+
+    fn partially_evicted_io_error(&self) -> io::Error {
+        io::Error::new(
+            ErrorKind::NotFound,
+            Error::PartiallyEvicted {
+                missing: self.missing_ranges().to_vec(),
+            },
+        )
+    }
+
+    /// Like raw_view, but checks the bytes first if the Handle verifies reads.
+    fn verified_raw_view<R>(&self, f: impl FnOnce(&[u8]) -> R) -> io::Result<R> {
+        if !self.verifies() {
+            return self.raw_view(f);
+        }
+        let checksums = self.checksums().unwrap();
+        self.raw_view(|bytes| match checksums.verify(0, bytes) {
+            Ok(()) => Ok(f(bytes)),
+            Err(chunk) => Err(self.corrupt_io_error(chunk)),
+        })?
+    }
+
+    /// Reads the value's stored bytes at offset, checking the chunks they're in first if the
+    /// Handle verifies reads. Parts of values stored in blocks are read one at a time.
+    fn read_stored_exact_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
         let value = self.value.as_ref();
+        if !self.verifies() {
+            let (file_clone, file_offset) = self.stored_at(offset)?;
+            return file_clone
+                .lock()
+                .unwrap()
+                .file
+                .read_exact_at(file_offset, buf);
+        }
+        // Parts are whole chunks, so the chunks are in the same block.
+        let chunks_start = offset / CHECKSUM_CHUNK_LEN * CHECKSUM_CHUNK_LEN;
+        let chunks_end = min(
+            ceil_multiple(offset + buf.len() as u64, CHECKSUM_CHUNK_LEN),
+            value.length(),
+        );
+        let (file_clone, file_offset) = self.stored_at(chunks_start)?;
+        let mut chunks = vec![0; to_usize_io(chunks_end - chunks_start)?];
+        file_clone
+            .lock()
+            .unwrap()
+            .file
+            .read_exact_at(file_offset, &mut chunks)?;
+        self.checksums()
+            .unwrap()
+            .verify(chunks_start, &chunks)
+            .map_err(|chunk| self.corrupt_io_error(chunk))?;
+        let start = (offset - chunks_start) as usize;
+        buf.copy_from_slice(&chunks[start..start + buf.len()]);
+        Ok(())
+    }
+
+    /// The cipher and nonce for decrypting the value, if it's encrypted.
+    fn decryption(&self) -> io::Result<Option<(&ValueCipher, &ValueNonce)>> {
+        let Some(nonce) = &self.value.as_ref().nonce else {
+            return Ok(None);
+        };
+        match &self.cipher {
+            Some(cipher) => Ok(Some((cipher, nonce))),
+            None => Err(io::Error::other(
+                "value is encrypted and the Handle has no encryption key",
+            )),
+        }
+    }
+
+    /// Returns the decrypted and decompressed value, or None if it's stored as is.
+    fn decoded(&self) -> io::Result<Option<&[u8]>> {
+        let value = self.value.as_ref();
+        if value.compression().is_none() && value.nonce.is_none() {
+            return Ok(None);
+        }
+        if self.decoded.get().is_none() {
+            let decryption = self.decryption()?;
+            let decoded = self.verified_raw_view(|bytes| {
+                let stored = encryption::decrypt_stored(decryption, bytes)?;
+                match value.compression() {
+                    Some(compression) => compression.decompress(&stored),
+                    None => Ok(stored.into_owned()),
+                }
+            })??;
+            // Another thread may have got here first, the result is the same.
+            let _ = self.decoded.set(decoded);
+        }
+        Ok(Some(self.decoded.get().unwrap()))
+    }
+
+    /// Calls f with the value's bytes as stored on disk, which are compressed or encrypted if the
+    /// value is.
+    pub(crate) fn raw_view<R>(&self, f: impl FnOnce(&[u8]) -> R) -> io::Result<R> {
+        let value = self.value.as_ref();
+        if let Some(blocks) = &self.blocks {
+            if !blocks.missing().is_empty() {
+                return Err(self.partially_evicted_io_error());
+            }
+            let parts = blocks.parts();
+            for part in parts {
+                self.touch(part.block.as_ref().unwrap());
+            }
+            // Parts that share blocks elsewhere are copied together once.
+            if !blocks.in_place(&value.location.into_non_zero().unwrap()) {
+                if self.decoded.get().is_none() {
+                    let mut assembled = vec![0; to_usize_io(value.length())?];
+                    for part in parts {
+                        let block = part.block.as_ref().unwrap();
+                        blocks.file(block).lock().unwrap().file.read_exact_at(
+                            block.file_offset,
+                            &mut assembled[part.range.start as usize..part.range.end as usize],
+                        )?;
+                    }
+                    // Another thread may have got here first, the result is the same.
+                    let _ = self.decoded.set(assembled);
+                }
+                return Ok(f(self.decoded.get().unwrap()));
+            }
+        }
         match value.location {
             Nonzero(NonzeroValueLocation {
                 file_offset,
@@ -651,6 +1292,14 @@ where
     }
 
     pub fn read(&self, mut buf: &mut [u8]) -> Result<usize> {
+        let value = self.value.as_ref();
+        if value.compression().is_some()
+            || value.nonce.is_some()
+            || self.verifies()
+            || self.blocks.is_some()
+        {
+            return Ok(ReadAt::read_at(self, 0, buf)?);
+        }
         match self.value.as_ref().location {
             ValueLocation::ZeroLength => Ok(0),
             Nonzero(NonzeroValueLocation {
@@ -750,6 +1399,7 @@ fn random_file_name_in_dir(dir: &Path, prefix: &str) -> PathBuf {
 const FILE_NAME_RAND_LENGTH: usize = 8;
 const VALUES_FILE_NAME_PREFIX: &str = "values-";
 const SNAPSHOT_DIR_NAME_PREFIX: &str = "snapshot-";
+const CLONE_TEST_FILE_NAME_PREFIX: &str = ".clone_test-";
 
 fn random_file_name(prefix: &str) -> OsString {
     let mut begin = prefix.as_bytes().to_vec();
@@ -793,8 +1443,19 @@ struct PunchValueOptions<'a> {
     constraints: PunchValueConstraints,
 }
 
+/// What punch_value did with a value's location.
+#[derive(Debug, PartialEq)]
+enum PunchOutcome {
+    /// A reader has the value locked, so the punch should be retried later.
+    Locked,
+    Punched,
+    /// The values file was truncated to the offset, or removed if it's 0. Everything after the
+    /// offset is gone, including whatever else was queued to be punched there.
+    Truncated(u64),
+}
+
 // Can't do this as &mut self for dumb Rust reasons.
-fn punch_value(opts: PunchValueOptions) -> Result<bool> {
+fn punch_value(opts: PunchValueOptions) -> Result<PunchOutcome> {
     let PunchValueOptions {
         dir,
         file_id,
@@ -820,20 +1481,28 @@ fn punch_value(opts: PunchValueOptions) -> Result<bool> {
     // Punching values probably requires write permission.
     let mut file = match OpenOptions::new().write(true).open(&file_path) {
         // The file could have already been deleted by a previous punch.
-        Err(err) if err.kind() == ErrorKind::NotFound && allow_remove => return Ok(true),
+        Err(err) if err.kind() == ErrorKind::NotFound && allow_remove => {
+            return Ok(PunchOutcome::Punched)
+        }
         Err(err) => return Err(err).context("opening value file"),
         Ok(ok) => ok,
     };
     // Find out how far back we can punch and start there, correcting for block boundaries as we go.
-    if offset % block_size != 0 || greedy_start {
+    if greedy_start {
         let last_end_offset = tx.query_last_end_offset(file_id, offset as u64)?;
         // Round up the end of the last value.
         let new_offset = ceil_multiple(last_end_offset, block_size as u64) as i64;
         // Because these are u64 we can't deal with overflow into negatives.
         length += offset - new_offset;
         offset = new_offset;
+    } else {
+        // Only punch whole blocks in the range, since what's before it may be in use.
+        let new_offset = ceil_multiple(offset, block_size);
+        length += offset - new_offset;
+        offset = new_offset;
     }
     assert_eq!(offset % block_size, 0);
+    let value_end = offset + length;
     if greedy_end {
         let next_offset = tx.next_value_offset(file_id, (offset + length).try_into().unwrap())?;
         let end_offset = match next_offset {
@@ -844,15 +1513,19 @@ fn punch_value(opts: PunchValueOptions) -> Result<bool> {
                     .context("locking value file")?;
                 // Get the file length after we have tried locking the file.
                 let file_end = file.seek(End(0))? as i64;
-                if locked_file {
+                if locked_file && file_end > value_end {
+                    // A writer may have committed values after the value since the transaction
+                    // began, and released the file. Only the value is known to be unused.
+                    floored_multiple(value_end, block_size)
+                } else if locked_file {
                     // I think it's okay to remove and truncate files if cloning doesn't use locks,
                     // because there are no values in this file to clone.
                     if offset == 0 && allow_remove {
                         remove_file(file_path).context("removing value file")?;
-                        return Ok(true);
+                        return Ok(PunchOutcome::Truncated(0));
                     } else if allow_truncate {
                         file.set_len(offset as u64)?;
-                        return Ok(true);
+                        return Ok(PunchOutcome::Truncated(offset as u64));
                     }
                     file_end
                 } else if cloning_lock_aware {
@@ -876,14 +1549,14 @@ fn punch_value(opts: PunchValueOptions) -> Result<bool> {
     // full block.
     assert!(length >= -block_size);
     if length <= 0 {
-        return Ok(true);
+        return Ok(PunchOutcome::Punched);
     }
     assert_eq!(offset % block_size, 0);
     if !file.lock_segment(LockExclusiveNonblock, Some(length as u64), offset as u64)? {
         // TODO: If we can't delete immediately, we should schedule to try again later. Maybe
         // spinning up a thread, or putting in a slow queue.
         warn!(%file_id, %offset, %length, "can't punch, file segment locked");
-        return Ok(false);
+        return Ok(PunchOutcome::Locked);
     }
     debug!(?file, %offset, %length, "punching");
     punchfile(
@@ -899,7 +1572,7 @@ fn punch_value(opts: PunchValueOptions) -> Result<bool> {
             warn!("checking hole: {}", err);
         }
     }
-    Ok(true)
+    Ok(PunchOutcome::Punched)
 }
 
 /// Checks that there's no data allocated in the region provided.
@@ -1,15 +1,20 @@
 use std::cmp::max;
 use std::ffi::OsString;
 use std::fs::{File, OpenOptions};
+use std::io::{stdin, stdout, BufReader, BufWriter};
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 use anyhow::{anyhow, bail, Context};
 use itertools::Itertools;
-use log::info;
+use log::{info, warn};
 use possum::sys::seekhole::{file_regions, Region, RegionType};
 use possum::sys::{punchfile, FileLocking, SparseFile};
 use possum::*;
 
+/// How long to wait for deleted values to be punched before exiting.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(10);
+
 #[derive(clap::Subcommand)]
 enum Commands {
     PunchHole {
@@ -19,6 +24,18 @@ enum Commands {
     },
     Database {
         dir: PathBuf,
+        /// How much of each commit survives a power loss: none, manifest or full.
+        #[arg(long, default_value_t)]
+        durability: Durability,
+        /// Reset the directory if its manifest is too old to migrate. This deletes every value.
+        #[arg(long)]
+        allow_destructive_reset: bool,
+        /// A file containing the 32 byte key to encrypt and decrypt values with.
+        #[arg(long)]
+        encryption_key_file: Option<PathBuf>,
+        /// Open the directory without changing it. Commands that write fail.
+        #[arg(long)]
+        read_only: bool,
         #[command(subcommand)]
         command: DatabaseCommands,
     },
@@ -40,6 +57,9 @@ enum DatabaseCommands {
     Info {},
     WriteFile {
         file: OsString,
+        /// Store the value compressed with this codec.
+        #[arg(long)]
+        compress: Option<Codec>,
     },
     ListKeys {
         #[arg(default_value = "")]
@@ -56,6 +76,106 @@ enum DatabaseCommands {
     PunchMissingHoles {
         file_id: Option<PathBuf>,
     },
+    /// Checks the directory for damage. Exits with an error if there are issues that weren't
+    /// repaired.
+    Fsck {
+        #[arg(long)]
+        repair: bool,
+    },
+    /// Writes keys with the prefix and their values to an archive file, or stdout.
+    Export {
+        #[arg(long, default_value = "")]
+        prefix: String,
+        file: Option<PathBuf>,
+    },
+    /// Writes the values in an archive file, or stdin, replacing existing keys.
+    Import {
+        file: Option<PathBuf>,
+    },
+    /// Makes a consistent copy of the directory, cloning values files where possible.
+    Backup {
+        dest_dir: PathBuf,
+    },
+    /// Makes the keys in another directory match this one, transferring only what's missing or
+    /// different.
+    Sync {
+        dst_dir: PathBuf,
+        #[arg(long, default_value = "")]
+        prefix: String,
+        /// Delete keys in the destination that aren't in this directory.
+        #[arg(long)]
+        delete: bool,
+    },
+    /// Moves values out of values files that are mostly unreferenced, and removes the old files.
+    Compact {
+        /// Compact values files with less than this fraction of their allocation in use.
+        #[arg(long, default_value_t = 0.5)]
+        max_live_ratio: f64,
+    },
+    /// Prints statistics. Most cover the operations of this command only, but the punch queue is
+    /// the directory's.
+    Stats {
+        /// Print in the Prometheus text exposition format.
+        #[arg(long)]
+        prometheus: bool,
+    },
+    /// Edits the limits stored in the manifest. Sizes can be "none" to remove the limit. Prints
+    /// the resulting limits.
+    SetLimits {
+        #[arg(long)]
+        max_value_length_sum: Option<LimitArg>,
+        #[arg(long)]
+        disable_hole_punching: Option<bool>,
+        #[arg(long)]
+        eviction_policy: Option<EvictionPolicy>,
+        #[arg(long)]
+        high_watermark: Option<LimitArg>,
+        #[arg(long)]
+        low_watermark: Option<LimitArg>,
+        #[arg(long)]
+        usage_accounting: Option<UsageAccounting>,
+        #[arg(long)]
+        min_free_bytes: Option<LimitArg>,
+        /// A percentage of the filesystem size, or "none".
+        #[arg(long)]
+        min_free_percent: Option<PercentArg>,
+        #[arg(long)]
+        partial_eviction: Option<bool>,
+    },
+}
+
+/// A percentage given on the command line.
+#[derive(Clone)]
+struct PercentArg(Option<u8>);
+
+impl std::str::FromStr for PercentArg {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "none" {
+            return Ok(Self(None));
+        }
+        let percent = s.parse()?;
+        if percent > 100 {
+            bail!("percentage over 100");
+        }
+        Ok(Self(Some(percent)))
+    }
+}
+
+/// A size limit given on the command line.
+#[derive(Clone)]
+struct LimitArg(Option<u64>);
+
+impl std::str::FromStr for LimitArg {
+    type Err = std::num::ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "none" {
+            return Ok(Self(None));
+        }
+        Ok(Self(Some(s.parse()?)))
+    }
 }
 
 #[derive(clap::Parser)]
@@ -78,21 +198,55 @@ fn main() -> anyhow::Result<()> {
             punchfile(&file, offset, length)?;
             Ok(())
         }
-        Database { dir, command } => {
+        Database {
+            dir,
+            durability,
+            allow_destructive_reset,
+            encryption_key_file,
+            read_only,
+            command,
+        } => {
             info!("sqlite version: {}", rusqlite::version());
-            let handle = Handle::new(dir)?;
+            let encryption_key = match encryption_key_file {
+                None => None,
+                Some(path) => {
+                    let bytes = std::fs::read(&path)
+                        .with_context(|| format!("reading {}", path.display()))?;
+                    let bytes: [u8; 32] = bytes
+                        .try_into()
+                        .map_err(|_| anyhow!("encryption key must be 32 bytes"))?;
+                    Some(EncryptionKey::new(bytes))
+                }
+            };
+            let mut handle = Handle::new_with_options(
+                dir,
+                HandleOptions {
+                    allow_destructive_reset,
+                    encryption_key,
+                    read_only,
+                },
+            )?;
+            handle.set_durability(durability)?;
             use DatabaseCommands::*;
-            match command {
+            let result: anyhow::Result<()> = match command {
                 Info {} => {
                     println!("{:?}", handle.dir());
+                    println!("{:?}", handle.disk_usage()?);
                     Ok(())
                 }
-                WriteFile { file } => {
+                WriteFile { file, compress } => {
                     let key = Path::new(&file)
                         .file_name()
                         .ok_or_else(|| anyhow!("can't extract file name"))?;
                     let file = File::open(&file).with_context(|| format!("opening {:?}", key))?;
-                    handle.single_write_from(key.to_os_string().into_encoded_bytes(), file)?;
+                    let mut writer = handle.new_writer()?;
+                    let mut value = writer.new_value().begin()?;
+                    if let Some(codec) = compress {
+                        value.compress(codec)?;
+                    }
+                    value.copy_from(file)?;
+                    writer.stage_write(key.to_os_string().into_encoded_bytes(), value)?;
+                    writer.commit()?;
                     Ok(())
                 }
                 ListKeys { prefix } => {
@@ -108,9 +262,8 @@ fn main() -> anyhow::Result<()> {
                     };
                     let mut r = value.new_reader();
                     let n = std::io::copy(&mut r, &mut std::io::stdout())?;
-                    // dbg!(n, value.length());
-                    if n != value.length() {
-                        bail!("read {} bytes, expected {}", n, value.length());
+                    if n != value.uncompressed_length() {
+                        bail!("read {} bytes, expected {}", n, value.uncompressed_length());
                     }
                     Ok(())
                 }
@@ -192,7 +345,158 @@ fn main() -> anyhow::Result<()> {
                     }
                     Ok(())
                 }
+                Fsck { repair } => {
+                    let report = handle.check(CheckOptions { repair })?;
+                    for Issue { kind, repaired } in &report.issues {
+                        if *repaired {
+                            println!("{:?} (repaired)", kind);
+                        } else {
+                            println!("{:?}", kind);
+                        }
+                    }
+                    for file_id in &report.files_in_use {
+                        println!("{}: in use, skipped", file_id);
+                    }
+                    if !report.is_clean() {
+                        bail!("{} issues found", report.issues.len());
+                    }
+                    Ok(())
+                }
+                Export { prefix, file } => {
+                    let count = match file {
+                        Some(path) => handle.export(
+                            prefix.as_bytes(),
+                            BufWriter::new(
+                                File::create(&path)
+                                    .with_context(|| format!("creating {}", path.display()))?,
+                            ),
+                        )?,
+                        None => {
+                            handle.export(prefix.as_bytes(), BufWriter::new(stdout().lock()))?
+                        }
+                    };
+                    eprintln!("exported {} values", count);
+                    Ok(())
+                }
+                Import { file } => {
+                    let count = match file {
+                        Some(path) => handle.import(BufReader::new(
+                            File::open(&path)
+                                .with_context(|| format!("opening {}", path.display()))?,
+                        ))?,
+                        None => handle.import(stdin().lock())?,
+                    };
+                    println!("imported {} values", count);
+                    Ok(())
+                }
+                Backup { dest_dir } => {
+                    let report = handle.backup_to(dest_dir)?;
+                    println!(
+                        "cloned {} files, copied {} files ({} bytes)",
+                        report.files_cloned, report.files_copied, report.bytes_copied
+                    );
+                    Ok(())
+                }
+                Sync {
+                    dst_dir,
+                    prefix,
+                    delete,
+                } => {
+                    let dst = Handle::new(dst_dir)?;
+                    let report = handle.sync_to(
+                        &dst,
+                        &SyncOptions {
+                            prefix: prefix.into_bytes(),
+                            delete,
+                        },
+                    )?;
+                    println!("{:?}", report);
+                    // Punch the values the sync replaced or deleted in the destination too.
+                    let report = dst.close(CLOSE_TIMEOUT)?;
+                    if !report.is_complete() {
+                        warn!("closing destination incomplete: {:?}", report);
+                    }
+                    Ok(())
+                }
+                Compact { max_live_ratio } => {
+                    let report = handle.compact(CompactOptions { max_live_ratio })?;
+                    for file_id in &report.files_in_use {
+                        println!("{}: in use, skipped", file_id);
+                    }
+                    println!(
+                        "compacted {} files, removed {}, moved {} values ({} bytes)",
+                        report.files_compacted.len(),
+                        report.files_removed.len(),
+                        report.values_moved,
+                        report.bytes_moved
+                    );
+                    Ok(())
+                }
+                Stats { prometheus } => {
+                    let mut stats = handle.stats();
+                    // The value puncher may not have made a pass yet.
+                    let backlog = handle.punch_backlog()?;
+                    stats.punch_queue_values = backlog.values;
+                    stats.punch_queue_bytes = backlog.bytes;
+                    if prometheus {
+                        stats.write_prometheus(&mut stdout())?;
+                    } else {
+                        print!("{stats}");
+                        println!("punches retrying: {}", backlog.retrying);
+                    }
+                    Ok(())
+                }
+                SetLimits {
+                    max_value_length_sum,
+                    disable_hole_punching,
+                    eviction_policy,
+                    high_watermark,
+                    low_watermark,
+                    usage_accounting,
+                    min_free_bytes,
+                    min_free_percent,
+                    partial_eviction,
+                } => {
+                    let mut limits = handle.persistent_limits()?;
+                    if let Some(LimitArg(max)) = max_value_length_sum {
+                        limits.max_value_length_sum = max;
+                    }
+                    if let Some(disable) = disable_hole_punching {
+                        limits.disable_hole_punching = disable;
+                    }
+                    if let Some(policy) = eviction_policy {
+                        limits.eviction_policy = policy;
+                    }
+                    if let Some(LimitArg(high)) = high_watermark {
+                        limits.high_watermark = high;
+                    }
+                    if let Some(LimitArg(low)) = low_watermark {
+                        limits.low_watermark = low;
+                    }
+                    if let Some(accounting) = usage_accounting {
+                        limits.usage_accounting = accounting;
+                    }
+                    if let Some(LimitArg(bytes)) = min_free_bytes {
+                        limits.min_free_bytes = bytes;
+                    }
+                    if let Some(PercentArg(percent)) = min_free_percent {
+                        limits.min_free_percent = percent;
+                    }
+                    if let Some(partial) = partial_eviction {
+                        limits.partial_eviction = partial;
+                    }
+                    handle.set_persistent_limits(limits.clone())?;
+                    println!("{:#?}", limits);
+                    Ok(())
+                }
+            };
+            result?;
+            // Punch anything the command deleted before exiting.
+            let report = handle.close(CLOSE_TIMEOUT)?;
+            if !report.is_complete() {
+                warn!("closing database incomplete: {:?}", report);
             }
+            Ok(())
         }
         ShowHoles { files: paths } => {
             for path in paths {
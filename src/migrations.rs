@@ -0,0 +1,284 @@
+//! Upgrades manifests created by older versions of the library, without losing their values.
+
+use super::*;
+
+/// The oldest manifest user version that can be migrated. Older manifests can only be reset.
+pub(crate) const OLDEST_MIGRATABLE_VERSION: ManifestUserVersion = 3;
+
+/// Upgrades a manifest from the version before it to the version after it. The step that
+/// upgrades to version N is at index N - OLDEST_MIGRATABLE_VERSION - 1.
+type Migration = fn(&rusqlite::Transaction<'_>) -> rusqlite::Result<()>;
+
+const MIGRATIONS: &[Migration] = &[
+    to_version_4,
+    to_version_5,
+    to_version_6,
+    to_version_7,
+    to_version_8,
+    to_version_9,
+    to_version_10,
+    to_version_11,
+    to_version_12,
+    to_version_13,
+    to_version_14,
+];
+
+/// The version the manifest schema in manifest.sql is at.
+pub(crate) const fn latest_version() -> ManifestUserVersion {
+    OLDEST_MIGRATABLE_VERSION + MIGRATIONS.len() as ManifestUserVersion
+}
+
+/// Applies the migrations from version to the latest version. Each step commits separately, so an
+/// interrupted upgrade resumes where it stopped.
+pub(crate) fn migrate(conn: &mut Connection, mut version: ManifestUserVersion) -> Result<()> {
+    assert!(version >= OLDEST_MIGRATABLE_VERSION);
+    while version < latest_version() {
+        let migration = MIGRATIONS[(version - OLDEST_MIGRATABLE_VERSION) as usize];
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Exclusive)?;
+        migration(&tx).with_context(|| format!("migrating to version {}", version + 1))?;
+        version += 1;
+        tx.pragma_update(None, "user_version", version)?;
+        tx.commit()?;
+        info!(version, "migrated manifest");
+    }
+    Ok(())
+}
+
+fn to_version_4(tx: &rusqlite::Transaction<'_>) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "alter table keys add column expires integer;
+        create index if not exists expires_index on keys (expires) where expires is not null;",
+    )
+}
+
+fn to_version_5(tx: &rusqlite::Transaction<'_>) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "create table fetches (
+            key blob primary key,
+            fetch_id integer not null,
+            started integer not null default (cast(unixepoch('subsec')*1e3 as integer))
+        ) strict, without rowid;",
+    )
+}
+
+fn to_version_6(tx: &rusqlite::Transaction<'_>) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "alter table keys add column use_count integer not null default 0;
+        create index if not exists use_count_index on keys (use_count, last_used, key_id);",
+    )
+}
+
+fn to_version_7(tx: &rusqlite::Transaction<'_>) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "create table settings (
+            name text primary key,
+            value any
+        ) strict, without rowid;",
+    )
+}
+
+fn to_version_8(tx: &rusqlite::Transaction<'_>) -> rusqlite::Result<()> {
+    // The sum is computed by usage::set_block_size when Handle::new stores the block size, since it
+    // isn't stored yet.
+    tx.execute_batch(
+        "insert or ignore into sums values ('block_rounded_value_length', 0);
+        create trigger if not exists block_rounded_value_length_on_delete delete on keys begin
+            update sums set value=value-coalesce((
+                select (old.value_length+s.value-1)/s.value*s.value from settings s where s.name='block_size'
+            ), old.value_length) where key='block_rounded_value_length';
+        end;
+        create trigger if not exists block_rounded_value_length_on_insert insert on keys begin
+            update sums set value=value+coalesce((
+                select (new.value_length+s.value-1)/s.value*s.value from settings s where s.name='block_size'
+            ), new.value_length) where key='block_rounded_value_length';
+        end;",
+    )
+}
+
+fn to_version_9(tx: &rusqlite::Transaction<'_>) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "alter table keys add column codec text;
+        alter table keys add column uncompressed_length integer;",
+    )
+}
+
+fn to_version_10(tx: &rusqlite::Transaction<'_>) -> rusqlite::Result<()> {
+    tx.execute_batch("alter table keys add column nonce blob;")
+}
+
+fn to_version_11(tx: &rusqlite::Transaction<'_>) -> rusqlite::Result<()> {
+    tx.execute_batch("alter table keys add column checksums blob;")
+}
+
+fn to_version_12(tx: &rusqlite::Transaction<'_>) -> rusqlite::Result<()> {
+    // Deduplicated values are shared by several keys, so the unique (file_id, file_offset)
+    // constraint has to go. Constraints can't be dropped, so the table is rebuilt. Dropping keys
+    // drops its indexes and triggers without firing the triggers, so the sums are unchanged. The
+    // recreated triggers count values shared by several keys once.
+    tx.execute_batch(
+        "create table new_keys (
+            key_id integer primary key,
+            file_id integer,
+            file_offset integer,
+            value_length integer not null,
+            last_used integer not null default (cast(unixepoch('subsec')*1e3 as integer)),
+            expires integer,
+            use_count integer not null default 0,
+            codec text,
+            uncompressed_length integer,
+            nonce blob,
+            checksums blob,
+            content_hash integer,
+            key blob unique not null,
+            check ( iif (
+                value_length=0,
+                file_id is null and file_offset is null,
+                file_id is not null and file_offset is not null ) )
+        ) strict;
+        insert into new_keys (
+            key_id, file_id, file_offset, value_length, last_used, expires, use_count, codec,
+            uncompressed_length, nonce, checksums, key
+        )
+        select
+            key_id, file_id, file_offset, value_length, last_used, expires, use_count, codec,
+            uncompressed_length, nonce, checksums, key
+        from keys;
+        drop table keys;
+        alter table new_keys rename to keys;
+        create index last_used_index on keys (last_used, key_id);
+        create index use_count_index on keys (use_count, last_used, key_id);
+        create index expires_index on keys (expires) where expires is not null;
+        create index file_id_then_offset on keys (file_id, file_offset);
+        create index file_id_then_end_offset on keys (file_id, file_offset+value_length);
+        create index content_hash_index on keys (content_hash) where content_hash is not null;
+        create trigger value_length_sum_on_delete delete on keys begin
+            update sums set value=value-old.value_length where key='value_length' and not exists (
+                select 1 from keys where file_id=old.file_id and file_offset=old.file_offset and key_id!=old.key_id
+            );
+        end;
+        create trigger value_length_sum_on_insert insert on keys begin
+            update sums set value=value+new.value_length where key='value_length' and not exists (
+                select 1 from keys where file_id=new.file_id and file_offset=new.file_offset and key_id!=new.key_id
+            );
+        end;
+        create trigger block_rounded_value_length_on_delete delete on keys begin
+            update sums set value=value-coalesce((
+                select (old.value_length+s.value-1)/s.value*s.value from settings s where s.name='block_size'
+            ), old.value_length) where key='block_rounded_value_length' and not exists (
+                select 1 from keys where file_id=old.file_id and file_offset=old.file_offset and key_id!=old.key_id
+            );
+        end;
+        create trigger block_rounded_value_length_on_insert insert on keys begin
+            update sums set value=value+coalesce((
+                select (new.value_length+s.value-1)/s.value*s.value from settings s where s.name='block_size'
+            ), new.value_length) where key='block_rounded_value_length' and not exists (
+                select 1 from keys where file_id=new.file_id and file_offset=new.file_offset and key_id!=new.key_id
+            );
+        end;",
+    )
+}
+
+fn to_version_13(tx: &rusqlite::Transaction<'_>) -> rusqlite::Result<()> {
+    // Existing values stay whole. Only values written with partial eviction are stored in blocks.
+    tx.execute_batch(
+        "alter table keys add column in_blocks integer not null default 0;
+        create table blocks (
+            block_id integer primary key,
+            file_id integer not null,
+            file_offset integer not null,
+            block_length integer not null,
+            block_hash integer,
+            last_used integer not null
+        ) strict;
+        create index blocks_by_location on blocks (file_id, file_offset);
+        create index blocks_by_end_offset on blocks (file_id, file_offset+block_length);
+        create index blocks_by_last_used on blocks (last_used);
+        create index blocks_by_hash on blocks (block_hash) where block_hash is not null;
+        create table value_parts (
+            key_id integer not null,
+            part_offset integer not null,
+            part_length integer not null,
+            block_id integer,
+            primary key (key_id, part_offset)
+        ) strict, without rowid;
+        create index value_parts_by_block on value_parts (block_id) where block_id is not null;
+        create table released_blocks (
+            file_id integer not null,
+            file_offset integer not null,
+            block_length integer not null,
+            primary key (file_id, file_offset)
+        ) strict, without rowid;
+        drop trigger value_length_sum_on_delete;
+        drop trigger value_length_sum_on_insert;
+        drop trigger block_rounded_value_length_on_delete;
+        drop trigger block_rounded_value_length_on_insert;
+        create trigger value_length_sum_on_delete delete on keys begin
+            update sums set value=value-old.value_length
+            where key='value_length' and not old.in_blocks and not exists (
+                select 1 from keys where file_id=old.file_id and file_offset=old.file_offset and key_id!=old.key_id
+            );
+        end;
+        create trigger value_length_sum_on_insert insert on keys begin
+            update sums set value=value+new.value_length
+            where key='value_length' and not new.in_blocks and not exists (
+                select 1 from keys where file_id=new.file_id and file_offset=new.file_offset and key_id!=new.key_id
+            );
+        end;
+        create trigger block_rounded_value_length_on_delete delete on keys begin
+            update sums set value=value-coalesce((
+                select (old.value_length+s.value-1)/s.value*s.value from settings s where s.name='block_size'
+            ), old.value_length)
+            where key='block_rounded_value_length' and not old.in_blocks and not exists (
+                select 1 from keys where file_id=old.file_id and file_offset=old.file_offset and key_id!=old.key_id
+            );
+        end;
+        create trigger block_rounded_value_length_on_insert insert on keys begin
+            update sums set value=value+coalesce((
+                select (new.value_length+s.value-1)/s.value*s.value from settings s where s.name='block_size'
+            ), new.value_length)
+            where key='block_rounded_value_length' and not new.in_blocks and not exists (
+                select 1 from keys where file_id=new.file_id and file_offset=new.file_offset and key_id!=new.key_id
+            );
+        end;
+        create trigger value_parts_on_key_delete delete on keys begin
+            delete from value_parts where key_id=old.key_id;
+        end;
+        create trigger blocks_on_value_part_delete after delete on value_parts begin
+            delete from blocks where block_id=old.block_id and not exists (
+                select 1 from value_parts where block_id=old.block_id
+            );
+        end;
+        create trigger blocks_on_value_part_update after update of block_id on value_parts begin
+            delete from blocks where block_id=old.block_id and not exists (
+                select 1 from value_parts where block_id=old.block_id
+            );
+        end;
+        create trigger block_sums_on_insert insert on blocks begin
+            update sums set value=value+new.block_length where key='value_length';
+            update sums set value=value+coalesce((
+                select (new.block_length+s.value-1)/s.value*s.value from settings s where s.name='block_size'
+            ), new.block_length) where key='block_rounded_value_length';
+        end;
+        create trigger block_sums_on_delete delete on blocks begin
+            update sums set value=value-old.block_length where key='value_length';
+            update sums set value=value-coalesce((
+                select (old.block_length+s.value-1)/s.value*s.value from settings s where s.name='block_size'
+            ), old.block_length) where key='block_rounded_value_length';
+            insert or ignore into released_blocks (file_id, file_offset, block_length)
+            values (old.file_id, old.file_offset, old.block_length);
+        end;",
+    )
+}
+
+fn to_version_14(tx: &rusqlite::Transaction<'_>) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "create table pending_punches (
+            file_id integer not null,
+            file_offset integer not null,
+            length integer not null,
+            attempts integer not null default 0,
+            next_attempt integer not null default (cast(unixepoch('subsec')*1e3 as integer)),
+            primary key (file_id, file_offset)
+        ) strict, without rowid;",
+    )
+}
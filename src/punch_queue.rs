@@ -0,0 +1,130 @@
+//! The queue of values waiting to be hole punched. Locations are added in the transaction that
+//! deletes their values, so they survive the process exiting, and any Handle's value puncher can
+//! punch them. Punches that fail, usually because a reader has the value locked or is still taking
+//! its snapshot, are retried with backoff.
+
+use super::*;
+
+/// The wait before retrying a failed punch. It doubles with each attempt up to
+/// MAX_RETRY_INTERVAL.
+const MIN_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+const MAX_RETRY_INTERVAL: Duration = Duration::from_secs(300);
+
+/// The values waiting to be punched in a directory, returned by Handle::punch_backlog.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PunchBacklog {
+    pub values: u64,
+    pub bytes: u64,
+    /// Values that have failed to be punched at least once.
+    pub retrying: u64,
+}
+
+/// Adds locations whose values were deleted in the transaction.
+pub(crate) fn enqueue(
+    tx: &rusqlite::Transaction<'_>,
+    locations: &[NonzeroValueLocation],
+) -> rusqlite::Result<()> {
+    if locations.is_empty() {
+        return Ok(());
+    }
+    // A location can be queued again if the values file was truncated and written to since.
+    let mut stmt = tx.prepare_cached(
+        "insert or replace into pending_punches (file_id, file_offset, length) values (?, ?, ?)",
+    )?;
+    for location in locations {
+        stmt.execute(params![
+            location.file_id,
+            location.file_offset,
+            location.length
+        ])?;
+    }
+    Ok(())
+}
+
+/// Returns the locations that are due to be punched, or all of them if ignore_backoff.
+pub(crate) fn due(
+    conn: &Connection,
+    ignore_backoff: bool,
+) -> rusqlite::Result<Vec<NonzeroValueLocation>> {
+    conn.prepare_cached(
+        "select file_id, file_offset, length from pending_punches \
+        where ? or next_attempt <= cast(unixepoch('subsec')*1e3 as integer) \
+        order by file_id, file_offset",
+    )?
+    .query_map([ignore_backoff], |row| {
+        Ok(NonzeroValueLocation {
+            file_id: row.get(0)?,
+            file_offset: row.get(1)?,
+            length: row.get(2)?,
+        })
+    })?
+    .collect()
+}
+
+/// Removes the punched locations from the queue, and puts off retrying the failed ones. Locations
+/// at or after the offset a values file was truncated to are removed too, whether or not they were
+/// due, since the file may be written to again.
+pub(crate) fn finish(
+    tx: &rusqlite::Transaction<'_>,
+    punched: &[NonzeroValueLocation],
+    failed: &[NonzeroValueLocation],
+    truncated: &[(FileId, u64)],
+) -> rusqlite::Result<()> {
+    let mut delete_truncated =
+        tx.prepare_cached("delete from pending_punches where file_id=? and file_offset>=?")?;
+    for (file_id, offset) in truncated {
+        delete_truncated.execute(params![file_id, offset])?;
+    }
+    let mut delete = tx.prepare_cached(
+        "delete from pending_punches where file_id=? and file_offset=? and length=?",
+    )?;
+    for location in punched {
+        delete.execute(params![
+            location.file_id,
+            location.file_offset,
+            location.length
+        ])?;
+    }
+    // The interval is capped before shifting so it can't overflow.
+    let mut retry = tx.prepare_cached(
+        "update pending_punches set \
+            attempts=attempts+1, \
+            next_attempt=cast(unixepoch('subsec')*1e3 as integer)+min(?1, ?2 << min(attempts, 20)) \
+        where file_id=?3 and file_offset=?4 and length=?5",
+    )?;
+    for location in failed {
+        retry.execute(params![
+            MAX_RETRY_INTERVAL.as_millis() as i64,
+            MIN_RETRY_INTERVAL.as_millis() as i64,
+            location.file_id,
+            location.file_offset,
+            location.length
+        ])?;
+    }
+    Ok(())
+}
+
+/// How long until the next queued location is due to be punched, if there are any.
+pub(crate) fn next_due(conn: &Connection) -> rusqlite::Result<Option<Duration>> {
+    let millis: Option<i64> = conn
+        .prepare_cached(
+            "select min(next_attempt)-cast(unixepoch('subsec')*1e3 as integer) \
+            from pending_punches",
+        )?
+        .query_row([], |row| row.get(0))?;
+    Ok(millis.map(|millis| Duration::from_millis(millis.max(0) as u64)))
+}
+
+pub(crate) fn backlog(conn: &Connection) -> rusqlite::Result<PunchBacklog> {
+    conn.prepare_cached(
+        "select count(*), coalesce(sum(length), 0), count(*) filter (where attempts > 0) \
+        from pending_punches",
+    )?
+    .query_row([], |row| {
+        Ok(PunchBacklog {
+            values: row.get(0)?,
+            bytes: row.get(1)?,
+            retrying: row.get(2)?,
+        })
+    })
+}
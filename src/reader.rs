@@ -1,4 +1,5 @@
 use super::*;
+use crate::blocks::BlockMap;
 use crate::ownedtx::OwnedTxTrait;
 
 // BTree possibly so we can merge extents in the future.
@@ -7,6 +8,12 @@ type Reads = HashMap<FileId, BTreeSet<ReadExtent>>;
 pub struct Reader<T> {
     pub(crate) owned_tx: T,
     pub(crate) reads: Reads,
+    // The parts of values stored in blocks, by key.
+    pub(crate) blocks: HashMap<i64, Vec<blocks::ValuePart>>,
+    // The checksums of values that have them, by key.
+    pub(crate) checksums: HashMap<i64, Checksums>,
+    // Held until the snapshot is taken. See clone_lock.
+    pub(crate) _clone_lock: CloneLock,
 }
 
 // TODO: This is annoying.
@@ -17,28 +24,75 @@ where
     H: AsRef<Handle>,
 {
     pub fn add(&mut self, key: &[u8]) -> rusqlite::Result<Option<Value>> {
-        let res = self.owned_tx.mut_transaction().touch_for_read(key);
+        let res = self.owned_tx.mut_transaction().read_value(key);
+        let handle = self.owned_tx.as_handle();
+        let stats = &handle.stats;
         match res {
-            Ok(value) => {
-                if let Nonzero(NonzeroValueLocation {
-                    file_offset,
-                    length,
-                    file_id,
-                }) = value.location
-                {
-                    let file = self.reads.entry(file_id);
-                    file.or_default().insert(ReadExtent {
-                        offset: file_offset,
-                        len: length,
-                    });
+            Ok((mut value, now)) => {
+                stats::Counters::add(&stats.read_hits, 1);
+                // The manifest is updated later, so reads don't need the write lock.
+                if !handle.read_only {
+                    if handle
+                        .touches
+                        .record(value.key_id, key, value.last_used, now)
+                    {
+                        handle.wake_touch_flush();
+                    }
+                    value.last_used = now;
                 }
+                self.add_value(&value)?;
                 Ok(Some(value))
             }
-            Err(QueryReturnedNoRows) => Ok(None),
+            Err(QueryReturnedNoRows) => {
+                stats::Counters::add(&stats.read_misses, 1);
+                Ok(None)
+            }
             Err(err) => Err(err),
         }
     }
 
+    /// Includes a value in the snapshot without counting it as used.
+    pub(crate) fn add_value(&mut self, value: &Value) -> rusqlite::Result<()> {
+        let Nonzero(NonzeroValueLocation {
+            file_offset,
+            length,
+            file_id,
+        }) = value.location
+        else {
+            return Ok(());
+        };
+        let tx = self.owned_tx.transaction().readonly_transaction();
+        if let Some(checksums) = checksum::key_checksums(tx, value.key_id)? {
+            self.checksums.insert(value.key_id, checksums);
+        }
+        if value.in_blocks() {
+            // The blocks may be in other files, and evicted parts aren't read.
+            let parts = blocks::value_parts(
+                self.owned_tx.transaction().readonly_transaction(),
+                value.key_id,
+            )?;
+            for part in &parts {
+                let Some(block) = &part.block else {
+                    continue;
+                };
+                self.reads
+                    .entry(block.file_id)
+                    .or_default()
+                    .insert(ReadExtent {
+                        offset: block.file_offset,
+                        len: part.range.end - part.range.start,
+                    });
+            }
+            self.blocks.insert(value.key_id, parts);
+            return Ok(());
+        }
+        self.reads.entry(file_id).or_default().insert(ReadExtent {
+            offset: file_offset,
+            len: length,
+        });
+        Ok(())
+    }
+
     /// Takes a snapshot and commits the read transaction.
     pub fn begin(self) -> Result<Snapshot> {
         log_time!(
@@ -46,10 +100,28 @@ where
             "cloning files";
             let file_clones = self.clone_files().context("cloning files")?
         );
+        let handle = self.owned_tx.as_handle();
+        let cipher = handle.cipher.clone();
+        let verify_reads = handle.verify_reads;
+        // Reads of blocks are recorded like reads of keys.
+        let touches = (!handle.read_only).then(|| Arc::clone(&handle.touches));
+        let blocks = self
+            .blocks
+            .into_iter()
+            .map(|(key_id, parts)| (key_id, Arc::new(BlockMap::new(parts, &file_clones))))
+            .collect();
+        let checksums = self.checksums;
         let commit = || self.owned_tx.end_tx(|tx| tx.commit());
         let post_work = log_time!("reader commit", commit());
         post_work.context("committing transaction")?.complete();
-        Ok(Snapshot { file_clones })
+        Ok(Snapshot {
+            file_clones,
+            cipher,
+            verify_reads,
+            blocks,
+            checksums,
+            touches,
+        })
     }
 
     fn clone_files(&self) -> Result<FileCloneCache> {
@@ -96,6 +168,7 @@ where
                 .unwrap();
             let file_clone_guard = ret.lock().unwrap();
             if file_clone_guard.len >= min_len {
+                stats::Counters::add(&self.owned_tx.as_handle().stats.clone_cache_hits, 1);
                 return Ok(ret.clone());
             }
         }
@@ -104,12 +177,14 @@ where
                 Err(err) if err.root_cause_is_unsupported_filesystem() => (),
                 Err(err) => return Err(err),
                 default @ Ok(_) => {
+                    stats::Counters::add(&self.owned_tx.as_handle().stats.file_clones, 1);
                     info!(%file_id, tempdir = %tempdir.as_ref().unwrap().path().display(), "cloned file");
                     return default;
                 }
             }
         }
         warn!(%file_id, ?read_extents, "falling back to segment locking to read");
+        stats::Counters::add(&self.owned_tx.as_handle().stats.segment_lock_fallbacks, 1);
         self.get_file_for_read_by_segment_locking(file_id, read_extents)
     }
 
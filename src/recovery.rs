@@ -0,0 +1,92 @@
+//! Reclaims data left at the end of values files by writers that never committed, such as
+//! processes that died while writing values.
+
+use walk::EntryType;
+
+use super::*;
+
+#[derive(Debug, Default)]
+pub struct TailRecoveryReport {
+    /// Values files that were truncated, and how many bytes were removed from each.
+    pub files_truncated: Vec<(FileId, u64)>,
+    pub bytes_reclaimed: u64,
+}
+
+/// How long after a Handle is opened its value puncher first recovers tails. Recovery holds the
+/// manifest write lock, so it waits for the Handle's first transactions.
+pub(crate) const TAIL_RECOVERY_DELAY: Duration = Duration::from_secs(5);
+/// How often a Handle's value puncher recovers tails after that.
+pub(crate) const TAIL_RECOVERY_INTERVAL: Duration = Duration::from_secs(600);
+/// How soon recovery is retried when it was skipped because the clone lock was busy.
+pub(crate) const TAIL_RECOVERY_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+impl Handle {
+    /// Truncates values files after their last referenced value, if no writer owns them. Each
+    /// Handle does this in the background TAIL_RECOVERY_DELAY after it's opened and every
+    /// TAIL_RECOVERY_INTERVAL after that, so this is only needed to recover immediately.
+    pub fn recover_tails(&self) -> Result<TailRecoveryReport> {
+        self.check_writable()?;
+        Ok(recover_tails(&self.conn.lock().unwrap(), &self.dir)?.unwrap_or_default())
+    }
+}
+
+/// Recovers the tails of all the values files in dir in one manifest transaction. Returns None if
+/// the clone lock is busy, and nothing was tried.
+pub(crate) fn recover_tails(conn: &Connection, dir: &Dir) -> Result<Option<TailRecoveryReport>> {
+    // Readers lock values before releasing the clone lock, so the exclusive locks are only taken
+    // with it held. The manifest write lock stops writers committing values into the tails.
+    let Some(_clone_lock) = CloneLock::try_exclusive(dir)? else {
+        return Ok(None);
+    };
+    let tx = Handle::retry_while_busy(|| {
+        rusqlite::Transaction::new_unchecked(conn, TransactionBehavior::Immediate)
+    })?;
+    let tail_offsets = tx
+        .prepare(
+            "select file_id, max(end_offset) from (\
+                select file_id, file_offset+value_length as end_offset from keys \
+                where file_id is not null \
+                union all select file_id, file_offset+block_length from blocks\
+            ) group by file_id",
+        )?
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<rusqlite::Result<HashMap<FileId, u64>>>()?;
+    let mut report = TailRecoveryReport::default();
+    for entry in walk_dir(dir)? {
+        if entry.entry_type != EntryType::ValuesFile {
+            continue;
+        }
+        let Some(file_id) = entry.file_id() else {
+            continue;
+        };
+        let tail_offset = tail_offsets.get(&file_id).copied().unwrap_or_default();
+        let reclaimed = recover_tail(file_id, &entry.path, tail_offset)
+            .with_context(|| format!("recovering tail of values file {}", file_id))?;
+        if reclaimed != 0 {
+            report.files_truncated.push((file_id, reclaimed));
+            report.bytes_reclaimed += reclaimed;
+        }
+    }
+    // Nothing was written, but the write lock is held until here.
+    drop(tx);
+    Ok(Some(report))
+}
+
+fn recover_tail(file_id: FileId, path: &Path, tail_offset: u64) -> Result<u64> {
+    let mut file = match OpenOptions::new().write(true).open(path) {
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(0),
+        result => result?,
+    };
+    // Exclusive files hold a lock from their last commit to the end of the file, so this fails if
+    // anyone could still be writing after the last value.
+    if !file.lock_segment(LockExclusiveNonblock, None, tail_offset)? {
+        return Ok(0);
+    }
+    let file_len = file.seek(End(0))?;
+    if file_len <= tail_offset {
+        return Ok(0);
+    }
+    warn!(%file_id, tail_offset, file_len, "truncating uncommitted values file tail");
+    file.set_len(tail_offset)?;
+    Ok(file_len - tail_offset)
+}
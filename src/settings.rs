@@ -0,0 +1,56 @@
+//! Settings stored in the manifest that apply to every Handle on a directory.
+
+use super::*;
+
+// Setting names. These are stored in the manifest, so don't change them.
+const MAX_VALUE_LENGTH_SUM: &str = "max_value_length_sum";
+const DISABLE_HOLE_PUNCHING: &str = "disable_hole_punching";
+const EVICTION_POLICY: &str = "eviction_policy";
+const HIGH_WATERMARK: &str = "high_watermark";
+const LOW_WATERMARK: &str = "low_watermark";
+const USAGE_ACCOUNTING: &str = "usage_accounting";
+const MIN_FREE_BYTES: &str = "min_free_bytes";
+const MIN_FREE_PERCENT: &str = "min_free_percent";
+const PARTIAL_EVICTION: &str = "partial_eviction";
+// Not a limit, see usage::set_block_size.
+const BLOCK_SIZE: &str = "block_size";
+
+/// Reads the limits stored in the manifest. Missing settings take their default.
+pub(crate) fn load_limits(conn: &Connection) -> rusqlite::Result<Limits> {
+    let mut limits = Limits::default();
+    let mut stmt = conn.prepare_cached("select name, value from settings")?;
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        let name: String = row.get(0)?;
+        match name.as_str() {
+            MAX_VALUE_LENGTH_SUM => limits.max_value_length_sum = row.get(1)?,
+            DISABLE_HOLE_PUNCHING => limits.disable_hole_punching = row.get(1)?,
+            EVICTION_POLICY => limits.eviction_policy = row.get(1)?,
+            HIGH_WATERMARK => limits.high_watermark = row.get(1)?,
+            LOW_WATERMARK => limits.low_watermark = row.get(1)?,
+            USAGE_ACCOUNTING => limits.usage_accounting = row.get(1)?,
+            MIN_FREE_BYTES => limits.min_free_bytes = row.get(1)?,
+            MIN_FREE_PERCENT => limits.min_free_percent = row.get(1)?,
+            PARTIAL_EVICTION => limits.partial_eviction = row.get(1)?,
+            BLOCK_SIZE => {}
+            // Probably from a newer version.
+            _ => warn!(name, "unknown setting"),
+        }
+    }
+    Ok(limits)
+}
+
+/// Replaces the limits stored in the manifest.
+pub(crate) fn store_limits(conn: &Connection, limits: &Limits) -> rusqlite::Result<()> {
+    let mut stmt = conn.prepare_cached("replace into settings (name, value) values (?, ?)")?;
+    stmt.execute(params![MAX_VALUE_LENGTH_SUM, limits.max_value_length_sum])?;
+    stmt.execute(params![DISABLE_HOLE_PUNCHING, limits.disable_hole_punching])?;
+    stmt.execute(params![EVICTION_POLICY, limits.eviction_policy])?;
+    stmt.execute(params![HIGH_WATERMARK, limits.high_watermark])?;
+    stmt.execute(params![LOW_WATERMARK, limits.low_watermark])?;
+    stmt.execute(params![USAGE_ACCOUNTING, limits.usage_accounting])?;
+    stmt.execute(params![MIN_FREE_BYTES, limits.min_free_bytes])?;
+    stmt.execute(params![MIN_FREE_PERCENT, limits.min_free_percent])?;
+    stmt.execute(params![PARTIAL_EVICTION, limits.partial_eviction])?;
+    Ok(())
+}
@@ -0,0 +1,261 @@
+//! Operational statistics for a Handle. Counters cover the Handle since it was opened, and aren't
+//! shared with other Handles on the same directory.
+
+use std::sync::atomic::Ordering::Relaxed;
+
+use super::*;
+use sync::atomic::AtomicU64;
+
+/// The counters updated as a Handle is used, shared with its background threads.
+#[derive(Debug, Default)]
+pub(crate) struct Counters {
+    pub(crate) read_hits: AtomicU64,
+    pub(crate) read_misses: AtomicU64,
+    pub(crate) bytes_written: AtomicU64,
+    pub(crate) values_committed: AtomicU64,
+    pub(crate) bytes_committed: AtomicU64,
+    pub(crate) values_file_syncs: AtomicU64,
+    pub(crate) evictions: AtomicU64,
+    pub(crate) bytes_evicted: AtomicU64,
+    pub(crate) punch_queue_values: AtomicU64,
+    pub(crate) punch_queue_bytes: AtomicU64,
+    pub(crate) punches: AtomicU64,
+    pub(crate) failed_punches: AtomicU64,
+    pub(crate) clone_cache_hits: AtomicU64,
+    pub(crate) file_clones: AtomicU64,
+    pub(crate) segment_lock_fallbacks: AtomicU64,
+    pub(crate) manifest_transactions: AtomicU64,
+    manifest_wait_nanos: AtomicU64,
+    manifest_max_wait_nanos: AtomicU64,
+}
+
+impl Counters {
+    pub(crate) fn add(counter: &AtomicU64, n: u64) {
+        counter.fetch_add(n, Relaxed);
+    }
+
+    pub(crate) fn set(counter: &AtomicU64, n: u64) {
+        counter.store(n, Relaxed);
+    }
+
+    /// Records the time spent waiting to start a manifest transaction.
+    pub(crate) fn record_manifest_wait(&self, wait: Duration) {
+        let nanos = u64::try_from(wait.as_nanos()).unwrap_or(u64::MAX);
+        self.manifest_transactions.fetch_add(1, Relaxed);
+        self.manifest_wait_nanos.fetch_add(nanos, Relaxed);
+        self.manifest_max_wait_nanos.fetch_max(nanos, Relaxed);
+    }
+
+    fn load(counter: &AtomicU64) -> u64 {
+        counter.load(Relaxed)
+    }
+
+    /// clone_cache_files is a gauge that's owned by the Handle rather than counted.
+    pub(crate) fn snapshot(&self, clone_cache_files: u64) -> Stats {
+        Stats {
+            read_hits: Self::load(&self.read_hits),
+            read_misses: Self::load(&self.read_misses),
+            bytes_written: Self::load(&self.bytes_written),
+            values_committed: Self::load(&self.values_committed),
+            bytes_committed: Self::load(&self.bytes_committed),
+            values_file_syncs: Self::load(&self.values_file_syncs),
+            evictions: Self::load(&self.evictions),
+            bytes_evicted: Self::load(&self.bytes_evicted),
+            punch_queue_values: Self::load(&self.punch_queue_values),
+            punch_queue_bytes: Self::load(&self.punch_queue_bytes),
+            punches: Self::load(&self.punches),
+            failed_punches: Self::load(&self.failed_punches),
+            clone_cache_files,
+            clone_cache_hits: Self::load(&self.clone_cache_hits),
+            file_clones: Self::load(&self.file_clones),
+            segment_lock_fallbacks: Self::load(&self.segment_lock_fallbacks),
+            manifest_transactions: Self::load(&self.manifest_transactions),
+            manifest_wait: Duration::from_nanos(Self::load(&self.manifest_wait_nanos)),
+            manifest_max_wait: Duration::from_nanos(Self::load(&self.manifest_max_wait_nanos)),
+        }
+    }
+}
+
+/// A snapshot of a Handle's statistics, returned by Handle::stats.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Stats {
+    /// Keys found by reads.
+    pub read_hits: u64,
+    /// Keys reads didn't find.
+    pub read_misses: u64,
+    /// Bytes of values written to values files, whether or not they were committed.
+    pub bytes_written: u64,
+    pub values_committed: u64,
+    pub bytes_committed: u64,
+    /// Values files synced before committing the keys that refer to them. See Durability::Full.
+    pub values_file_syncs: u64,
+    /// Values and parts of values evicted, by commits and in the background.
+    pub evictions: u64,
+    pub bytes_evicted: u64,
+    /// Values waiting to be punched in the directory, as of the value puncher's last pass. See
+    /// Handle::punch_backlog.
+    pub punch_queue_values: u64,
+    pub punch_queue_bytes: u64,
+    pub punches: u64,
+    /// Punches that were put off because a reader had the value locked. They're retried.
+    pub failed_punches: u64,
+    /// Files in the Handle's cache of file clones.
+    pub clone_cache_files: u64,
+    /// Reads that reused a cached file clone.
+    pub clone_cache_hits: u64,
+    pub file_clones: u64,
+    /// Reads that locked segments of values files because cloning wasn't possible.
+    pub segment_lock_fallbacks: u64,
+    pub manifest_transactions: u64,
+    /// The total time spent waiting to start manifest transactions.
+    pub manifest_wait: Duration,
+    pub manifest_max_wait: Duration,
+}
+
+#[derive(Clone, Copy)]
+enum MetricType {
+    Counter,
+    Gauge,
+}
+
+impl Stats {
+    /// Each statistic's name, type, help text and value.
+    fn metrics(&self) -> [(&'static str, MetricType, &'static str, f64); 19] {
+        use MetricType::*;
+        [
+            (
+                "read_hits",
+                Counter,
+                "Keys found by reads.",
+                self.read_hits as f64,
+            ),
+            (
+                "read_misses",
+                Counter,
+                "Keys reads didn't find.",
+                self.read_misses as f64,
+            ),
+            (
+                "bytes_written",
+                Counter,
+                "Bytes of values written to values files.",
+                self.bytes_written as f64,
+            ),
+            (
+                "values_committed",
+                Counter,
+                "Values committed.",
+                self.values_committed as f64,
+            ),
+            (
+                "bytes_committed",
+                Counter,
+                "Bytes of values committed.",
+                self.bytes_committed as f64,
+            ),
+            (
+                "values_file_syncs",
+                Counter,
+                "Values files synced before commits.",
+                self.values_file_syncs as f64,
+            ),
+            (
+                "evictions",
+                Counter,
+                "Values and parts of values evicted.",
+                self.evictions as f64,
+            ),
+            (
+                "bytes_evicted",
+                Counter,
+                "Bytes of values evicted.",
+                self.bytes_evicted as f64,
+            ),
+            (
+                "punch_queue_values",
+                Gauge,
+                "Values waiting to be punched.",
+                self.punch_queue_values as f64,
+            ),
+            (
+                "punch_queue_bytes",
+                Gauge,
+                "Bytes of values waiting to be punched.",
+                self.punch_queue_bytes as f64,
+            ),
+            ("punches", Counter, "Values punched.", self.punches as f64),
+            (
+                "failed_punches",
+                Counter,
+                "Punches put off because the value was locked.",
+                self.failed_punches as f64,
+            ),
+            (
+                "clone_cache_files",
+                Gauge,
+                "Files in the file clone cache.",
+                self.clone_cache_files as f64,
+            ),
+            (
+                "clone_cache_hits",
+                Counter,
+                "Reads that reused a cached file clone.",
+                self.clone_cache_hits as f64,
+            ),
+            (
+                "file_clones",
+                Counter,
+                "Values files cloned for reads.",
+                self.file_clones as f64,
+            ),
+            (
+                "segment_lock_fallbacks",
+                Counter,
+                "Reads that locked file segments instead of cloning.",
+                self.segment_lock_fallbacks as f64,
+            ),
+            (
+                "manifest_transactions",
+                Counter,
+                "Manifest transactions started.",
+                self.manifest_transactions as f64,
+            ),
+            (
+                "manifest_wait_seconds",
+                Counter,
+                "Time spent waiting to start manifest transactions.",
+                self.manifest_wait.as_secs_f64(),
+            ),
+            (
+                "manifest_max_wait_seconds",
+                Gauge,
+                "The longest wait to start a manifest transaction.",
+                self.manifest_max_wait.as_secs_f64(),
+            ),
+        ]
+    }
+
+    /// Writes the statistics in the Prometheus text exposition format.
+    pub fn write_prometheus(&self, w: &mut impl Write) -> io::Result<()> {
+        for (name, metric_type, help, value) in self.metrics() {
+            // Counters are conventionally suffixed with _total.
+            let (name, type_name) = match metric_type {
+                MetricType::Counter => (format!("possum_{name}_total"), "counter"),
+                MetricType::Gauge => (format!("possum_{name}"), "gauge"),
+            };
+            writeln!(w, "# HELP {name} {help}")?;
+            writeln!(w, "# TYPE {name} {type_name}")?;
+            writeln!(w, "{name} {value}")?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for Stats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for (name, _, _, value) in self.metrics() {
+            writeln!(f, "{name}: {value}")?;
+        }
+        Ok(())
+    }
+}
@@ -27,6 +27,11 @@ pub trait FileLocking {
     fn lock_max_segment(&self, arg: FlockArg) -> io::Result<bool> {
         self.lock_segment(arg, None, 0)
     }
+    /// Whether nobody else holds a lock anywhere in the file. Unlike taking an exclusive lock, this
+    /// works on files opened read-only. Any lock it takes is held until the file is closed.
+    fn unlocked(&self) -> io::Result<bool> {
+        self.lock_max_segment(LockExclusiveNonblock)
+    }
 }
 
 #[cfg(test)]
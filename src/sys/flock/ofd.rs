@@ -80,7 +80,30 @@ pub(super) fn lock_file_segment(
     }
 }
 
+/// Whether an exclusive lock on the whole file would succeed, without taking it. OFD write locks
+/// need the file open for writing, but querying them doesn't.
+fn exclusive_lock_free(file: &File) -> nix::Result<bool> {
+    let mut flock_arg: flock_struct = unsafe { std::mem::zeroed() };
+    #[allow(clippy::useless_conversion)]
+    let l_type = libc::F_WRLCK.try_into().unwrap();
+    flock_arg.l_type = l_type;
+    #[allow(clippy::useless_conversion)]
+    let l_whence = libc::SEEK_SET.try_into().unwrap();
+    flock_arg.l_whence = l_whence;
+    nix::errno::Errno::result(unsafe {
+        libc::fcntl(file.as_raw_fd(), libc::F_OFD_GETLK, &mut flock_arg)
+    })?;
+    Ok(flock_arg.l_type == libc::F_UNLCK as libc::c_short)
+}
+
 impl FileLocking for File {
+    fn unlocked(&self) -> io::Result<bool> {
+        if flocking() {
+            return self.flock(LockExclusiveNonblock);
+        }
+        Ok(exclusive_lock_free(self)?)
+    }
+
     fn trim_exclusive_lock_left(&self, old_left: u64, new_left: u64) -> io::Result<bool> {
         if flocking() {
             return Ok(true);
@@ -48,6 +48,14 @@ cfg_if! {
     }
 }
 
+/// Space on a filesystem, as returned by path_free_space.
+#[derive(Debug, Clone, Copy)]
+pub struct FreeSpace {
+    /// Bytes available to unprivileged users.
+    pub available: u64,
+    pub total: u64,
+}
+
 pub trait SparseFile {
     fn set_sparse(&self, set_sparse: bool) -> io::Result<()>;
 }
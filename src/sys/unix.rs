@@ -5,7 +5,7 @@ use std::path::Path;
 pub(crate) use nix::errno::errno;
 
 use crate::env::emulate_freebsd;
-use crate::sys::{DirMeta, FileSystemFlags};
+use crate::sys::{DirMeta, FileSystemFlags, FreeSpace};
 
 pub fn path_disk_allocation(path: &Path) -> std::io::Result<u64> {
     let metadata = std::fs::metadata(path)?;
@@ -13,6 +13,15 @@ pub fn path_disk_allocation(path: &Path) -> std::io::Result<u64> {
     Ok(metadata.blocks() * 512)
 }
 
+pub fn path_free_space(path: &Path) -> std::io::Result<FreeSpace> {
+    let stat = nix::sys::statvfs::statvfs(path)?;
+    let fragment_size = stat.fragment_size() as u64;
+    Ok(FreeSpace {
+        available: stat.blocks_available() as u64 * fragment_size,
+        total: stat.blocks() as u64 * fragment_size,
+    })
+}
+
 struct UnixFilesystemFlags {}
 
 impl FileSystemFlags for UnixFilesystemFlags {
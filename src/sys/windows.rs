@@ -49,6 +49,14 @@ pub fn path_disk_allocation(path: &Path) -> io::Result<u64> {
     file_disk_allocation(&File::open(path)?)
 }
 
+pub fn path_free_space(path: &Path) -> io::Result<FreeSpace> {
+    let path = ::windows::core::HSTRING::from(path);
+    let mut available = 0;
+    let mut total = 0;
+    unsafe { GetDiskFreeSpaceExW(&path, Some(&mut available), Some(&mut total), None) }?;
+    Ok(FreeSpace { available, total })
+}
+
 // Do we need to require that I and O be slices? Does that mean we can do the bytes_returned element
 // calculations here rather than force the caller to do it?
 pub(crate) fn device_io_control<I: ?Sized, O: ?Sized>(
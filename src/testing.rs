@@ -84,7 +84,7 @@ pub fn test_tempdir(name: &'static str) -> Result<TestTempDir> {
 }
 
 pub fn readable_repeated_bytes(byte: u8, limit: usize) -> Vec<u8> {
-    std::iter::repeat(byte).take(limit).collect()
+    std::iter::repeat_n(byte, limit).collect()
 }
 
 pub fn condense_repeated_bytes(r: impl Read) -> (Option<u8>, u64) {
@@ -46,6 +46,7 @@ impl TorrentStorageOpts {
             handle.set_instance_limits(handle::Limits {
                 disable_hole_punching: opts.disable_hole_punching,
                 max_value_length_sum: Some(opts.piece_size as u64 * opts.num_pieces as u64 / 2),
+                ..Default::default()
             })?;
             Ok(handle)
         };
@@ -188,3 +188,190 @@ fn test_sqlite_update_same_value_txn_state() -> Result<()> {
     assert_eq!(tx.transaction_state(None)?, TransactionState::Write);
     Ok(())
 }
+
+/// The background evictor is only started once there's something for it to do.
+#[test]
+fn background_evictor_started_on_demand() -> Result<()> {
+    let tempdir = test_tempdir("background_evictor_started_on_demand")?;
+    let mut handle = Handle::new(tempdir.path.clone())?;
+    handle.single_write_from("a".as_bytes().to_vec(), "b".as_bytes())?;
+    assert!(handle.background_evictor.lock().unwrap().is_none());
+    handle.set_instance_limits(Limits {
+        high_watermark: Some(0),
+        ..Default::default()
+    })?;
+    assert!(handle.background_evictor.lock().unwrap().is_some());
+    Ok(())
+}
+
+/// Touches that fail to apply are kept for the next attempt.
+#[test]
+fn failed_touches_are_kept() -> Result<()> {
+    let touches = touches::Touches::default();
+    let now = Timestamp::from(SystemTime::now());
+    assert!(touches.record(1, b"a", Timestamp::from(UNIX_EPOCH), now));
+    let mut conn = rusqlite::Connection::open_in_memory()?;
+    // There's no keys table.
+    assert!(touches.apply(&conn.transaction()?).is_err());
+    assert!(touches.due_in().is_some());
+    Ok(())
+}
+
+/// The block rounded sum counts unrounded lengths until the block size is stored.
+#[test]
+fn block_rounded_sum_without_block_size() -> Result<()> {
+    let tempdir = test_tempdir("block_rounded_sum_without_block_size")?;
+    let handle = Handle::new(tempdir.path.clone())?;
+    let conn = handle.conn.lock().unwrap();
+    conn.execute("delete from settings where name='block_size'", [])?;
+    // The directory is kept between runs.
+    conn.execute("delete from keys where key=x'00'", [])?;
+    let sum = || -> rusqlite::Result<Option<u64>> {
+        conn.query_row(
+            "select value from sums where key='block_rounded_value_length'",
+            [],
+            |row| row.get(0),
+        )
+    };
+    let before = sum()?.unwrap();
+    conn.execute(
+        "insert into keys (key, file_id, file_offset, value_length) values (x'00', 1, 0, 10)",
+        [],
+    )?;
+    assert_eq!(sum()?, Some(before + 10));
+    conn.execute("delete from keys where key=x'00'", [])?;
+    assert_eq!(sum()?, Some(before));
+    Ok(())
+}
+
+/// Each durability level sets synchronous on the Handle's and background connections, and Full syncs
+/// values files before the manifest transaction writes keys.
+#[test]
+fn durability_levels_apply() -> Result<()> {
+    let tempdir = test_tempdir("durability_levels_apply")?;
+    let mut handle = Handle::new(tempdir.path.clone())?;
+    let shared = Mutex::new(Durability::default());
+    let mut background_conn = handle::BackgroundConn::open(&handle.dir)?;
+    for (durability, synchronous) in [
+        (Durability::None, 0),
+        (Durability::Manifest, 1),
+        (Durability::Full, 2),
+    ] {
+        handle.set_durability(durability)?;
+        *shared.lock().unwrap() = durability;
+        let pragma = |conn: &Connection| -> rusqlite::Result<i64> {
+            conn.pragma_query_value(None, "synchronous", |row| row.get(0))
+        };
+        assert_eq!(pragma(&handle.conn.lock().unwrap())?, synchronous);
+        assert_eq!(pragma(background_conn.get(&shared)?)?, synchronous);
+        let syncs_before = handle.stats().values_file_syncs;
+        let mut writer = handle.new_writer()?;
+        let mut value = writer.new_value().begin()?;
+        value.write_all(durability.name().as_bytes())?;
+        writer.stage_write(durability.name().as_bytes().to_vec(), value)?;
+        writer.commit_inner(|| {
+            let synced = handle.stats().values_file_syncs > syncs_before;
+            assert_eq!(synced, durability.sync_values());
+        })?;
+    }
+    Ok(())
+}
+
+/// Punches that don't start greedily leave the block the range starts in, since what's before the
+/// range in that block may still be in use, like the earlier parts of a value stored in blocks.
+#[test]
+fn punch_value_unaligned_start() -> Result<()> {
+    let tempdir = test_tempdir("punch_value_unaligned_start")?;
+    let handle = Handle::new(tempdir.path.clone())?;
+    let block_size = handle.block_size();
+    handle.single_write_from(b"a".to_vec(), &*vec![1; 4 * block_size as usize])?;
+    let location = handle.list_items(b"a")?[0]
+        .value
+        .location
+        .into_non_zero()
+        .unwrap();
+    let tx = handle.start_deferred_transaction_for_read()?;
+    let outcome = punch_value(PunchValueOptions {
+        dir: handle.dir.path(),
+        file_id: &location.file_id,
+        offset: location.file_offset + block_size / 2,
+        length: 3 * block_size - block_size / 2,
+        tx: &tx,
+        block_size,
+        constraints: PunchValueConstraints {
+            greedy_start: false,
+            greedy_end: false,
+            allow_truncate: false,
+            allow_remove: false,
+            ..Default::default()
+        },
+    })?;
+    assert_eq!(outcome, PunchOutcome::Punched);
+    let contents = fs::read(file_path(handle.dir.path(), location.file_id))?;
+    let value = &contents[location.file_offset as usize..][..4 * block_size as usize];
+    let block_size = block_size as usize;
+    assert!(value[..block_size].iter().all(|&byte| byte == 1));
+    assert!(value[block_size..3 * block_size]
+        .iter()
+        .all(|&byte| byte == 0));
+    assert!(value[3 * block_size..].iter().all(|&byte| byte == 1));
+    Ok(())
+}
+
+/// Reads of part of a compressed value don't keep the rest of it decompressed.
+#[test]
+fn compressed_read_at_not_kept() -> Result<()> {
+    let tempdir = test_tempdir("compressed_read_at_not_kept")?;
+    let handle = Handle::new(tempdir.path.clone())?;
+    let data = b"compressible ".repeat(100_000);
+    let mut writer = handle.new_writer()?;
+    let mut value = writer.new_value().begin()?;
+    value.compress(Codec::Zstd)?;
+    value.copy_from(&data[..])?;
+    writer.stage_write(b"a".to_vec(), value)?;
+    writer.commit()?;
+    let value = handle.read_single(b"a")?.unwrap();
+    let mut buf = [0; 12];
+    assert_eq!(value.read_at(13 * 50_000, &mut buf)?, 12);
+    assert_eq!(&buf, b"compressible");
+    assert!(value.decoded.get().is_none());
+    value.view(|bytes| assert_eq!(bytes, data))?;
+    assert!(value.decoded.get().is_some());
+    Ok(())
+}
+
+/// Large values are only stored in blocks by Handles with partial eviction.
+#[test]
+fn blocks_need_partial_eviction() -> Result<()> {
+    let tempdir = test_tempdir("blocks_need_partial_eviction")?;
+    let mut handle = Handle::new(tempdir.path.clone())?;
+    let data = vec![1; 3 * blocks::BLOCK_LEN as usize];
+    handle.single_write_from(b"whole".to_vec(), &data[..])?;
+    handle.set_instance_limits(Limits {
+        partial_eviction: true,
+        ..Default::default()
+    })?;
+    handle.single_write_from(b"blocks".to_vec(), &data[..])?;
+    let whole = handle.read_single(b"whole")?.unwrap();
+    let in_blocks = handle.read_single(b"blocks")?.unwrap();
+    assert!(!whole.in_blocks());
+    assert!(in_blocks.in_blocks());
+    let conn = handle.conn.lock().unwrap();
+    let parts = |key_id| -> rusqlite::Result<u64> {
+        conn.query_row(
+            "select count(*) from value_parts where key_id=?",
+            [key_id],
+            |row| row.get(0),
+        )
+    };
+    assert_eq!(parts(whole.key_id)?, 0);
+    assert_eq!(parts(in_blocks.key_id)?, 3);
+    // Each value is counted once, whether by its key or by its blocks.
+    let sum: u64 = conn.query_row(
+        "select value from sums where key='value_length'",
+        [],
+        |row| row.get(0),
+    )?;
+    assert_eq!(sum, 2 * data.len() as u64);
+    Ok(())
+}
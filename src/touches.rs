@@ -0,0 +1,184 @@
+//! Reads record that their keys were used here instead of updating the manifest, so they don't need
+//! the manifest write lock. The background evictor applies them in batches, and so do the Handle's
+//! own commits, so eviction sees recent reads. Touches that fail to apply are kept for the next
+//! attempt, and the number of pending keys is bounded in case applying keeps failing. Snapshot
+//! reads of values stored in blocks record the blocks they read the same way. Those don't wake the
+//! background evictor, but they're applied with the key touches, which is always before evicting.
+
+use std::collections::hash_map::Entry;
+use std::time::Instant;
+
+use super::*;
+
+/// Pending touches are applied at least this often.
+pub(crate) const TOUCH_FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+/// Pending touches are applied as soon as there are this many keys.
+const TOUCH_BATCH_KEYS: usize = 1000;
+/// Reads of other keys aren't recorded while this many are pending. That only happens if applying
+/// touches is failing.
+const MAX_PENDING_TOUCH_KEYS: usize = 16 * TOUCH_BATCH_KEYS;
+
+#[derive(Debug)]
+struct Touch {
+    // The key_id can be reused if the key is deleted before the touch is applied.
+    key: Vec<u8>,
+    last_used: Timestamp,
+    // Reads in the same millisecond count once, as they do in the manifest.
+    count: u64,
+}
+
+#[derive(Debug)]
+struct BlockTouch {
+    // The block_id can be reused if the block is released before the touch is applied.
+    file_id: FileId,
+    file_offset: u64,
+    last_used: Timestamp,
+}
+
+#[derive(Debug, Default)]
+struct Pending {
+    by_key_id: HashMap<i64, Touch>,
+    by_block_id: HashMap<i64, BlockTouch>,
+    // When the first of the pending touches was recorded.
+    since: Option<Instant>,
+}
+
+/// A Handle's touches that haven't been applied to the manifest yet.
+#[derive(Debug, Default)]
+pub(crate) struct Touches(Mutex<Pending>);
+
+impl Touches {
+    /// Records a read of key at now, given the last_used the read saw in the manifest. Returns
+    /// true if the background evictor should be woken to schedule a flush.
+    pub(crate) fn record(
+        &self,
+        key_id: i64,
+        key: &[u8],
+        manifest_last_used: Timestamp,
+        now: Timestamp,
+    ) -> bool {
+        let mut pending = self.0.lock().unwrap();
+        let last_used = pending
+            .by_key_id
+            .get(&key_id)
+            .map_or(manifest_last_used, |touch| touch.last_used);
+        if last_used == now {
+            return false;
+        }
+        if pending.by_key_id.len() >= MAX_PENDING_TOUCH_KEYS
+            && !pending.by_key_id.contains_key(&key_id)
+        {
+            warn!(key_id, "too many pending touches, dropping");
+            return false;
+        }
+        let touch = pending.by_key_id.entry(key_id).or_insert_with(|| Touch {
+            key: key.to_owned(),
+            last_used: now,
+            count: 0,
+        });
+        touch.last_used = now;
+        touch.count += 1;
+        let was_empty = pending.since.is_none();
+        pending.since.get_or_insert_with(Instant::now);
+        was_empty || pending.by_key_id.len() == TOUCH_BATCH_KEYS
+    }
+
+    /// Records a snapshot read of a block at now.
+    pub(crate) fn record_block(&self, block: &blocks::BlockLocation, now: Timestamp) {
+        let mut pending = self.0.lock().unwrap();
+        if pending.by_block_id.len() >= MAX_PENDING_TOUCH_KEYS
+            && !pending.by_block_id.contains_key(&block.block_id)
+        {
+            warn!(block.block_id, "too many pending block touches, dropping");
+            return;
+        }
+        pending.by_block_id.insert(
+            block.block_id,
+            BlockTouch {
+                file_id: block.file_id,
+                file_offset: block.file_offset,
+                last_used: now,
+            },
+        );
+        pending.since.get_or_insert_with(Instant::now);
+    }
+
+    /// How long until the pending touches should be applied, or None if there aren't any.
+    pub(crate) fn due_in(&self) -> Option<Duration> {
+        let pending = self.0.lock().unwrap();
+        let since = pending.since?;
+        if pending.by_key_id.len() >= TOUCH_BATCH_KEYS {
+            return Some(Duration::ZERO);
+        }
+        Some(TOUCH_FLUSH_INTERVAL.saturating_sub(since.elapsed()))
+    }
+
+    /// Applies the pending touches in the transaction. They're kept for the next attempt if they
+    /// can't be applied, but dropped if the transaction doesn't commit after, since they only inform
+    /// eviction.
+    pub(crate) fn apply(&self, tx: &rusqlite::Transaction<'_>) -> rusqlite::Result<()> {
+        let (by_key_id, by_block_id, since) = {
+            let mut pending = self.0.lock().unwrap();
+            (
+                std::mem::take(&mut pending.by_key_id),
+                std::mem::take(&mut pending.by_block_id),
+                pending.since.take(),
+            )
+        };
+        if by_key_id.is_empty() && by_block_id.is_empty() {
+            return Ok(());
+        }
+        let result = (|| {
+            let mut stmt = tx.prepare_cached(
+                "update keys set last_used=max(last_used, ?), use_count=use_count+? \
+                where key_id=? and key=?",
+            )?;
+            for (key_id, touch) in &by_key_id {
+                stmt.execute(params![touch.last_used, touch.count, key_id, touch.key])?;
+            }
+            let mut stmt = tx.prepare_cached(
+                "update blocks set last_used=max(last_used, ?) \
+                where block_id=? and file_id=? and file_offset=?",
+            )?;
+            for (block_id, touch) in &by_block_id {
+                stmt.execute(params![
+                    touch.last_used,
+                    block_id,
+                    touch.file_id,
+                    touch.file_offset
+                ])?;
+            }
+            Ok(())
+        })();
+        if result.is_err() {
+            self.restore(by_key_id, by_block_id, since);
+        }
+        result
+    }
+
+    /// Merges touches that failed to apply back into those recorded since.
+    fn restore(
+        &self,
+        by_key_id: HashMap<i64, Touch>,
+        by_block_id: HashMap<i64, BlockTouch>,
+        since: Option<Instant>,
+    ) {
+        let mut pending = self.0.lock().unwrap();
+        for (block_id, touch) in by_block_id {
+            // A block read again since has a later last_used already.
+            pending.by_block_id.entry(block_id).or_insert(touch);
+        }
+        for (key_id, touch) in by_key_id {
+            match pending.by_key_id.entry(key_id) {
+                Entry::Occupied(mut entry) => {
+                    // The key was read again since, so its last_used is already later.
+                    entry.get_mut().count += touch.count;
+                }
+                Entry::Vacant(entry) => {
+                    entry.insert(touch);
+                }
+            }
+        }
+        pending.since = pending.since.into_iter().chain(since).min();
+    }
+}
@@ -4,23 +4,27 @@ use super::*;
 #[must_use]
 pub(crate) struct PostCommitWork<H> {
     handle: H,
-    deleted_values: Vec<NonzeroValueLocation>,
+    // Set when values were added to the punch queue.
+    wake_value_puncher: bool,
     altered_files: HashSet<FileId>,
+    sweep_expired: bool,
+    background_eviction: bool,
+    evicted: eviction::Evicted,
 }
 
 /// Exposes a rusqlite Transaction to implement ReadTransaction.
 pub trait ReadOnlyTransactionAccessor {
-    fn readonly_transaction(&self) -> &rusqlite::Transaction;
+    fn readonly_transaction(&self) -> &rusqlite::Transaction<'_>;
 }
 
 /// Extends rusqlite objects with stuff needed for ReadTransaction.
 trait ReadOnlyRusqliteTransaction {
-    fn prepare_cached_readonly(&self, sql: &str) -> rusqlite::Result<CachedStatement>;
+    fn prepare_cached_readonly(&self, sql: &str) -> rusqlite::Result<CachedStatement<'_>>;
 }
 
 // This could just as easily be implemented for rusqlite::Connection too.
 impl ReadOnlyRusqliteTransaction for rusqlite::Transaction<'_> {
-    fn prepare_cached_readonly(&self, sql: &str) -> rusqlite::Result<CachedStatement> {
+    fn prepare_cached_readonly(&self, sql: &str) -> rusqlite::Result<CachedStatement<'_>> {
         prepare_cached_readonly(self.borrow(), sql)
     }
 }
@@ -40,7 +44,7 @@ pub type ReadTransactionRef<'a> = &'a ReadTransactionOwned<'a>;
 pub struct ReadTransactionOwned<'a>(pub(crate) rusqlite::Transaction<'a>);
 
 impl ReadOnlyTransactionAccessor for ReadTransactionOwned<'_> {
-    fn readonly_transaction(&self) -> &rusqlite::Transaction {
+    fn readonly_transaction(&self) -> &rusqlite::Transaction<'_> {
         &self.0
     }
 }
@@ -48,7 +52,10 @@ impl ReadOnlyTransactionAccessor for ReadTransactionOwned<'_> {
 /// Extra methods for types exposing a rusqlite Transaction that's allowed to do read transaction
 /// stuff.
 pub trait ReadTransaction: ReadOnlyTransactionAccessor {
-    fn file_values(&self, file_id: FileId) -> rusqlite::Result<FileValues<CachedStatement>> {
+    fn file_values(
+        &self,
+        file_id: FileId,
+    ) -> rusqlite::Result<FileValues<'_, CachedStatement<'_>>> {
         let stmt = self
             .readonly_transaction()
             .prepare_cached_readonly(&format!(
@@ -65,13 +72,16 @@ pub trait ReadTransaction: ReadOnlyTransactionAccessor {
             .query_row([], |row| row.get(0))
     }
 
-    /// Returns the end offset of the last active value before offset in the same file.
+    /// Returns the end offset of the last active value or block before offset in the same file.
     fn query_last_end_offset(&self, file_id: &FileId, offset: u64) -> rusqlite::Result<u64> {
         self.readonly_transaction()
             .prepare_cached_readonly(
-                "select max(file_offset+value_length) as last_offset \
-                from keys \
-                where file_id=? and file_offset+value_length <= ?",
+                "select max(\
+                    (select coalesce(max(file_offset+value_length), 0) from keys \
+                    where file_id=?1 and file_offset+value_length <= ?2), \
+                    (select coalesce(max(file_offset+block_length), 0) from blocks \
+                    where file_id=?1 and file_offset+block_length <= ?2)\
+                ) as last_offset",
             )?
             .query_row(params![file_id, offset], |row| {
                 // I don't know why, but this can return null for file_ids that have values but
@@ -81,7 +91,47 @@ pub trait ReadTransaction: ReadOnlyTransactionAccessor {
             })
     }
 
-    /// Returns the next value offset with at least min_offset.
+    /// Whether a range of a values file is exactly a part of a value that a key still refers to,
+    /// and the part was evicted or shares a block elsewhere.
+    fn is_released_part(
+        &self,
+        file_id: &FileId,
+        offset: u64,
+        length: u64,
+    ) -> rusqlite::Result<bool> {
+        self.readonly_transaction()
+            .prepare_cached_readonly(
+                "select exists(\
+                    select 1 from keys join value_parts using (key_id) \
+                    left join blocks using (block_id) \
+                    where keys.file_id=?1 and keys.file_offset+part_offset=?2 and part_length=?3 \
+                    and not (blocks.file_id is ?1 and blocks.file_offset is ?2)\
+                )",
+            )?
+            .query_row(params![file_id, offset, length], |row| row.get(0))
+    }
+
+    /// Whether any value that a key refers to, or block, overlaps a range of a values file.
+    fn range_overlaps_value(
+        &self,
+        file_id: &FileId,
+        offset: u64,
+        length: u64,
+    ) -> rusqlite::Result<bool> {
+        self.readonly_transaction()
+            .prepare_cached_readonly(
+                "select exists(\
+                    select 1 from keys \
+                    where file_id=?1 and file_offset<?2 and file_offset+value_length>?3\
+                ) or exists(\
+                    select 1 from blocks \
+                    where file_id=?1 and file_offset<?2 and file_offset+block_length>?3\
+                )",
+            )?
+            .query_row(params![file_id, offset + length, offset], |row| row.get(0))
+    }
+
+    /// Returns the next value or block offset with at least min_offset.
     fn next_value_offset(
         &self,
         file_id: &FileId,
@@ -89,9 +139,10 @@ pub trait ReadTransaction: ReadOnlyTransactionAccessor {
     ) -> rusqlite::Result<Option<u64>> {
         self.readonly_transaction()
             .prepare_cached_readonly(
-                "select min(file_offset) \
-                from keys \
-                where file_id=? and file_offset >= ?",
+                "with next (key_offset, block_offset) as (select \
+                    (select min(file_offset) from keys where file_id=?1 and file_offset >= ?2), \
+                    (select min(file_offset) from blocks where file_id=?1 and file_offset >= ?2)\
+                ) select coalesce(min(key_offset, block_offset), key_offset, block_offset) from next",
             )?
             .query_row(params![file_id, min_offset], |row| row.get(0))
     }
@@ -110,16 +161,18 @@ pub trait ReadTransaction: ReadOnlyTransactionAccessor {
             None => list_items_inner(
                 self.readonly_transaction(),
                 &format!(
-                    "select {}, key from keys where key >= ?",
-                    value_columns_sql()
+                    "select {}, key from keys where key >= ? and {}",
+                    value_columns_sql(),
+                    NOT_EXPIRED_SQL,
                 ),
                 [prefix],
             ),
             Some(range_end) => list_items_inner(
                 self.readonly_transaction(),
                 &format!(
-                    "select {}, key from keys where key >= ? and key < ?",
-                    value_columns_sql()
+                    "select {}, key from keys where key >= ? and key < ? and {}",
+                    value_columns_sql(),
+                    NOT_EXPIRED_SQL,
                 ),
                 rusqlite::params![prefix, range_end],
             ),
@@ -127,6 +180,11 @@ pub trait ReadTransaction: ReadOnlyTransactionAccessor {
     }
 }
 
+/// Condition for keys that are visible to readers. Expired keys linger until the next write
+/// transaction deletes them.
+const NOT_EXPIRED_SQL: &str =
+    "(expires is null or expires > cast(unixepoch('subsec')*1e3 as integer))";
+
 fn list_items_inner(
     tx: &rusqlite::Transaction,
     sql: &str,
@@ -151,15 +209,20 @@ where
     pub fn complete(self) {
         // This has to happen after exclusive files are flushed or there's a tendency for hole
         // punches to not persist. It doesn't fix the problem, but it significantly reduces it.
-        if !self.handle.as_ref().instance_limits.disable_hole_punching {
-            self.handle
-                .as_ref()
-                .send_values_for_delete(self.deleted_values);
+        if self.wake_value_puncher {
+            self.handle.as_ref().wake_value_puncher();
         }
         // Forget any references to clones of files that have changed.
         for file_id in self.altered_files {
             self.handle.as_ref().clones.lock().unwrap().remove(&file_id);
         }
+        if self.sweep_expired {
+            self.handle.as_ref().request_expiry_sweep();
+        }
+        self.evicted.record(&self.handle.as_ref().stats);
+        if self.background_eviction {
+            self.handle.as_ref().request_background_eviction(true);
+        }
     }
 }
 
@@ -170,12 +233,18 @@ pub struct Transaction<'h, H> {
     handle: H,
     deleted_values: Vec<NonzeroValueLocation>,
     altered_files: HashSet<FileId>,
+    // Set when keys with an expiry are inserted.
+    sweep_expired: bool,
+    // Set when the high watermark is exceeded.
+    background_eviction: bool,
+    // Counted once the transaction commits. The locations are in deleted_values.
+    evicted: eviction::Evicted,
 }
 
 // TODO: Try doing this with a read trait that just requires a rusqlite::Transaction be available.
 
 impl<H> ReadOnlyTransactionAccessor for Transaction<'_, H> {
-    fn readonly_transaction(&self) -> &rusqlite::Transaction {
+    fn readonly_transaction(&self) -> &rusqlite::Transaction<'_> {
         &self.tx
     }
 }
@@ -183,27 +252,20 @@ impl<H> ReadOnlyTransactionAccessor for Transaction<'_, H> {
 impl<T> ReadTransaction for T where T: ReadOnlyTransactionAccessor {}
 
 impl<H> Transaction<'_, H> {
+    /// Looks up a key's value and counts it as used in the manifest, which needs the write lock.
+    /// Readers record touches on the Handle instead.
     pub fn touch_for_read(&mut self, key: &[u8]) -> rusqlite::Result<Value> {
-        // Avoid modifying the manifest. We had to take a write lock already to ensure our data
-        // isn't modified on us, but it still seems to be an improvement. (-67% on read times in
-        // fact).
-        let (file_id, file_offset, value_length, mut last_used, now) = self
-            .tx
-            .prepare_cached_readonly(&format!(
-                "select {}, cast(unixepoch('subsec')*1e3 as integer) \
-                from keys where key=?",
-                value_columns_sql()
-            ))?
-            .query_row([key], |row| row.try_into())?;
-        let update_last_used = last_used != now;
-        // eprintln!("updating last used: {}", update_last_used);
-        if update_last_used {
+        let (mut value, now) = self.read_value(key)?;
+        // Avoid modifying the manifest if last_used wouldn't change. It seems to be an improvement
+        // (-67% on read times in fact).
+        if value.last_used != now {
             let (new_last_used,) = self
                 .tx
                 .prepare_cached(
                     r"
                     update keys
-                    set last_used=cast(unixepoch('subsec')*1e3 as integer)
+                    set last_used=cast(unixepoch('subsec')*1e3 as integer),
+                        use_count=use_count+1
                     where key=?
                     returning last_used
                     ",
@@ -211,10 +273,24 @@ impl<H> Transaction<'_, H> {
                 .query_row([key], |row| row.try_into())?;
             // This can in fact change between calls. Since we're updating now anyway, we don't
             // really care.
-            //assert_eq!(new_last_used, now);
-            last_used = new_last_used;
+            value.last_used = new_last_used;
         }
-        Value::from_column_values(file_id, file_offset, value_length, last_used)
+        Ok(value)
+    }
+
+    /// Looks up a key's value without counting it as used. Also returns the manifest's current
+    /// time, for recording the use.
+    pub(crate) fn read_value(&mut self, key: &[u8]) -> rusqlite::Result<(Value, Timestamp)> {
+        self.tx
+            .prepare_cached_readonly(&format!(
+                "select {}, cast(unixepoch('subsec')*1e3 as integer) \
+                from keys where key=? and {}",
+                value_columns_sql(),
+                NOT_EXPIRED_SQL,
+            ))?
+            .query_row([key], |row| {
+                Ok((Value::from_row(row)?, row.get(VALUE_COLUMN_NAMES.len())?))
+            })
     }
 }
 
@@ -227,12 +303,26 @@ where
     }
 
     pub(crate) fn commit(mut self) -> Result<PostCommitWork<H>> {
+        self.delete_expired()?;
+        self.apply_touches()?;
         self.apply_limits()?;
+        self.take_released_blocks()?;
+        // Keys sharing a deduplicated value can all be deleted after the first one is checked.
+        self.deleted_values.sort_unstable();
+        self.deleted_values.dedup();
+        let wake_value_puncher = !self.deleted_values.is_empty()
+            && !self.handle.as_ref().instance_limits.disable_hole_punching;
+        if wake_value_puncher {
+            punch_queue::enqueue(&self.tx, &self.deleted_values)?;
+        }
         self.tx.commit()?;
         Ok(PostCommitWork {
             handle: self.handle,
-            deleted_values: self.deleted_values,
+            wake_value_puncher,
             altered_files: self.altered_files,
+            sweep_expired: self.sweep_expired,
+            background_eviction: self.background_eviction,
+            evicted: self.evicted,
         })
     }
 
@@ -242,64 +332,146 @@ where
         }
         if let Some(max) = self.handle.as_ref().instance_limits.max_value_length_sum {
             loop {
-                let actual = self
-                    .sum_value_length()
-                    .context("reading value_length sum")?;
-                if actual <= max {
+                let usage = self.disk_usage().context("reading disk usage")?;
+                if usage.total() <= max {
+                    break;
+                }
+                if usage.values == 0 {
+                    warn!(?usage, max, "no values left to evict");
+                    break;
+                }
+                // Evicting by value length frees at least as much as it's counted for.
+                let evicted_before = self.evicted.count;
+                self.evict_values(min(usage.total() - max, usage.values))?;
+                if self.evicted.count == evicted_before {
+                    // The sums count values that aren't in keys.
+                    warn!(?usage, max, "no values could be evicted");
                     break;
                 }
-                self.evict_values(actual - max)?;
             }
         }
+        self.evict_for_free_space()?;
+        if let Some(high_watermark) = self.handle.as_ref().instance_limits.high_watermark {
+            // The background evictor can't start until we commit.
+            self.background_eviction =
+                self.disk_usage().context("reading disk usage")?.total() > high_watermark;
+        }
+        Ok(())
+    }
+
+    /// Evicts values if the filesystem has less free space than the limits require. The space isn't
+    /// freed until the values are punched, so values waiting to be punched count as free.
+    fn evict_for_free_space(&mut self) -> Result<()> {
+        let handle = self.handle.as_ref();
+        let pending_punch_bytes = || {
+            Ok(punch_queue::backlog(&self.tx)?.bytes
+                + self
+                    .deleted_values
+                    .iter()
+                    .map(|value| value.length)
+                    .sum::<u64>())
+        };
+        let shortfall =
+            usage::free_space_shortfall(&handle.dir, &handle.instance_limits, pending_punch_bytes)
+                .context("checking free space")?;
+        if shortfall == 0 {
+            return Ok(());
+        }
+        let target = min(shortfall, self.sum_value_length()?);
+        if target != 0 {
+            self.evict_values(target)?;
+        }
+        Ok(())
+    }
+
+    /// Disk usage as counted while committing. See UsageAccounting::for_commit.
+    fn disk_usage(&self) -> Result<DiskUsage> {
+        let handle = self.handle.as_ref();
+        usage::disk_usage(
+            &self.tx,
+            &handle.dir,
+            handle.instance_limits.usage_accounting.for_commit(),
+        )
+    }
+
+    /// Applies reads recorded on the Handle, since the transaction has the write lock anyway, and
+    /// so eviction sees them.
+    fn apply_touches(&mut self) -> Result<()> {
+        if self.tx.transaction_state(None)? != rusqlite::TransactionState::Write {
+            return Ok(());
+        }
+        self.handle.as_ref().touches.apply(&self.tx)?;
+        Ok(())
+    }
+
+    /// Schedules blocks released by the transaction for hole punching. Transactions that haven't
+    /// written can't have released any.
+    fn take_released_blocks(&mut self) -> Result<()> {
+        if self.tx.transaction_state(None)? != rusqlite::TransactionState::Write {
+            return Ok(());
+        }
+        self.deleted_values
+            .append(&mut blocks::take_released(&self.tx)?);
         Ok(())
     }
+
+    /// Deletes keys that have expired, scheduling their values for hole punching. Like
+    /// apply_limits, this does nothing unless the transaction is already writing.
+    pub fn delete_expired(&mut self) -> Result<()> {
+        if self.tx.transaction_state(None)? != rusqlite::TransactionState::Write {
+            return Ok(());
+        }
+        let mut expired = expiry::delete_expired(&self.tx, None)?;
+        self.deleted_values.append(&mut expired);
+        Ok(())
+    }
+
     pub fn new(tx: rusqlite::Transaction<'h>, handle: H) -> Self {
         Self {
             tx,
             handle,
             deleted_values: vec![],
             altered_files: Default::default(),
+            sweep_expired: false,
+            background_eviction: false,
+            evicted: Default::default(),
         }
     }
 
     // TODO: Add a test for renaming onto itself.
     pub fn rename_value(&mut self, value: &Value, new_key: Vec<u8>) -> PubResult<bool> {
-        match self
+        let existing_value = self
             .tx
             .prepare_cached(&format!(
                 "delete from keys where key=? returning {}",
                 value_columns_sql()
             ))?
-            .query_row(params![&new_key], Value::from_row)
-        {
+            .query_row(params![&new_key], Value::from_row);
+        match existing_value {
             Err(QueryReturnedNoRows) => {}
             Err(err) => return Err(err.into()),
             Ok(existing_value) => {
-                match existing_value.location {
-                    Nonzero(a) => {
-                        let b = value;
-                        if Some(a.file_offset) == b.file_offset() && Some(&a.file_id) == b.file_id()
-                        {
-                            assert_eq!(a.length, b.length());
-                            // Renamed but the name is the same.
-                            return Ok(true);
-                        }
-                        // Schedule the value that previously had the key to be hole punched.
-                        self.deleted_values.push(a);
-                    }
-                    ZeroLength => {}
+                if existing_value.key_id == value.key_id
+                    && matches!(existing_value.location, Nonzero(_))
+                {
+                    assert_eq!(existing_value.length(), value.length());
+                    // Renamed but the name is the same.
+                    return Ok(true);
                 }
+                // Schedule the value that previously had the key to be hole punched.
+                self.push_value_for_deletion(existing_value)?;
             }
         };
 
+        // The location guards against the key having been replaced by one that reused its id.
         let res: rusqlite::Result<ValueLength> = self
             .tx
             .prepare_cached(
-                "update keys set key=? where file_id=? and file_offset=?\
+                "update keys set key=? where key_id=? and file_id=? and file_offset=? \
                 returning value_length",
             )?
             .query_row(
-                params![new_key, value.file_id(), value.file_offset()],
+                params![new_key, value.key_id, value.file_id(), value.file_offset()],
                 |row| row.get(0),
             );
         match res {
@@ -342,27 +514,106 @@ where
         let inserted = self
             .tx
             .prepare_cached(
-                "insert into keys (key, file_id, file_offset, value_length)\
-                values (?, ?, ?, ?)",
+                "insert into keys (key, file_id, file_offset, value_length, expires, last_used, use_count, \
+                codec, uncompressed_length, nonce, checksums, content_hash, in_blocks) \
+                values (?, ?, ?, ?, ?, coalesce(?, cast(unixepoch('subsec')*1e3 as integer)), \
+                (select coalesce(min(use_count), 0) + 1 from keys), ?, ?, ?, ?, ?, ?)",
             )?
             .execute(rusqlite::params!(
                 pw.key,
                 file_id,
                 file_offset,
-                pw.value_length
+                pw.value_length,
+                pw.expires,
+                pw.last_used,
+                pw.compression.map(|compression| compression.codec),
+                pw.compression
+                    .map(|compression| compression.uncompressed_length),
+                pw.nonce,
+                pw.checksums,
+                pw.content_hash,
+                pw.in_blocks,
             ))?;
         assert_eq!(inserted, 1);
+        if pw.in_blocks {
+            let key_id = self.tx.last_insert_rowid();
+            let mut duplicated = blocks::insert_parts(&self.tx, key_id, &pw)?;
+            self.deleted_values.append(&mut duplicated);
+        }
+        self.sweep_expired |= pw.expires.is_some();
         if pw.value_length != 0 {
             self.altered_files.insert(pw.value_file_id);
         }
+        // Any write for a key completes a fetch that's in progress for it.
+        self.tx
+            .prepare_cached("delete from fetches where key=?")?
+            .execute([&pw.key])?;
+        Ok(())
+    }
+
+    /// Points a pending write at the existing value with the same content found before the commit,
+    /// if it's still there, and schedules the copy that was written to be hole punched.
+    pub(crate) fn dedup_value(&mut self, pw: &mut PendingWrite) -> Result<()> {
+        let Some(existing) = pw.duplicate_of.take() else {
+            return Ok(());
+        };
+        if !dedup::duplicate_unchanged(&self.tx, pw, &existing)? {
+            return Ok(());
+        }
+        let written = NonzeroValueLocation {
+            file_id: pw.value_file_id,
+            file_offset: pw.value_file_offset,
+            length: pw.value_length,
+        };
+        debug!(?written, ?existing, "deduplicated value");
+        pw.value_file_id = existing.file_id;
+        pw.value_file_offset = existing.file_offset;
+        self.deleted_values.push(written);
+        Ok(())
+    }
+
+    /// Tags key as being fetched by fetch_id. Returns false if another fetch for the key started
+    /// less than timeout ago.
+    pub(crate) fn try_start_fetch(
+        &mut self,
+        key: &[u8],
+        fetch_id: i64,
+        timeout: Duration,
+    ) -> rusqlite::Result<bool> {
+        let timeout_millis = i64::try_from(timeout.as_millis()).unwrap_or(i64::MAX);
+        let changed = self
+            .tx
+            .prepare_cached(
+                "insert into fetches (key, fetch_id) values (?, ?) \
+                on conflict (key) do update \
+                set fetch_id=excluded.fetch_id, started=excluded.started \
+                where started <= cast(unixepoch('subsec')*1e3 as integer) - ?",
+            )?
+            .execute(params![key, fetch_id, timeout_millis])?;
+        Ok(changed == 1)
+    }
+
+    /// Removes the fetch tag for key if it still belongs to fetch_id.
+    pub(crate) fn end_fetch(&mut self, key: &[u8], fetch_id: i64) -> rusqlite::Result<()> {
+        self.tx
+            .prepare_cached("delete from fetches where key=? and fetch_id=?")?
+            .execute(params![key, fetch_id])?;
         Ok(())
     }
 
-    fn push_value_for_deletion(&mut self, value: Value) {
-        match value.location {
-            Nonzero(location) => self.deleted_values.push(location),
-            ZeroLength => {}
+    /// Schedules the value of a deleted key to be hole punched, unless other keys still share it.
+    /// Values stored in blocks are punched as their blocks are released.
+    fn push_value_for_deletion(&mut self, value: Value) -> rusqlite::Result<()> {
+        let Nonzero(location) = value.location else {
+            return Ok(());
+        };
+        if value.in_blocks() {
+            return Ok(());
+        }
+        if !dedup::location_referenced(&self.tx, &location)? {
+            self.deleted_values.push(location);
         }
+        Ok(())
     }
 
     pub fn delete_key(&mut self, key: &[u8]) -> rusqlite::Result<Option<c_api::PossumStat>> {
@@ -377,33 +628,27 @@ where
             Err(QueryReturnedNoRows) => Ok(None),
             Ok(value) => {
                 let stat = value.as_ref().into();
-                self.push_value_for_deletion(value);
+                self.push_value_for_deletion(value)?;
                 Ok(Some(stat))
             }
             Err(err) => Err(err),
         }
     }
 
+    /// Deletes values in the order given by the Handle's eviction policy until at least
+    /// target_bytes of values have been freed.
     pub fn evict_values(&mut self, target_bytes: u64) -> Result<()> {
-        let mut stmt = self.tx.prepare_cached(&format!(
-            "delete from keys where key_id in (\
-                select key_id from keys order by last_used limit 1\
-            )\
-            returning {}",
-            value_columns_sql()
-        ))?;
-        let mut value_bytes_deleted = 0;
-        let mut values_deleted = vec![];
-        while value_bytes_deleted < target_bytes {
-            let value = stmt.query_row([], Value::from_row)?;
-            value_bytes_deleted += value.length();
-            info!("evicting {:?}", &value);
-            values_deleted.push(value);
-        }
-        drop(stmt);
-        for value in values_deleted {
-            self.push_value_for_deletion(value);
-        }
+        let limits = &self.handle.as_ref().instance_limits;
+        let mut evicted = eviction::evict_values(
+            &self.tx,
+            limits.eviction_policy,
+            target_bytes,
+            None,
+            limits.partial_eviction,
+        )?;
+        self.deleted_values.append(&mut evicted.locations);
+        self.evicted.count += evicted.count;
+        self.evicted.bytes += evicted.bytes;
         Ok(())
     }
 }
@@ -0,0 +1,228 @@
+//! Accounting for the disk space a directory uses, for enforcing limits.
+
+use rusqlite::OptionalExtension;
+
+use super::*;
+
+/// How disk usage is counted against the size limits in Limits.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub enum UsageAccounting {
+    /// The sum of value lengths. This is the original behaviour, and the cheapest.
+    #[default]
+    ValueLength,
+    /// Value lengths rounded up to the directory block size, plus the manifest and its WAL. This
+    /// is maintained incrementally in the manifest, so it's nearly as cheap as ValueLength. The
+    /// WAL only counts up to the length it's checkpointed at: readers that stay open hold up
+    /// checkpoints and the WAL grows past that, which evicting values can't fix.
+    BlockRounded,
+    /// The space allocated to every file in the directory, including holes that are yet to be
+    /// punched and snapshot clones that are still held. Extents shared by clones are counted for
+    /// each file, and the WAL is capped like BlockRounded. Walking the directory is too slow to do
+    /// on every commit, so commits count usage like BlockRounded, and the background evictor
+    /// measures it every MEASURED_USAGE_REFRESH_INTERVAL and evicts to the limits.
+    Measured,
+}
+
+/// How long the background evictor reuses a measurement of the directory for Measured accounting.
+pub(crate) const MEASURED_USAGE_REFRESH_INTERVAL: Duration = Duration::from_secs(10);
+
+impl UsageAccounting {
+    /// The name used in the manifest settings and on the command line.
+    pub fn name(self) -> &'static str {
+        use UsageAccounting::*;
+        match self {
+            ValueLength => "value-length",
+            BlockRounded => "block-rounded",
+            Measured => "measured",
+        }
+    }
+
+    /// The accounting used while committing, which has to be cheap since it holds the manifest
+    /// write lock.
+    pub(crate) fn for_commit(self) -> Self {
+        match self {
+            UsageAccounting::Measured => UsageAccounting::BlockRounded,
+            accounting => accounting,
+        }
+    }
+}
+
+impl Display for UsageAccounting {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl std::str::FromStr for UsageAccounting {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        use UsageAccounting::*;
+        [ValueLength, BlockRounded, Measured]
+            .into_iter()
+            .find(|accounting| accounting.name() == s)
+            .ok_or_else(|| anyhow!("unknown usage accounting {s:?}"))
+    }
+}
+
+impl ToSql for UsageAccounting {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        self.name().to_sql()
+    }
+}
+
+impl FromSql for UsageAccounting {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        value
+            .as_str()?
+            .parse()
+            .map_err(|err: anyhow::Error| FromSqlError::Other(err.into()))
+    }
+}
+
+/// Disk usage as counted by a UsageAccounting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DiskUsage {
+    /// The part of the usage that evicting values can reduce.
+    pub values: u64,
+    /// Everything else, such as the manifest.
+    pub overhead: u64,
+}
+
+impl DiskUsage {
+    pub fn total(&self) -> u64 {
+        self.values + self.overhead
+    }
+}
+
+/// Returns the disk usage of dir according to accounting, as of the transaction.
+pub(crate) fn disk_usage(
+    tx: &rusqlite::Transaction<'_>,
+    dir: &Dir,
+    accounting: UsageAccounting,
+) -> Result<DiskUsage> {
+    use UsageAccounting::*;
+    let sum = |key: &str| -> rusqlite::Result<u64> {
+        tx.prepare_cached("select value from sums where key=?")?
+            .query_row([key], |row| row.get(0))
+    };
+    Ok(match accounting {
+        ValueLength => DiskUsage {
+            values: sum("value_length")?,
+            overhead: 0,
+        },
+        BlockRounded => {
+            let pages: u64 = tx
+                .prepare_cached(
+                    "select page_count*page_size from pragma_page_count(), pragma_page_size()",
+                )?
+                .query_row([], |row| row.get(0))?;
+            let wal = match fs::metadata(wal_path(dir)) {
+                Ok(metadata) => metadata.len(),
+                Err(err) if err.kind() == ErrorKind::NotFound => 0,
+                Err(err) => return Err(err.into()),
+            };
+            DiskUsage {
+                values: sum("block_rounded_value_length")?,
+                overhead: pages + min(wal, max_wal_len(tx)?),
+            }
+        }
+        Measured => {
+            let total = measure_dir(dir, max_wal_len(tx)?)?;
+            // Only values still in the manifest can be evicted.
+            let values = sum("value_length")?.min(total);
+            DiskUsage {
+                values,
+                overhead: total - values,
+            }
+        }
+    })
+}
+
+fn wal_path(dir: &Dir) -> PathBuf {
+    dir.path().join(format!("{}-wal", MANIFEST_DB_FILE_NAME))
+}
+
+/// The most of the manifest WAL that's counted as usage: the length it's checkpointed at.
+fn max_wal_len(tx: &rusqlite::Transaction<'_>) -> rusqlite::Result<u64> {
+    let pages: u64 = tx.pragma_query_value(None, "wal_autocheckpoint", |row| row.get(0))?;
+    let page_size: u64 = tx.pragma_query_value(None, "page_size", |row| row.get(0))?;
+    Ok(pages * page_size)
+}
+
+/// The space allocated to the manifest, values files and snapshots in dir, counting at most
+/// max_wal of the manifest WAL.
+fn measure_dir(dir: &Dir, max_wal: u64) -> Result<u64> {
+    let wal_path = wal_path(dir);
+    let mut total = 0;
+    for entry in walk_dir(dir)? {
+        use walk::EntryType::*;
+        if !matches!(entry.entry_type, ManifestFile | ValuesFile | SnapshotValue) {
+            continue;
+        }
+        match path_disk_allocation(&entry.path) {
+            Ok(allocated) if entry.path == wal_path => total += min(allocated, max_wal),
+            Ok(allocated) => total += allocated,
+            // Snapshots and punched files can disappear while we walk.
+            Err(err) if err.kind() == ErrorKind::NotFound => {}
+            Err(err) => return Err(err).with_context(|| format!("{:?}", entry.path)),
+        }
+    }
+    Ok(total)
+}
+
+/// Whether limits has a free space limit that eviction can do anything about.
+pub(crate) fn checks_free_space(limits: &Limits) -> bool {
+    !limits.disable_hole_punching
+        && (limits.min_free_bytes.is_some() || limits.min_free_percent.is_some())
+}
+
+/// Returns how many bytes need to be freed on the filesystem containing dir to satisfy the free
+/// space limits. Bytes waiting to be punched are counted as free already.
+pub(crate) fn free_space_shortfall(
+    dir: &Dir,
+    limits: &Limits,
+    pending_punch_bytes: impl FnOnce() -> Result<u64>,
+) -> Result<u64> {
+    if !checks_free_space(limits) {
+        return Ok(0);
+    }
+    let pending_punch_bytes = pending_punch_bytes()?;
+    let FreeSpace { available, total } = path_free_space(dir.path())?;
+    let required = max(
+        limits.min_free_bytes.unwrap_or_default(),
+        limits
+            .min_free_percent
+            .map_or(0, |percent| total / 100 * percent as u64),
+    );
+    Ok(required.saturating_sub(available.saturating_add(pending_punch_bytes)))
+}
+
+/// Records the block size used to round values for BlockRounded accounting, recomputing the sum if
+/// it has changed.
+pub(crate) fn set_block_size(
+    tx: &rusqlite::Transaction<'_>,
+    block_size: u64,
+) -> rusqlite::Result<()> {
+    let stored: Option<u64> = tx
+        .prepare_cached("select value from settings where name='block_size'")?
+        .query_row([], |row| row.get(0))
+        .optional()?;
+    if stored == Some(block_size) {
+        return Ok(());
+    }
+    tx.prepare_cached("replace into settings (name, value) values ('block_size', ?)")?
+        .execute([block_size])?;
+    tx.prepare_cached(
+        "update sums set value=(\
+            select coalesce(sum((value_length+?1-1)/?1*?1), 0) \
+            from (select distinct file_id, file_offset, value_length from keys \
+                where key_id not in (select key_id from value_parts))\
+        ) + (\
+            select coalesce(sum((block_length+?1-1)/?1*?1), 0) from blocks\
+        ) where key='block_rounded_value_length'",
+    )?
+    .execute([block_size])?;
+    Ok(())
+}
@@ -27,6 +27,10 @@ pub enum EntryType {
     SnapshotDir,
     SnapshotValue,
     ValuesFile,
+    /// Left over from testing whether the directory supports file cloning.
+    CloneTestFile,
+    /// Synchronizes readers with hole punching. See clone_lock.
+    CloneLockFile,
     Unknown,
 }
 
@@ -67,6 +71,10 @@ where
             ManifestFile
         } else if file_name.starts_with(VALUES_FILE_NAME_PREFIX) && file_type.is_file() {
             ValuesFile
+        } else if file_name.starts_with(CLONE_TEST_FILE_NAME_PREFIX) && file_type.is_file() {
+            CloneTestFile
+        } else if file_name == clone_lock::CLONE_LOCK_FILE_NAME && file_type.is_file() {
+            CloneLockFile
         } else if file_name.starts_with(SNAPSHOT_DIR_NAME_PREFIX) && file_type.is_dir() {
             ok.extend(walk_snapshot_dir(std_entry.path())?);
             SnapshotDir
@@ -15,6 +15,7 @@ use std::time::{Duration, Instant};
 use anyhow::{anyhow, bail, Context, Result};
 use fdlimit::raise_fd_limit;
 use itertools::Itertools;
+use positioned_io::ReadAt;
 use possum::concurrency::thread;
 use possum::testing::*;
 use possum::walk::{walk_dir, EntryType};
@@ -491,6 +492,1609 @@ fn reads_update_last_used() -> Result<()> {
     )
 }
 
+/// The bytes allocated to the Handle's values files.
+fn allocated_values_bytes(handle: &Handle) -> Result<u64> {
+    let mut allocated = 0;
+    for entry in handle.walk_dir()? {
+        if entry.entry_type != EntryType::ValuesFile {
+            continue;
+        }
+        let mut file = std::fs::File::open(&entry.path)?;
+        for region in possum::sys::seekhole::Iter::new(&mut file) {
+            let region = region?;
+            if matches!(region.region_type, possum::sys::seekhole::RegionType::Data) {
+                allocated += region.length();
+            }
+        }
+    }
+    Ok(allocated)
+}
+
+#[test]
+fn expired_keys_are_missing() -> Result<()> {
+    check_concurrency(
+        || {
+            let tempdir = tempdir()?;
+            let handle = Handle::new(tempdir.path().to_owned())?;
+            let expired = Timestamp::from(std::time::SystemTime::now() - Duration::from_secs(1));
+            let later = Timestamp::after(Duration::from_secs(3600));
+            handle.single_write_from_with_expiry(
+                "stale".as_bytes().to_vec(),
+                "old".as_bytes(),
+                Some(expired),
+            )?;
+            handle.single_write_from_with_expiry(
+                "fresh".as_bytes().to_vec(),
+                "new".as_bytes(),
+                Some(later),
+            )?;
+            assert!(handle.read_single("stale".as_bytes())?.is_none());
+            let fresh = handle
+                .read_single("fresh".as_bytes())?
+                .expect("unexpired key should exist");
+            // The manifest stores milliseconds.
+            assert_eq!(
+                fresh.expires().map(|ts| ts.and_utc().timestamp_millis()),
+                Some(later.and_utc().timestamp_millis())
+            );
+            let keys: Vec<_> = handle
+                .list_items("".as_bytes())?
+                .into_iter()
+                .map(|item| item.key)
+                .collect();
+            assert_eq!(keys, vec!["fresh".as_bytes()]);
+            // Expired values are reclaimed in the background without further writes.
+            let block_size = handle.block_size() as usize;
+            handle.single_write_from_with_expiry(
+                "brief".as_bytes().to_vec(),
+                &*vec![1; 4 * block_size],
+                Some(Timestamp::after(Duration::from_millis(100))),
+            )?;
+            let written = allocated_values_bytes(&handle)?;
+            let deadline = Instant::now() + Duration::from_secs(10);
+            while allocated_values_bytes(&handle)? + 3 * block_size as u64 > written {
+                assert!(Instant::now() < deadline, "expired value wasn't punched");
+                sleep(Duration::from_millis(10));
+            }
+            assert!(handle.read_single("brief".as_bytes())?.is_none());
+            Ok(())
+        },
+        100,
+    )
+}
+
+#[test]
+fn get_or_fetch_singleflight() -> Result<()> {
+    check_concurrency(
+        || {
+            let tempdir = tempdir()?;
+            let dir = tempdir.path().to_owned();
+            // Create a handle first to get a clean initialization.
+            let _handle = Handle::new(dir.clone())?;
+            let fetches = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+            let key = "fetched".as_bytes();
+            let threads: Vec<_> = (0..4)
+                .map(|_| {
+                    let dir = dir.clone();
+                    let fetches = fetches.clone();
+                    thread::spawn(move || -> Result<()> {
+                        let handle = Handle::new(dir)?;
+                        let value = handle.get_or_fetch(key, |value| {
+                            fetches.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                            sleep(Duration::from_millis(50));
+                            value.write_all("origin".as_bytes())?;
+                            Ok(())
+                        })?;
+                        value.view(|bytes| assert_eq!(bytes, "origin".as_bytes()))?;
+                        Ok(())
+                    })
+                })
+                .collect();
+            for thread in threads {
+                thread.join().unwrap()?;
+            }
+            assert_eq!(fetches.load(std::sync::atomic::Ordering::SeqCst), 1);
+            Ok(())
+        },
+        10,
+    )
+}
+
+#[test]
+fn get_or_fetch_abandoned() -> Result<()> {
+    check_concurrency(
+        || {
+            let tempdir = tempdir()?;
+            let handle = Handle::new(tempdir.path().to_owned())?;
+            let key = "fetched".as_bytes();
+            // A failed fetch clears its tag, so the next fetch doesn't wait.
+            assert!(handle
+                .get_or_fetch(key, |_| Err(anyhow!("origin unavailable")))
+                .is_err());
+            let started = Instant::now();
+            handle.get_or_fetch(key, |value| Ok(value.write_all("a".as_bytes())?))?;
+            assert!(started.elapsed() < Duration::from_secs(1));
+            handle.delete_prefix(key)?;
+            // A panicking fetch leaves its tag behind, like a crashed process would.
+            let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                handle.get_or_fetch(key, |_| panic!("fetcher crashed"))
+            }));
+            assert!(panicked.is_err());
+            let timeout = Duration::from_millis(100);
+            let started = Instant::now();
+            let value = handle.get_or_fetch_with_timeout(key, timeout, |value| {
+                Ok(value.write_all("b".as_bytes())?)
+            })?;
+            // The tag was written just before started, so allow for some slack.
+            assert!(started.elapsed() >= timeout / 2);
+            value.view(|bytes| assert_eq!(bytes, "b".as_bytes()))?;
+            Ok(())
+        },
+        10,
+    )
+}
+
+fn remaining_keys(handle: &Handle) -> Result<Vec<String>> {
+    Ok(handle
+        .list_items("".as_bytes())?
+        .into_iter()
+        .map(|item| String::from_utf8(item.key).unwrap())
+        .collect())
+}
+
+#[test]
+fn eviction_policies() -> Result<()> {
+    let write = |handle: &Handle, key: &str, len: usize| -> Result<()> {
+        handle.single_write_from(key.as_bytes().to_vec(), &*vec![0; len])?;
+        // Make sure timestamps are distinct.
+        std::thread::sleep(2 * LAST_USED_RESOLUTION);
+        Ok(())
+    };
+    let touch = |handle: &Handle, key: &str| -> Result<()> {
+        handle.read_single(key.as_bytes())?.unwrap();
+        std::thread::sleep(2 * LAST_USED_RESOLUTION);
+        Ok(())
+    };
+    let new_handle = |tempdir: &tempfile::TempDir, policy, max| -> Result<Handle> {
+        let mut handle = Handle::new(tempdir.path().to_owned())?;
+        handle.set_instance_limits(Limits {
+            max_value_length_sum: Some(max),
+            eviction_policy: policy,
+            ..Default::default()
+        })?;
+        Ok(handle)
+    };
+    for (policy, evicted) in [(EvictionPolicy::Lru, "b"), (EvictionPolicy::Lfu, "a")] {
+        let tempdir = tempdir()?;
+        let handle = new_handle(&tempdir, policy, 30)?;
+        for key in ["a", "b", "c"] {
+            write(&handle, key, 10)?;
+        }
+        // b is used the most, but a and c are used most recently.
+        for key in ["b", "b", "b", "a", "c"] {
+            touch(&handle, key)?;
+        }
+        write(&handle, "d", 10)?;
+        let mut expected: Vec<_> = ["a", "b", "c", "d"]
+            .into_iter()
+            .filter(|key| *key != evicted)
+            .collect();
+        expected.sort();
+        assert_eq!(remaining_keys(&handle)?, expected, "{:?}", policy);
+    }
+    for (policy, expected) in [
+        (EvictionPolicy::Lru, ["a", "c", "d"].as_slice()),
+        (EvictionPolicy::SizeWeighted, ["b", "c", "d"].as_slice()),
+    ] {
+        let tempdir = tempdir()?;
+        let handle = new_handle(&tempdir, policy, 1020)?;
+        write(&handle, "b", 10)?;
+        write(&handle, "c", 10)?;
+        // a is used more recently than b, but it's much bigger.
+        write(&handle, "a", 1000)?;
+        write(&handle, "d", 10)?;
+        assert_eq!(remaining_keys(&handle)?, expected, "{:?}", policy);
+    }
+    Ok(())
+}
+
+#[test]
+fn eviction_across_victim_batches() -> Result<()> {
+    let tempdir = tempdir()?;
+    let mut handle = Handle::new(tempdir.path().to_owned())?;
+    let mut writer = handle.new_writer()?;
+    for i in 0..600 {
+        let mut value = writer.new_value().begin()?;
+        value.write_all(&[0; 10])?;
+        writer.stage_write(format!("{i:03}").into_bytes(), value)?;
+    }
+    writer.commit()?;
+    std::thread::sleep(2 * LAST_USED_RESOLUTION);
+    handle.set_instance_limits(Limits {
+        max_value_length_sum: Some(1000),
+        eviction_policy: EvictionPolicy::SizeWeighted,
+        ..Default::default()
+    })?;
+    // This evicts more values than are selected at a time.
+    handle.single_write_from(b"z".to_vec(), &*vec![0; 10])?;
+    let remaining = remaining_keys(&handle)?;
+    assert_eq!(remaining.len(), 100);
+    assert!(remaining.contains(&"z".to_owned()));
+    Ok(())
+}
+
+/// Eviction stops when there's nothing left to evict, even if the usage sums say otherwise.
+#[test]
+fn eviction_with_drifted_sums() -> Result<()> {
+    let tempdir = tempdir()?;
+    let dir = tempdir.path().to_owned();
+    Handle::new(dir.clone())?.single_write_from(b"a".to_vec(), &*vec![0; 10])?;
+    rusqlite::Connection::open(dir.join(MANIFEST_DB_FILE_NAME))?.execute(
+        "update sums set value=value+1000 where key='value_length'",
+        [],
+    )?;
+    let mut handle = Handle::new(dir)?;
+    handle.set_instance_limits(Limits {
+        max_value_length_sum: Some(100),
+        ..Default::default()
+    })?;
+    handle.single_write_from(b"b".to_vec(), &*vec![0; 10])?;
+    assert!(remaining_keys(&handle)?.is_empty());
+    Ok(())
+}
+
+#[test]
+fn background_eviction_watermarks() -> Result<()> {
+    let tempdir = tempdir()?;
+    let mut handle = Handle::new(tempdir.path().to_owned())?;
+    handle.set_instance_limits(Limits {
+        high_watermark: Some(30),
+        low_watermark: Some(10),
+        ..Default::default()
+    })?;
+    for key in ["a", "b", "c"] {
+        handle.single_write_from(key.as_bytes().to_vec(), &*vec![0; 10])?;
+        sleep(2 * LAST_USED_RESOLUTION);
+    }
+    // The high watermark hasn't been exceeded.
+    assert_eq!(remaining_keys(&handle)?, ["a", "b", "c"]);
+    handle.single_write_from(b"d".to_vec(), &*vec![0; 10])?;
+    let started = Instant::now();
+    while remaining_keys(&handle)? != ["d"] {
+        assert!(started.elapsed() < Duration::from_secs(10));
+        sleep(Duration::from_millis(1));
+    }
+    Ok(())
+}
+
+#[test]
+fn persistent_limits() -> Result<()> {
+    let tempdir = tempdir()?;
+    let limits = Limits {
+        max_value_length_sum: Some(20),
+        eviction_policy: EvictionPolicy::Lfu,
+        ..Default::default()
+    };
+    Handle::new(tempdir.path().to_owned())?.set_persistent_limits(limits.clone())?;
+    let mut handle = Handle::new(tempdir.path().to_owned())?;
+    assert_eq!(handle.persistent_limits()?, limits);
+    for key in ["a", "b", "c"] {
+        handle.single_write_from(key.as_bytes().to_vec(), &*vec![0; 10])?;
+        sleep(2 * LAST_USED_RESOLUTION);
+    }
+    assert_eq!(remaining_keys(&handle)?, ["b", "c"]);
+    // Instance limits don't change what's stored.
+    handle.set_instance_limits(Default::default())?;
+    handle.single_write_from(b"d".to_vec(), &*vec![0; 10])?;
+    assert_eq!(remaining_keys(&handle)?, ["b", "c", "d"]);
+    assert_eq!(
+        Handle::new(tempdir.path().to_owned())?.persistent_limits()?,
+        limits
+    );
+    Ok(())
+}
+
+#[test]
+fn usage_accounting() -> Result<()> {
+    let tempdir = tempdir()?;
+    let mut handle = Handle::new(tempdir.path().to_owned())?;
+    let block_size = handle.block_size();
+    for key in ["a", "b", "c"] {
+        handle.single_write_from(key.as_bytes().to_vec(), &*vec![0; 10])?;
+    }
+    assert_eq!(
+        handle.disk_usage()?,
+        DiskUsage {
+            values: 30,
+            overhead: 0
+        }
+    );
+    handle.set_instance_limits(Limits {
+        usage_accounting: UsageAccounting::BlockRounded,
+        ..Default::default()
+    })?;
+    let usage = handle.disk_usage()?;
+    assert_eq!(usage.values, 3 * block_size);
+    let manifest_len = std::fs::metadata(tempdir.path().join(MANIFEST_DB_FILE_NAME))?.len();
+    assert!(usage.overhead >= manifest_len);
+    handle.single_delete(b"b")?;
+    assert_eq!(handle.disk_usage()?.values, 2 * block_size);
+    handle.set_instance_limits(Limits {
+        usage_accounting: UsageAccounting::Measured,
+        ..Default::default()
+    })?;
+    let usage = handle.disk_usage()?;
+    assert_eq!(usage.values, 20);
+    assert!(usage.total() >= manifest_len);
+    Ok(())
+}
+
+/// A reader that holds up checkpoints makes the manifest WAL grow, which evicting values can't
+/// undo, so the growth doesn't count against the limits.
+#[test]
+fn usage_accounting_wal_growth() -> Result<()> {
+    let tempdir = tempdir()?;
+    let dir = tempdir.path();
+    let mut handle = Handle::new(dir.to_owned())?;
+    let block_size = handle.block_size();
+    handle.single_write_from(b"a".to_vec(), &*vec![0; 10])?;
+    let mut reader = rusqlite::Connection::open(dir.join(MANIFEST_DB_FILE_NAME))?;
+    // The WAL is checkpointed every 1000 pages by default.
+    let page_size: u64 = reader.pragma_query_value(None, "page_size", |row| row.get(0))?;
+    let max_wal_len = 1000 * page_size;
+    let reader_tx = reader.transaction()?;
+    reader_tx.query_row("select count(*) from keys", [], |_| Ok(()))?;
+    handle.set_instance_limits(Limits {
+        usage_accounting: UsageAccounting::BlockRounded,
+        ..Default::default()
+    })?;
+    let overhead = handle.disk_usage()?.overhead;
+    handle.set_instance_limits(Limits {
+        usage_accounting: UsageAccounting::BlockRounded,
+        max_value_length_sum: Some(2 * block_size + overhead + max_wal_len),
+        ..Default::default()
+    })?;
+    let wal_path = dir.join(format!("{}-wal", MANIFEST_DB_FILE_NAME));
+    let mut i: u8 = 0;
+    while std::fs::metadata(&wal_path)?.len() < 2 * max_wal_len {
+        handle.single_write_from(b"b".to_vec(), &*vec![i; 10])?;
+        i = i.wrapping_add(1);
+    }
+    assert!(handle.disk_usage()?.overhead <= overhead + max_wal_len);
+    assert_eq!(remaining_keys(&handle)?, ["a", "b"]);
+    drop(reader_tx);
+    Ok(())
+}
+
+#[test]
+fn free_space_eviction() -> Result<()> {
+    let tempdir = tempdir()?;
+    let mut handle = Handle::new(tempdir.path().to_owned())?;
+    for key in ["a", "b", "c"] {
+        handle.single_write_from(key.as_bytes().to_vec(), &*vec![0; 10])?;
+    }
+    // This can't be satisfied, but hole punching is disabled so eviction wouldn't help.
+    let limits = Limits {
+        min_free_bytes: Some(u64::MAX),
+        disable_hole_punching: true,
+        ..Default::default()
+    };
+    handle.set_instance_limits(limits.clone())?;
+    handle.single_write_from(b"d".to_vec(), &*vec![0; 10])?;
+    assert_eq!(remaining_keys(&handle)?, ["a", "b", "c", "d"]);
+    // The background evictor should notice without a commit.
+    handle.set_instance_limits(Limits {
+        disable_hole_punching: false,
+        ..limits
+    })?;
+    let started = Instant::now();
+    while !remaining_keys(&handle)?.is_empty() {
+        assert!(started.elapsed() < Duration::from_secs(10));
+        sleep(Duration::from_millis(1));
+    }
+    // Commits evict synchronously.
+    handle.single_write_from(b"e".to_vec(), &*vec![0; 10])?;
+    assert_eq!(remaining_keys(&handle)?, Vec::<String>::new());
+    Ok(())
+}
+
+#[test]
+fn check_and_repair() -> Result<()> {
+    let tempdir = tempdir()?;
+    let dir = tempdir.path().to_owned();
+    let block_size;
+    let value;
+    let deleted;
+    {
+        let mut handle = Handle::new(dir.clone())?;
+        handle.set_instance_limits(Limits {
+            disable_hole_punching: true,
+            ..Default::default()
+        })?;
+        block_size = handle.block_size();
+        for key in ["a", "b", "c"] {
+            handle
+                .single_write_from(key.as_bytes().to_vec(), &*vec![1; 3 * block_size as usize])?;
+        }
+        value = handle.list_items(b"a")?.remove(0).value;
+        deleted = handle.list_items(b"b")?.remove(0).value;
+        handle.single_delete(b"b")?;
+        assert!(handle.check(Default::default())?.is_clean());
+    }
+    let values_path = dir.join(value.file_id().unwrap().values_file_path());
+    let values_file = OpenOptions::new().write(true).open(&values_path)?;
+    let hole_offset = value.file_offset().unwrap() + block_size;
+    possum::sys::punchfile(&values_file, hole_offset, block_size)?;
+    drop(values_file);
+    std::fs::write(dir.join(".clone_test-deadbeef"), [])?;
+    let handle = Handle::new(dir)?;
+    let report = handle.check(Default::default())?;
+    assert!(!report.is_clean());
+    let kinds: Vec<_> = report.issues.into_iter().map(|issue| issue.kind).collect();
+    assert!(kinds.contains(&IssueKind::HoleInValue {
+        key: b"a".to_vec(),
+        location: value.location.into_non_zero().unwrap(),
+        hole_offset,
+    }));
+    assert!(kinds.contains(&IssueKind::UnreferencedData {
+        file_id: *value.file_id().unwrap(),
+        offset: deleted.file_offset().unwrap(),
+        length: 3 * block_size,
+    }));
+    assert!(kinds
+        .iter()
+        .any(|kind| matches!(kind, IssueKind::StrayCloneTest { .. })));
+    assert_eq!(kinds.len(), 3, "{:?}", kinds);
+    assert!(handle.check(CheckOptions { repair: true })?.is_clean());
+    assert_eq!(remaining_keys(&handle)?, ["c"]);
+    // The rest of the damaged value can be punched now that its key is gone.
+    assert!(handle.check(CheckOptions { repair: true })?.is_clean());
+    let report = handle.check(Default::default())?;
+    assert!(report.issues.is_empty(), "{:?}", report);
+    Ok(())
+}
+
+#[test]
+fn check_hole_across_values() -> Result<()> {
+    let tempdir = tempdir()?;
+    let dir = tempdir.path().to_owned();
+    let mut handle = Handle::new(dir.clone())?;
+    handle.set_instance_limits(Limits {
+        disable_hole_punching: true,
+        ..Default::default()
+    })?;
+    let block_size = handle.block_size();
+    for key in ["a", "b"] {
+        handle.single_write_from(key.as_bytes().to_vec(), &*vec![1; 3 * block_size as usize])?;
+    }
+    let a = handle.read_single(b"a")?.unwrap();
+    let b = handle.read_single(b"b")?.unwrap();
+    assert_eq!(b.file_offset(), Some(a.file_offset().unwrap() + a.length()));
+    // One hole covering the end of a and the start of b.
+    let hole_offset = b.file_offset().unwrap() - block_size;
+    let values_file = OpenOptions::new()
+        .write(true)
+        .open(dir.join(a.file_id().unwrap().values_file_path()))?;
+    possum::sys::punchfile(&values_file, hole_offset, 2 * block_size)?;
+    drop(values_file);
+    // The writing Handle owns the values file, so check from another.
+    drop(handle);
+    let kinds: Vec<_> = Handle::new(dir)?
+        .check(Default::default())?
+        .issues
+        .into_iter()
+        .map(|issue| issue.kind)
+        .collect();
+    let expected: Vec<_> = [(b"a", &a), (b"b", &b)]
+        .into_iter()
+        .map(|(key, value)| IssueKind::HoleInValue {
+            key: key.to_vec(),
+            location: value.location.into_non_zero().unwrap(),
+            hole_offset,
+        })
+        .collect();
+    assert_eq!(kinds, expected);
+    Ok(())
+}
+
+#[test]
+fn compaction() -> Result<()> {
+    let tempdir = tempdir()?;
+    let mut handle = Handle::new(tempdir.path().to_owned())?;
+    handle.set_instance_limits(Limits {
+        disable_hole_punching: true,
+        ..Default::default()
+    })?;
+    let block_size = handle.block_size() as usize;
+    for (key, byte) in [("a", 1), ("b", 2), ("c", 3), ("d", 4)] {
+        handle.single_write_from(key.as_bytes().to_vec(), &*vec![byte; 3 * block_size])?;
+    }
+    let old_file_id = *handle.read_single(b"d")?.unwrap().file_id().unwrap();
+    // Nothing to reclaim yet.
+    let report = handle.compact(Default::default())?;
+    assert!(report.files_compacted.is_empty(), "{:?}", report);
+    for key in ["a", "b", "c"] {
+        handle.single_delete(key.as_bytes())?;
+    }
+    let report = handle.compact(Default::default())?;
+    assert_eq!(report.files_compacted, [old_file_id]);
+    assert_eq!(report.files_removed, [old_file_id]);
+    assert_eq!(report.values_moved, 1);
+    assert_eq!(report.bytes_moved, 3 * block_size as u64);
+    let value = handle.read_single(b"d")?.unwrap();
+    assert_ne!(value.file_id(), Some(&old_file_id));
+    value.view(|bytes| assert_eq!(bytes, vec![4; 3 * block_size]))?;
+    assert!(!tempdir.path().join(old_file_id.values_file_path()).exists());
+    let report = handle.check(Default::default())?;
+    assert!(report.issues.is_empty(), "{:?}", report);
+    Ok(())
+}
+
+#[test]
+fn durability_levels() -> Result<()> {
+    let tempdir = tempdir()?;
+    let dir = tempdir.path().to_owned();
+    for durability in [Durability::None, Durability::Manifest, Durability::Full] {
+        let mut handle = Handle::new(dir.clone())?;
+        handle.set_durability(durability)?;
+        assert_eq!(handle.durability(), durability);
+        let key = durability.name().as_bytes();
+        handle.single_write_from(key.to_vec(), key)?;
+        let mut writer = handle.new_writer()?;
+        let mut value = writer.new_value().begin()?;
+        value.write_all(b"batched")?;
+        writer.stage_write(key.to_vec(), value)?;
+        writer.commit()?;
+        // Only Full syncs values files.
+        assert_eq!(
+            handle.stats().values_file_syncs > 0,
+            durability == Durability::Full
+        );
+    }
+    let handle = Handle::new(dir)?;
+    assert_eq!(remaining_keys(&handle)?, ["full", "manifest", "none"]);
+    handle
+        .read_single(b"full")?
+        .unwrap()
+        .view(|bytes| assert_eq!(bytes, b"batched"))?;
+    Ok(())
+}
+
+#[test]
+fn recover_uncommitted_tails() -> Result<()> {
+    let tempdir = tempdir()?;
+    let dir = tempdir.path().to_owned();
+    let values_path;
+    let value_end;
+    {
+        let handle = Handle::new(dir.clone())?;
+        handle.single_write_from(b"a".to_vec(), &*vec![1; 100])?;
+        let value = handle.read_single(b"a")?.unwrap();
+        values_path = dir.join(value.file_id().unwrap().values_file_path());
+        value_end = value.file_offset().unwrap() + value.length();
+        // This Handle's exclusive file still owns the tail.
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(&values_path)?
+            .write_all(&[2; 50])?;
+        assert_eq!(handle.recover_tails()?.bytes_reclaimed, 0);
+    }
+    assert_eq!(std::fs::metadata(&values_path)?.len(), value_end + 50);
+    // Opening a Handle recovers tails that nobody owns anymore, in the background once it has
+    // been open a few seconds.
+    let handle = Handle::new(dir)?;
+    let deadline = Instant::now() + Duration::from_secs(20);
+    while std::fs::metadata(&values_path)?.len() != value_end {
+        assert!(Instant::now() < deadline, "tail wasn't recovered");
+        sleep(Duration::from_millis(10));
+    }
+    assert_eq!(handle.recover_tails()?.bytes_reclaimed, 0);
+    handle
+        .read_single(b"a")?
+        .unwrap()
+        .view(|bytes| assert_eq!(bytes, vec![1; 100]))?;
+    Ok(())
+}
+
+/// Handles can write as soon as they're opened, while other Handles on the directory are opening
+/// and writing.
+#[test]
+fn write_on_open_under_contention() -> Result<()> {
+    let tempdir = tempdir()?;
+    let dir = tempdir.path().to_owned();
+    // Creating the manifest isn't safe to do concurrently.
+    Handle::new(dir.clone())?;
+    let threads = (0..8)
+        .map(|i| {
+            let dir = dir.clone();
+            thread::spawn(move || -> Result<()> {
+                let key = format!("{i}").into_bytes();
+                let moved = format!("moved{i}").into_bytes();
+                for _ in 0..5 {
+                    let mut handle = Handle::new(dir.clone())?;
+                    handle.set_instance_limits(Default::default())?;
+                    handle.single_write_from(key.clone(), &*vec![1; 10])?;
+                    handle.move_prefix(&key, &moved)?;
+                    handle.single_delete(&moved)?;
+                    handle.single_write_from(key.clone(), &*vec![2; 10])?;
+                    handle.delete_prefix(&key)?;
+                }
+                Ok(())
+            })
+        })
+        .collect_vec();
+    for thread in threads {
+        thread.join().unwrap()?;
+    }
+    Ok(())
+}
+
+#[test]
+fn recover_tails_keeps_staged_values() -> Result<()> {
+    let tempdir = tempdir()?;
+    let handle = Handle::new(tempdir.path().to_owned())?;
+    let mut writer = handle.new_writer()?;
+    let mut value = writer.new_value().begin()?;
+    value.write_all(&[1; 100])?;
+    writer.stage_write(b"a".to_vec(), value)?;
+    // This reuses the staged value's file, and is dropped without being staged.
+    let mut value = writer.new_value().begin()?;
+    value.write_all(&[2; 50])?;
+    drop(value);
+    assert_eq!(handle.recover_tails()?.bytes_reclaimed, 0);
+    writer.commit()?;
+    handle
+        .read_single(b"a")?
+        .unwrap()
+        .view(|bytes| assert_eq!(bytes, vec![1; 100]))?;
+    Ok(())
+}
+
+/// Schema object names, and the keys table's column names.
+fn manifest_schema(dir: &std::path::Path) -> Result<(Vec<String>, Vec<String>)> {
+    let conn = rusqlite::Connection::open(dir.join(MANIFEST_DB_FILE_NAME))?;
+    let objects = conn
+        .prepare("select type || ' ' || name from sqlite_master order by 1")?
+        .query_map([], |row| row.get(0))?
+        .collect::<rusqlite::Result<_>>()?;
+    let columns = conn
+        .prepare("select name from pragma_table_info('keys') order by 1")?
+        .query_map([], |row| row.get(0))?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok((objects, columns))
+}
+
+/// Replaces the manifest with one from the version 3 schema with the same keys, and the given
+/// user version.
+fn replace_with_v3_manifest(dir: &std::path::Path, user_version: u32) -> Result<()> {
+    let path = dir.join(MANIFEST_DB_FILE_NAME);
+    let columns = "key, file_id, file_offset, value_length, last_used";
+    let rows: Vec<[rusqlite::types::Value; 5]> = {
+        let conn = rusqlite::Connection::open(&path)?;
+        let mut stmt = conn.prepare(&format!("select {columns} from keys"))?;
+        let rows = stmt.query_map([], |row| {
+            Ok([
+                row.get(0)?,
+                row.get(1)?,
+                row.get(2)?,
+                row.get(3)?,
+                row.get(4)?,
+            ])
+        })?;
+        rows.collect::<rusqlite::Result<_>>()?
+    };
+    for suffix in ["", "-wal", "-shm"] {
+        let _ = std::fs::remove_file(dir.join(format!("{MANIFEST_DB_FILE_NAME}{suffix}")));
+    }
+    let conn = rusqlite::Connection::open(&path)?;
+    conn.execute_batch(include_str!("manifest-v3.sql"))?;
+    for row in rows {
+        conn.execute(
+            &format!("insert into keys ({columns}) values (?, ?, ?, ?, ?)"),
+            rusqlite::params_from_iter(row),
+        )?;
+    }
+    conn.pragma_update(None, "user_version", user_version)?;
+    Ok(())
+}
+
+#[test]
+fn manifest_migrations() -> Result<()> {
+    let tempdir = tempdir()?;
+    let dir = tempdir.path().to_owned();
+    let latest_schema;
+    {
+        let handle = Handle::new(dir.clone())?;
+        handle.single_write_from(b"a".to_vec(), &*vec![1; 100])?;
+        handle.single_write_from(b"b".to_vec(), &[][..])?;
+        latest_schema = manifest_schema(&dir)?;
+    }
+    replace_with_v3_manifest(&dir, 3)?;
+    {
+        let mut handle = Handle::new(dir.clone())?;
+        assert_eq!(remaining_keys(&handle)?, ["a", "b"]);
+        handle
+            .read_single(b"a")?
+            .unwrap()
+            .view(|bytes| assert_eq!(bytes, vec![1; 100]))?;
+        // The migrated triggers count deduplicated values once.
+        handle.set_dedup_values(true);
+        handle.single_write_from(b"c".to_vec(), &*vec![2; 100])?;
+        handle.single_write_from(b"d".to_vec(), &*vec![2; 100])?;
+        handle.single_delete(b"a")?;
+        assert_eq!(handle.disk_usage()?.values, 100);
+        let report = handle.check(Default::default())?;
+        assert!(report.issues.is_empty(), "{:?}", report);
+        // Version 3 manifests can't have shared values.
+        handle.single_delete(b"d")?;
+        assert_eq!(handle.disk_usage()?.values, 100);
+    }
+    assert_eq!(manifest_schema(&dir)?, latest_schema);
+    // Versions before 3 can't be migrated.
+    replace_with_v3_manifest(&dir, 2)?;
+    let err = Handle::new(dir.clone()).unwrap_err();
+    assert!(err.to_string().contains("too old"), "{}", err);
+    let handle = Handle::new_with_options(
+        dir.clone(),
+        HandleOptions {
+            allow_destructive_reset: true,
+            ..Default::default()
+        },
+    )?;
+    assert_eq!(remaining_keys(&handle)?, Vec::<String>::new());
+    drop(handle);
+    rusqlite::Connection::open(dir.join(MANIFEST_DB_FILE_NAME))?.pragma_update(
+        None,
+        "user_version",
+        1000,
+    )?;
+    let err = Handle::new(dir).unwrap_err();
+    assert!(err.to_string().contains("newer"), "{}", err);
+    Ok(())
+}
+
+#[test]
+fn export_and_import() -> Result<()> {
+    let src_dir = tempdir()?;
+    let dst_dir = tempdir()?;
+    let src = Handle::new(src_dir.path().to_owned())?;
+    let expires = Timestamp::after(Duration::from_secs(3600));
+    src.single_write_from(b"a/1".to_vec(), &*vec![1; 10000])?;
+    src.single_write_from_with_expiry(b"a/2".to_vec(), &b"two"[..], Some(expires))?;
+    src.single_write_from(b"a/3".to_vec(), &[][..])?;
+    src.single_write_from(b"b".to_vec(), &b"not exported"[..])?;
+    let items = src.list_items(b"a/")?;
+    let mut archive = vec![];
+    assert_eq!(src.export(b"a/", &mut archive)?, 3);
+    // Exporting doesn't count as a use.
+    assert_eq!(
+        src.list_items(b"a/")?
+            .iter()
+            .map(|item| item.value.last_used())
+            .collect_vec(),
+        items
+            .iter()
+            .map(|item| item.value.last_used())
+            .collect_vec()
+    );
+    assert_eq!(&archive[..ARCHIVE_MAGIC.len()], ARCHIVE_MAGIC);
+    let dst = Handle::new(dst_dir.path().to_owned())?;
+    dst.single_write_from(b"a/1".to_vec(), &b"replaced"[..])?;
+    assert!(dst.import(&archive[..archive.len() - 1]).is_err());
+    assert_eq!(dst.import(&*archive)?, 3);
+    assert_eq!(remaining_keys(&dst)?, ["a/1", "a/2", "a/3"]);
+    for (item, imported) in items.iter().zip(dst.list_items(b"")?) {
+        assert_eq!(imported.value.last_used(), item.value.last_used());
+        assert_eq!(imported.value.expires(), item.value.expires());
+        let value = dst.read_single(&item.key)?.unwrap();
+        let expected = src.read_single(&item.key)?.unwrap();
+        expected.view(|expected| value.view(|actual| assert_eq!(actual, expected)))??;
+    }
+    Ok(())
+}
+
+#[test]
+fn backup_to() -> Result<()> {
+    let src_dir = tempdir()?;
+    let dest_dir = tempdir()?;
+    let src = Handle::new(src_dir.path().to_owned())?;
+    for (key, byte) in [("a", 1), ("b", 2), ("c", 3)] {
+        src.single_write_from(key.as_bytes().to_vec(), &*vec![byte; 5000])?;
+    }
+    src.single_write_from(b"empty".to_vec(), &[][..])?;
+    src.single_delete(b"b")?;
+    let expected = src.list_items(b"")?;
+    let report = src.backup_to(dest_dir.path())?;
+    assert_eq!(report.files_cloned + report.files_copied, 1, "{:?}", report);
+    // Changes after the backup aren't included.
+    src.single_write_from(b"d".to_vec(), &*vec![4; 5000])?;
+    src.single_delete(b"a")?;
+    assert!(src.backup_to(dest_dir.path()).is_err());
+    let dest = Handle::new(dest_dir.path().to_owned())?;
+    let actual = dest.list_items(b"")?;
+    assert_eq!(
+        actual.iter().map(|item| &item.key).collect_vec(),
+        expected.iter().map(|item| &item.key).collect_vec()
+    );
+    for (key, byte) in [("a", 1), ("c", 3)] {
+        dest.read_single(key.as_bytes())?
+            .unwrap()
+            .view(|bytes| assert_eq!(bytes, vec![byte; 5000]))?;
+    }
+    let report = dest.check(Default::default())?;
+    assert!(report.issues.is_empty(), "{:?}", report);
+    Ok(())
+}
+
+/// Values committed while a backup is taken are in it whole or not at all.
+#[test]
+fn backup_during_writes() -> Result<()> {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    let src_dir = tempdir()?;
+    let backups_dir = tempdir()?;
+    let src = Handle::new(src_dir.path().to_owned())?;
+    src.single_write_from(b"first".to_vec(), &*vec![0; 5000])?;
+    let stop = Arc::new(AtomicBool::new(false));
+    let writer = thread::spawn({
+        let dir = src_dir.path().to_owned();
+        let stop = Arc::clone(&stop);
+        move || -> Result<()> {
+            let mut byte = 0u8;
+            while !stop.load(Ordering::Relaxed) {
+                byte = byte.wrapping_add(1);
+                // Each Handle writes to a new values file, which the backups must include.
+                let handle = Handle::new(dir.clone())?;
+                handle.single_write_from(vec![byte], &*vec![byte; 5000])?;
+            }
+            Ok(())
+        }
+    });
+    for index in 0..10 {
+        let dest_dir = backups_dir.path().join(index.to_string());
+        src.backup_to(&dest_dir)?;
+        let dest = Handle::new(dest_dir)?;
+        let report = dest.check(Default::default())?;
+        assert!(report.issues.is_empty(), "{:?}", report);
+        for item in dest.list_items(b"")? {
+            dest.read_single(&item.key)?
+                .unwrap()
+                .view(|bytes| assert!(bytes.iter().all(|byte| *byte == bytes[0])))?;
+        }
+    }
+    stop.store(true, Ordering::Relaxed);
+    writer.join().unwrap()?;
+    Ok(())
+}
+
+#[test]
+fn sync_to() -> Result<()> {
+    let src_dir = tempdir()?;
+    let dst_dir = tempdir()?;
+    let src = Handle::new(src_dir.path().to_owned())?;
+    let dst = Handle::new(dst_dir.path().to_owned())?;
+    let block_size = src.block_size() as usize;
+    for (key, byte) in [("same", 1), ("changed", 2), ("missing", 3)] {
+        src.single_write_from(key.as_bytes().to_vec(), &*vec![byte; 2 * block_size])?;
+    }
+    src.single_write_from(b"empty".to_vec(), &[][..])?;
+    dst.single_write_from(b"same".to_vec(), &*vec![1; 2 * block_size])?;
+    dst.single_write_from(b"changed".to_vec(), &*vec![0; 2 * block_size])?;
+    dst.single_write_from(b"extra".to_vec(), &b"extra"[..])?;
+    let report = src.sync_to(&dst, &Default::default())?;
+    assert_eq!(report.values_unchanged, 1, "{:?}", report);
+    assert_eq!(report.values_transferred, 3, "{:?}", report);
+    assert_eq!(report.values_deleted, 0);
+    assert_eq!(
+        remaining_keys(&dst)?,
+        ["changed", "empty", "extra", "missing", "same"]
+    );
+    for item in src.list_items(b"")? {
+        let dst_value = dst.read_single(&item.key)?.unwrap();
+        src.read_single(&item.key)?
+            .unwrap()
+            .view(|expected| dst_value.view(|actual| assert_eq!(actual, expected)))??;
+    }
+    let report = src.sync_to(
+        &dst,
+        &SyncOptions {
+            delete: true,
+            ..Default::default()
+        },
+    )?;
+    assert_eq!(report.values_unchanged, 4, "{:?}", report);
+    assert_eq!(report.values_deleted, 1);
+    assert_eq!(remaining_keys(&dst)?, remaining_keys(&src)?);
+    Ok(())
+}
+
+/// Partially evicted values aren't transferred, and the destination's copies aren't deleted.
+#[test]
+fn sync_partially_evicted() -> Result<()> {
+    const PART_LEN: u64 = 1 << 20;
+    let src_dir = tempdir()?;
+    let dst_dir = tempdir()?;
+    let mut src = Handle::new(src_dir.path().to_owned())?;
+    let dst = Handle::new(dst_dir.path().to_owned())?;
+    src.set_instance_limits(Limits {
+        max_value_length_sum: Some(5 * PART_LEN),
+        partial_eviction: true,
+        ..Default::default()
+    })?;
+    let data = (0..4 * PART_LEN).map(|i| (i % 251) as u8).collect_vec();
+    src.single_write_from(b"big".to_vec(), &data[..])?;
+    src.sync_to(&dst, &Default::default())?;
+    sleep(2 * LAST_USED_RESOLUTION);
+    // Reading the value leaves its other blocks colder than it.
+    src.read_single(b"big")?.unwrap().read_at(0, &mut [0; 1])?;
+    sleep(2 * LAST_USED_RESOLUTION);
+    src.single_write_from(b"small".to_vec(), &data[..3 * PART_LEN as usize])?;
+    assert!(!src
+        .read_single(b"big")?
+        .unwrap()
+        .missing_ranges()
+        .is_empty());
+    let report = src.sync_to(
+        &dst,
+        &SyncOptions {
+            delete: true,
+            ..Default::default()
+        },
+    )?;
+    assert_eq!(report.values_skipped, 1, "{:?}", report);
+    assert_eq!(report.values_transferred, 1, "{:?}", report);
+    assert_eq!(report.values_deleted, 0, "{:?}", report);
+    assert_eq!(remaining_keys(&dst)?, ["big", "small"]);
+    dst.read_single(b"big")?
+        .unwrap()
+        .view(|bytes| assert_eq!(bytes, data))?;
+    Ok(())
+}
+
+#[test]
+fn compressed_values() -> Result<()> {
+    let tempdir = tempdir()?;
+    let handle = Handle::new(tempdir.path().to_owned())?;
+    let data = b"compressible ".repeat(1000);
+    let mut writer = handle.new_writer()?;
+    let mut value = writer.new_value().begin()?;
+    value.compress(Codec::Zstd)?;
+    value.copy_from(&data[..])?;
+    writer.stage_write(b"compressed".to_vec(), value)?;
+    let mut value = writer.new_value().begin()?;
+    value.compress(Codec::Zstd)?;
+    writer.stage_write(b"empty".to_vec(), value)?;
+    let mut value = writer.new_value().begin()?;
+    value.write_all(b"x")?;
+    assert!(value.compress(Codec::Zstd).is_err());
+    // The dropped value shares a file with the staged values, which stays locked until commit.
+    drop(value);
+    writer.commit()?;
+    let value = handle.read_single(b"compressed")?.unwrap();
+    assert_eq!(
+        value.compression().map(|compression| compression.codec),
+        Some(Codec::Zstd)
+    );
+    assert_eq!(value.uncompressed_length(), data.len() as u64);
+    assert!(value.length() < data.len() as u64 / 10);
+    // Limits and usage count what's on disk.
+    assert_eq!(handle.disk_usage()?.values, value.length());
+    // Reads of part of the value decompress up to the end of the read.
+    let mut buf = [0; 12];
+    assert_eq!(value.read_at(13 * 999, &mut buf)?, 12);
+    assert_eq!(&buf, b"compressible");
+    assert_eq!(value.read_at(data.len() as u64 - 1, &mut buf)?, 1);
+    assert_eq!(buf[0], b' ');
+    assert_eq!(value.read_at(data.len() as u64, &mut buf)?, 0);
+    value.view(|bytes| assert_eq!(bytes, data))?;
+    let mut buf = vec![0; data.len() + 1];
+    assert_eq!(value.read(&mut buf)?, data.len());
+    assert_eq!(&buf[..data.len()], data);
+    let mut buf = [0; 12];
+    assert_eq!(value.read_at(13, &mut buf)?, 12);
+    assert_eq!(&buf, b"compressible");
+    let mut read = vec![];
+    value.new_reader().read_to_end(&mut read)?;
+    assert_eq!(read, data);
+    let empty = handle.read_single(b"empty")?.unwrap();
+    assert_eq!(empty.compression(), None);
+    assert_eq!(empty.length(), 0);
+    Ok(())
+}
+
+#[test]
+fn encrypted_values() -> Result<()> {
+    let tempdir = tempdir()?;
+    let key = EncryptionKey::new([7; 32]);
+    let open = |encryption_key| {
+        Handle::new_with_options(
+            tempdir.path().to_owned(),
+            HandleOptions {
+                encryption_key,
+                ..Default::default()
+            },
+        )
+    };
+    let handle = open(Some(key.clone()))?;
+    let marker = b"plaintext marker ";
+    // Lengths around the chunk size, which is 64 KiB.
+    let values = [
+        ("small", marker.repeat(10)),
+        ("chunk", vec![1; 64 << 10]),
+        ("chunks", marker.repeat(20000)),
+    ];
+    for (key, data) in &values {
+        handle.single_write_from(key.as_bytes().to_vec(), &data[..])?;
+    }
+    let mut writer = handle.new_writer()?;
+    let mut value = writer.new_value().begin()?;
+    assert!(value.get_file().is_err());
+    value.compress(Codec::Zstd)?;
+    value.write_all(&marker.repeat(1000))?;
+    writer.stage_write(b"compressed".to_vec(), value)?;
+    // Clones are encrypted as they're copied in.
+    let mut file = tempfile::tempfile()?;
+    file.write_all(&marker.repeat(100))?;
+    let value = writer.new_value().clone_file(&mut file)?;
+    writer.stage_write(b"cloned".to_vec(), value)?;
+    writer.commit()?;
+    for entry in walk_dir(tempdir.path())? {
+        if entry.entry_type == EntryType::ValuesFile {
+            let contents = std::fs::read(&entry.path)?;
+            assert!(!contents
+                .windows(marker.len())
+                .any(|window| window == marker));
+        }
+    }
+    for (key, data) in values.iter().cloned().chain([
+        ("compressed", marker.repeat(1000)),
+        ("cloned", marker.repeat(100)),
+    ]) {
+        let value = handle.read_single(key.as_bytes())?.unwrap();
+        assert!(value.nonce().is_some(), "{}", key);
+        assert_eq!(value.uncompressed_length(), data.len() as u64, "{}", key);
+        // Reads that span chunks, before a view decodes the whole value.
+        let mut buf = vec![0; 100];
+        let pos = data.len().saturating_sub(150) as u64;
+        let n = value.read_at(pos, &mut buf)?;
+        assert_eq!(&buf[..n], &data[pos as usize..][..n], "{}", key);
+        value.view(|bytes| assert_eq!(bytes, data, "{}", key))?;
+        let mut read = vec![];
+        value.new_reader().read_to_end(&mut read)?;
+        assert_eq!(read, data, "{}", key);
+    }
+    let unencrypted = open(None)?;
+    let value = unencrypted.read_single(b"small")?.unwrap();
+    assert!(value.view(|_| ()).is_err());
+    let wrong_key = open(Some(EncryptionKey::new([8; 32])))?;
+    let value = wrong_key.read_single(b"small")?.unwrap();
+    assert!(value.read(&mut [0; 10]).is_err());
+    // Values written without a key stay readable.
+    unencrypted.single_write_from(b"plain".to_vec(), &b"plain"[..])?;
+    handle
+        .read_single(b"plain")?
+        .unwrap()
+        .view(|bytes| assert_eq!(bytes, b"plain"))?;
+    Ok(())
+}
+
+#[test]
+fn checksummed_values() -> Result<()> {
+    let tempdir = tempdir()?;
+    let mut handle = Handle::new(tempdir.path().to_owned())?;
+    // Checksums cover 64 KiB chunks.
+    let data = (0..200_000u32).map(|i| i as u8).collect_vec();
+    handle.single_write_from(b"value".to_vec(), &data[..])?;
+    let mut writer = handle.new_writer()?;
+    let mut direct = writer.new_value().begin()?;
+    direct.get_file()?.write_all(b"direct")?;
+    writer.stage_write(b"direct".to_vec(), direct)?;
+    writer.commit()?;
+    let value = handle.list_items(b"value")?.remove(0).value;
+    // Corrupt the second chunk.
+    let mut file = OpenOptions::new()
+        .write(true)
+        .open(tempdir.path().join(value.file_id().unwrap().to_string()))?;
+    file.seek(Start(value.file_offset().unwrap() + 100_000))?;
+    file.write_all(&[!data[100_000]])?;
+    drop(file);
+    let is_corrupt_chunk_1 =
+        |err: &possum::Error| matches!(err, possum::Error::Corrupt { chunk: 1, .. });
+    let snapshot_value = handle.read_single(b"value")?.unwrap();
+    // Checksums are kept with the snapshot, so Value stays Copy.
+    let copied: possum::Value = *snapshot_value;
+    assert_eq!(copied.location, value.location);
+    assert!(snapshot_value.checksums().is_some());
+    assert!(is_corrupt_chunk_1(&snapshot_value.verify().unwrap_err()));
+    // Without verified reads the corruption goes unnoticed.
+    snapshot_value.view(|bytes| assert_ne!(bytes, data))?;
+    handle.set_verify_reads(true);
+    let snapshot_value = handle.read_single(b"value")?.unwrap();
+    let err = snapshot_value.view(|_| ()).unwrap_err();
+    assert!(is_corrupt_chunk_1(
+        err.get_ref().unwrap().downcast_ref().unwrap()
+    ));
+    assert!(snapshot_value.read(&mut [0; 10]).is_ok());
+    let mut buf = [0; 100];
+    // Range reads only check the chunks they cover.
+    assert_eq!(snapshot_value.read_at(1000, &mut buf)?, 100);
+    assert_eq!(buf, data[1000..1100]);
+    assert_eq!(snapshot_value.read_at(150_000, &mut buf)?, 100);
+    assert_eq!(buf, data[150_000..150_100]);
+    assert!(snapshot_value.read_at(99_990, &mut buf).is_err());
+    assert!(snapshot_value
+        .new_reader()
+        .read_to_end(&mut vec![])
+        .is_err());
+    // Values written directly to the file have nothing to check against.
+    let direct = handle.read_single(b"direct")?.unwrap();
+    assert_eq!(direct.checksums(), None);
+    assert!(!direct.verify()?);
+    direct.view(|bytes| assert_eq!(bytes, b"direct"))?;
+    Ok(())
+}
+
+#[test]
+fn dedup_values() -> Result<()> {
+    let tempdir = tempdir()?;
+    let dir = tempdir.path().to_owned();
+    let value_of = |handle: &Handle, key: &[u8]| -> Result<possum::Value> {
+        Ok(handle.list_items(key)?.remove(0).value)
+    };
+    // Waits for deleted values to be punched, then checks nothing unreferenced was left behind.
+    let reopen = |handle: Handle| -> Result<Handle> {
+        let punched = handle.get_value_puncher_done();
+        drop(handle);
+        punched.wait();
+        let mut handle = Handle::new(dir.clone())?;
+        let report = handle.check(Default::default())?;
+        assert!(report.is_clean(), "{:?}", report);
+        handle.set_dedup_values(true);
+        Ok(handle)
+    };
+    let mut handle = Handle::new(dir.clone())?;
+    handle.set_dedup_values(true);
+    let data = (0..4 * handle.block_size())
+        .map(|i| (i % 251) as u8)
+        .collect_vec();
+    handle.single_write_from(b"a".to_vec(), &data[..])?;
+    // Values written directly to the file are hashed at commit, and duplicates within a batch are
+    // found too.
+    let mut writer = handle.new_writer()?;
+    let mut direct = writer.new_value().begin()?;
+    direct.get_file()?.write_all(&data)?;
+    writer.stage_write(b"b".to_vec(), direct)?;
+    let mut copied = writer.new_value().begin()?;
+    copied.copy_from(&data[..])?;
+    writer.stage_write(b"c".to_vec(), copied)?;
+    writer.commit()?;
+    handle.single_write_from(b"other".to_vec(), &data[1..])?;
+    let location = value_of(&handle, b"a")?.location;
+    for key in [&b"b"[..], b"c"] {
+        assert_eq!(value_of(&handle, key)?.location, location);
+    }
+    assert_ne!(value_of(&handle, b"other")?.location, location);
+    // The shared value is counted once.
+    let len = data.len() as u64;
+    assert_eq!(handle.disk_usage()?.values, 2 * len - 1);
+    handle.rename_item(b"c", b"renamed")?;
+    handle.single_delete(b"a")?;
+    assert_eq!(handle.disk_usage()?.values, 2 * len - 1);
+    // The shared value is only punched once its last key is deleted.
+    let handle = reopen(handle)?;
+    for key in [&b"b"[..], b"renamed"] {
+        handle
+            .read_single(key)?
+            .unwrap()
+            .view(|bytes| assert_eq!(bytes, data))?;
+    }
+    handle.single_delete(b"b")?;
+    handle.single_delete(b"renamed")?;
+    let mut handle = reopen(handle)?;
+    assert_eq!(remaining_keys(&handle)?, ["other"]);
+    assert_eq!(handle.disk_usage()?.values, len - 1);
+    // Evicting a key that shares its value frees nothing until the value's last key goes.
+    handle.single_write_from(b"x".to_vec(), &data[..])?;
+    handle.single_write_from(b"y".to_vec(), &data[..])?;
+    handle.set_instance_limits(Limits {
+        max_value_length_sum: Some(3 * len),
+        ..Default::default()
+    })?;
+    sleep(2 * LAST_USED_RESOLUTION);
+    handle.single_write_from(b"z".to_vec(), &*vec![1; 2 * len as usize])?;
+    assert_eq!(remaining_keys(&handle)?, ["x", "y", "z"]);
+    let evicted_before = handle.stats().bytes_evicted;
+    handle.single_write_from(b"last".to_vec(), &*vec![2; 10])?;
+    assert_eq!(remaining_keys(&handle)?, ["last", "z"]);
+    assert_eq!(handle.stats().bytes_evicted - evicted_before, len);
+    reopen(handle)?;
+    Ok(())
+}
+
+#[test]
+fn partial_eviction() -> Result<()> {
+    const PART_LEN: u64 = 1 << 20;
+    let tempdir = tempdir()?;
+    let dir = tempdir.path().to_owned();
+    let mut handle = Handle::new(dir.clone())?;
+    handle.set_instance_limits(Limits {
+        max_value_length_sum: Some(5 * PART_LEN),
+        partial_eviction: true,
+        ..Default::default()
+    })?;
+    let data = (0..4 * PART_LEN).map(|i| (i % 251) as u8).collect_vec();
+    handle.single_write_from(b"big".to_vec(), &data[..])?;
+    sleep(2 * LAST_USED_RESOLUTION);
+    // Reading the second block keeps it warm.
+    let mut buf = vec![0; 2 * PART_LEN as usize];
+    let value = handle.read_single(b"big")?.unwrap();
+    assert_eq!(
+        value.read_at(PART_LEN, &mut buf[..PART_LEN as usize])?,
+        PART_LEN as usize
+    );
+    drop(value);
+    sleep(2 * LAST_USED_RESOLUTION);
+    // The unread blocks of the least recently used value go to make room, instead of the value.
+    let evicted_before = handle.stats().bytes_evicted;
+    handle.single_write_from(b"small".to_vec(), &data[..3 * PART_LEN as usize])?;
+    assert_eq!(handle.stats().bytes_evicted - evicted_before, 2 * PART_LEN);
+    assert_eq!(remaining_keys(&handle)?, ["big", "small"]);
+    let missing = 2 * PART_LEN..4 * PART_LEN;
+    let value = handle.read_single(b"big")?.unwrap();
+    assert_eq!(value.missing_ranges(), std::slice::from_ref(&missing));
+    // Reads stop at the start of the missing range.
+    let n = value.read_at(PART_LEN, &mut buf)?;
+    assert_eq!(n as u64, PART_LEN);
+    assert_eq!(buf[..n], data[PART_LEN as usize..2 * PART_LEN as usize]);
+    let partially_evicted = |err: std::io::Error| {
+        matches!(
+            err.into_inner().unwrap().downcast_ref::<possum::Error>(),
+            Some(possum::Error::PartiallyEvicted { .. })
+        )
+    };
+    assert!(partially_evicted(
+        value.read_at(3 * PART_LEN, &mut buf).unwrap_err()
+    ));
+    assert!(partially_evicted(value.view(|_| ()).unwrap_err()));
+    drop(value);
+    handle
+        .read_single(b"small")?
+        .unwrap()
+        .view(|bytes| assert_eq!(bytes, &data[..3 * PART_LEN as usize]))?;
+    handle.single_delete(b"small")?;
+    let punched = handle.get_value_puncher_done();
+    drop(handle);
+    punched.wait();
+    // Compaction keeps the missing parts missing, and punches their copies.
+    let handle = Handle::new(dir.clone())?;
+    // The file has nothing to reclaim, so force it to be compacted.
+    let report = handle.compact(CompactOptions {
+        max_live_ratio: 2.0,
+    })?;
+    assert_eq!(report.values_moved, 1, "{:?}", report);
+    let value = handle.read_single(b"big")?.unwrap();
+    assert_eq!(value.missing_ranges(), [missing]);
+    let n = value.read_at(0, &mut buf)?;
+    assert_eq!(n as u64, 2 * PART_LEN);
+    assert_eq!(buf[..n], data[..n]);
+    drop(value);
+    let punched = handle.get_value_puncher_done();
+    drop(handle);
+    punched.wait();
+    let handle = Handle::new(dir)?;
+    let report = handle.check(Default::default())?;
+    assert!(report.is_clean(), "{:?}", report);
+    Ok(())
+}
+
+#[test]
+fn dedup_blocks() -> Result<()> {
+    const PART_LEN: usize = 1 << 20;
+    let dst_dir = tempdir()?;
+    let tempdir = tempdir()?;
+    let dir = tempdir.path().to_owned();
+    let reopen = |handle: Handle| -> Result<Handle> {
+        let punched = handle.get_value_puncher_done();
+        drop(handle);
+        punched.wait();
+        let mut handle = Handle::new(dir.clone())?;
+        let report = handle.check(Default::default())?;
+        assert!(report.is_clean(), "{:?}", report);
+        handle.set_dedup_values(true);
+        Ok(handle)
+    };
+    let mut handle = Handle::new(dir.clone())?;
+    handle.set_dedup_values(true);
+    // Values are only stored in blocks with partial eviction.
+    handle.set_instance_limits(Limits {
+        partial_eviction: true,
+        ..Default::default()
+    })?;
+    let data = (0..4 * PART_LEN).map(|i| (i % 251) as u8).collect_vec();
+    handle.single_write_from(b"a".to_vec(), &data[..])?;
+    // Only the last block of b differs from a, and the blocks of c are all the same.
+    let mut b = data.clone();
+    b[3 * PART_LEN..].fill(1);
+    handle.single_write_from(b"b".to_vec(), &b[..])?;
+    handle.single_write_from(b"c".to_vec(), &*vec![2; 3 * PART_LEN])?;
+    // Shared blocks are counted once.
+    assert_eq!(handle.disk_usage()?.values, 6 * PART_LEN as u64);
+    handle.single_delete(b"a")?;
+    assert_eq!(handle.disk_usage()?.values, 5 * PART_LEN as u64);
+    // The blocks b shared with a outlive it.
+    let handle = reopen(handle)?;
+    handle
+        .read_single(b"b")?
+        .unwrap()
+        .view(|bytes| assert_eq!(bytes, b))?;
+    let value = handle.read_single(b"c")?.unwrap();
+    let mut buf = vec![0; PART_LEN];
+    assert_eq!(value.read_at(2 * PART_LEN as u64, &mut buf)?, PART_LEN);
+    assert_eq!(buf, vec![2; PART_LEN]);
+    drop(value);
+    // Compaction moves the blocks that no value in the file is at anymore.
+    let report = handle.compact(CompactOptions {
+        max_live_ratio: 2.0,
+    })?;
+    assert_eq!(
+        report.files_compacted.len(),
+        report.files_removed.len(),
+        "{:?}",
+        report
+    );
+    let mut handle = reopen(handle)?;
+    handle
+        .read_single(b"b")?
+        .unwrap()
+        .view(|bytes| assert_eq!(bytes, b))?;
+    // Syncing copies values with blocks elsewhere, since cloning their file would miss them.
+    let dst = Handle::new(dst_dir.path().to_owned())?;
+    handle.sync_to(&dst, &Default::default())?;
+    for key in [&b"b"[..], b"c"] {
+        let dst_value = dst.read_single(key)?.unwrap();
+        handle
+            .read_single(key)?
+            .unwrap()
+            .view(|expected| dst_value.view(|actual| assert_eq!(actual, expected)))??;
+    }
+    handle.single_delete(b"b")?;
+    handle.single_delete(b"c")?;
+    assert_eq!(handle.disk_usage()?.values, 0);
+    handle = reopen(handle)?;
+    assert_eq!(remaining_keys(&handle)?, Vec::<String>::new());
+    Ok(())
+}
+
+#[test]
+fn stats() -> Result<()> {
+    let tempdir = tempdir()?;
+    let mut handle = Handle::new(tempdir.path().to_owned())?;
+    handle.set_instance_limits(Limits {
+        max_value_length_sum: Some(20),
+        ..Default::default()
+    })?;
+    handle.single_write_from(b"a".to_vec(), &b"hello"[..])?;
+    assert!(handle.read_single(b"a")?.is_some());
+    assert!(handle.read_single(b"b")?.is_none());
+    let stats = handle.stats();
+    assert_eq!(stats.read_hits, 1);
+    assert_eq!(stats.read_misses, 1);
+    assert_eq!(stats.bytes_written, 5);
+    assert_eq!(stats.values_committed, 1);
+    assert_eq!(stats.bytes_committed, 5);
+    assert_eq!(stats.evictions, 0);
+    assert!(stats.manifest_transactions > 0);
+    // Exceeding the limit evicts a.
+    handle.single_write_from(b"b".to_vec(), &[0; 20][..])?;
+    let stats = handle.stats();
+    assert_eq!(stats.values_committed, 2);
+    assert_eq!(stats.evictions, 1);
+    assert_eq!(stats.bytes_evicted, 5);
+    let mut prometheus = vec![];
+    stats.write_prometheus(&mut prometheus)?;
+    let prometheus = String::from_utf8(prometheus)?;
+    assert!(
+        prometheus.contains("# TYPE possum_evictions_total counter\npossum_evictions_total 1\n")
+    );
+    assert!(prometheus.contains("# TYPE possum_punch_queue_bytes gauge\n"));
+    Ok(())
+}
+
+#[test]
+fn punch_queue_resumed() -> Result<()> {
+    let tempdir = tempdir()?;
+    let dir = tempdir.path().to_owned();
+    let mut handle = Handle::new(dir.clone())?;
+    let block_size = handle.block_size() as usize;
+    handle.single_write_from(b"a".to_vec(), &*vec![1; 3 * block_size])?;
+    handle.single_write_from(b"b".to_vec(), &*vec![2; block_size])?;
+    let ValueLocation::Nonzero(location) = handle.list_items(b"a")?.remove(0).value.location else {
+        panic!("value is empty");
+    };
+    // Leave the value unpunched, as though the process exited before its puncher got to it.
+    handle.set_instance_limits(Limits {
+        disable_hole_punching: true,
+        ..Default::default()
+    })?;
+    handle.single_delete(b"a")?;
+    drop(handle);
+    rusqlite::Connection::open(dir.join(MANIFEST_DB_FILE_NAME))?.execute(
+        "insert into pending_punches (file_id, file_offset, length) values (?, ?, ?)",
+        rusqlite::params![location.file_id, location.file_offset, location.length],
+    )?;
+    let handle = Handle::new(dir.clone())?;
+    let punched = handle.get_value_puncher_done();
+    drop(handle);
+    punched.wait();
+    let handle = Handle::new(dir)?;
+    assert_eq!(handle.punch_backlog()?, PunchBacklog::default());
+    let report = handle.check(Default::default())?;
+    assert!(report.is_clean(), "{:?}", report);
+    Ok(())
+}
+
+/// A queued punch that keeps failing is retried with backoff, and the value puncher carries on
+/// with the rest of the queue.
+#[test]
+fn punch_queue_failing_entry() -> Result<()> {
+    let tempdir = tempdir()?;
+    let dir = tempdir.path().to_owned();
+    let handle = Handle::new(dir.clone())?;
+    let block_size = handle.block_size();
+    // A values file that can't be opened for writing.
+    std::fs::create_dir(dir.join("values-0000beef"))?;
+    rusqlite::Connection::open(dir.join(MANIFEST_DB_FILE_NAME))?.execute(
+        "insert into pending_punches (file_id, file_offset, length) values (?, 0, ?)",
+        rusqlite::params![0xbeef, block_size],
+    )?;
+    let failing = PunchBacklog {
+        values: 1,
+        bytes: block_size,
+        retrying: 1,
+    };
+    handle.single_write_from(b"a".to_vec(), &*vec![1; 3 * block_size as usize])?;
+    handle.single_write_from(b"b".to_vec(), &*vec![2; block_size as usize])?;
+    for key in [b"a", b"b"] {
+        handle.single_delete(key)?;
+        let start = Instant::now();
+        while handle.punch_backlog()? != failing {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            sleep(Duration::from_millis(10));
+        }
+    }
+    Ok(())
+}
+
+/// A reader that's never begun holds the clone lock shared. Punching and repairs put off their work
+/// instead of waiting for it, even on the reader's own thread.
+#[test]
+fn unbegun_reader_punch_retried() -> Result<()> {
+    let tempdir = tempdir()?;
+    let dir = tempdir.path().to_owned();
+    let handle = Handle::new(dir.clone())?;
+    let block_size = handle.block_size() as usize;
+    handle.single_write_from(b"a".to_vec(), &*vec![1; 3 * block_size])?;
+    handle.single_write_from(b"b".to_vec(), &*vec![2; block_size])?;
+    // Readers hold their Handle's connection, so it comes from another one.
+    let other = Handle::new(dir.clone())?;
+    let reader = other.read()?;
+    handle.single_delete(b"a")?;
+    let start = Instant::now();
+    while handle.punch_backlog()?.retrying == 0 {
+        assert!(start.elapsed() < Duration::from_secs(10));
+        sleep(Duration::from_millis(10));
+    }
+    let report = handle.check(CheckOptions { repair: true })?;
+    assert!(!report.files_in_use.is_empty(), "{:?}", report);
+    drop(reader);
+    drop(other);
+    let punched = handle.get_value_puncher_done();
+    drop(handle);
+    punched.wait();
+    let handle = Handle::new(dir)?;
+    assert_eq!(handle.punch_backlog()?, PunchBacklog::default());
+    let report = handle.check(Default::default())?;
+    assert!(report.is_clean(), "{:?}", report);
+    Ok(())
+}
+
+#[test]
+fn handle_close() -> Result<()> {
+    let tempdir = tempdir()?;
+    let dir = tempdir.path().to_owned();
+    let handle = Handle::new(dir.clone())?;
+    let block_size = handle.block_size() as usize;
+    handle.single_write_from(b"a".to_vec(), &*vec![1; 3 * block_size])?;
+    handle.single_write_from(b"b".to_vec(), &*vec![2; block_size])?;
+    // Leave an uncommitted value in the exclusive file the Handle holds.
+    let mut writer = handle.new_writer()?;
+    let mut value = writer.new_value().begin()?;
+    value.write_all(&vec![3; block_size])?;
+    writer.stage_write(b"c".to_vec(), value)?;
+    drop(writer);
+    handle.single_delete(b"a")?;
+    let report = handle.close(Duration::from_secs(10))?;
+    assert!(report.is_complete(), "{:?}", report);
+    assert_eq!(report.punch_backlog, PunchBacklog::default());
+    assert_eq!(report.exclusive_files_returned, 1);
+    assert_eq!(report.uncommitted_bytes_truncated, block_size as u64);
+    let handle = Handle::new(dir)?;
+    assert!(handle.read_single(b"c")?.is_none());
+    let report = handle.check(Default::default())?;
+    assert!(report.is_clean(), "{:?}", report);
+    Ok(())
+}
+
+#[test]
+fn read_only_handle() -> Result<()> {
+    let tempdir = tempdir()?;
+    let dir = tempdir.path().to_owned();
+    let handle = Handle::new(dir.clone())?;
+    handle.single_write_from(b"a".to_vec(), &b"hello"[..])?;
+    let last_used = handle.list_items(b"a")?[0].value.last_used();
+    let dir_entries = || -> Result<Vec<_>> {
+        let mut entries = std::fs::read_dir(&dir)?
+            .map(|entry| Ok(entry?.file_name()))
+            .collect::<Result<Vec<_>>>()?;
+        entries.sort();
+        Ok(entries)
+    };
+    let entries = dir_entries()?;
+    let read_only = Handle::new_with_options(
+        dir.clone(),
+        HandleOptions {
+            read_only: true,
+            ..Default::default()
+        },
+    )?;
+    assert!(read_only.read_only());
+    // Make sure reading doesn't happen in the same millisecond as the write.
+    thread::sleep(Duration::from_millis(2));
+    let value = read_only.read_single(b"a")?.unwrap();
+    assert_eq!(value.view(|bytes| bytes.to_vec())?, b"hello");
+    assert_eq!(value.last_used(), last_used);
+    let err = read_only.new_writer().err().unwrap();
+    assert!(
+        matches!(err.downcast_ref(), Some(possum::Error::ReadOnly)),
+        "{err:?}"
+    );
+    assert!(matches!(
+        read_only.single_delete(b"a"),
+        Err(possum::Error::ReadOnly)
+    ));
+    let report = read_only.close(Duration::from_secs(1))?;
+    assert!(report.is_complete(), "{:?}", report);
+    assert_eq!(dir_entries()?, entries);
+    assert_eq!(handle.list_items(b"a")?[0].value.last_used(), last_used);
+    Ok(())
+}
+
+#[test]
+fn batched_touches() -> Result<()> {
+    let tempdir = tempdir()?;
+    let dir = tempdir.path().to_owned();
+    let handle = Handle::new(dir.clone())?;
+    handle.single_write_from(b"a".to_vec(), &b"hello"[..])?;
+    let written = handle.list_items(b"a")?[0].value.last_used();
+    // Make sure reading doesn't happen in the same millisecond as the write.
+    thread::sleep(Duration::from_millis(2));
+    let read = handle.read_single(b"a")?.unwrap().last_used();
+    assert!(read > written);
+    // The read is only recorded in the Handle until the background evictor flushes it.
+    assert_eq!(handle.list_items(b"a")?[0].value.last_used(), written);
+    // Commits apply it too.
+    handle.single_write_from(b"b".to_vec(), &b"world"[..])?;
+    assert_eq!(handle.list_items(b"a")?[0].value.last_used(), read);
+    thread::sleep(Duration::from_millis(2));
+    let read = handle.read_single(b"a")?.unwrap().last_used();
+    // And so does closing.
+    let report = handle.close(Duration::from_secs(10))?;
+    assert!(report.is_complete(), "{:?}", report);
+    let handle = Handle::new(dir)?;
+    assert_eq!(handle.list_items(b"a")?[0].value.last_used(), read);
+    Ok(())
+}
+
 #[test]
 fn read_and_writes_different_handles() -> Result<()> {
     check_concurrency(